@@ -0,0 +1,5 @@
+use fly_core::Workload;
+
+fn main() -> anyhow::Result<()> {
+    fly_core::cli::main(Workload::UniqueIds)
+}
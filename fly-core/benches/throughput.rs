@@ -0,0 +1,105 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use fly_core::{Msg, Node};
+use std::hint::black_box;
+use tokio::io::{self, Sink};
+use tokio::runtime::Runtime;
+
+// roughly what the maelstrom broadcast tests run with
+const CLUSTER: usize = 25;
+
+fn cluster() -> Vec<String> {
+    (0..CLUSTER).map(|i| format!("n{}", i)).collect()
+}
+
+fn node() -> Node<Sink> {
+    Node::new(io::sink(), "n0".to_string(), cluster())
+}
+
+fn broadcast(message: usize) -> String {
+    format!(
+        r#"{{"src":"c1","dest":"n0","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+        message, message
+    )
+}
+
+fn serialization(c: &mut Criterion) {
+    let line = broadcast(42);
+    let msg = serde_json::from_str::<Msg>(&line).unwrap();
+
+    let mut group = c.benchmark_group("serialization");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("parse", |b| {
+        b.iter(|| serde_json::from_str::<Msg>(black_box(&line)).unwrap())
+    });
+    group.bench_function("serialize", |b| {
+        b.iter(|| serde_json::to_string(black_box(&msg)).unwrap())
+    });
+    group.finish();
+}
+
+fn handle(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("handle");
+    group.throughput(Throughput::Elements(1));
+
+    // fresh msg_id every time, a repeated one would just be answered from the
+    // dedup cache
+    let mut n = node();
+    let mut msg_id = 0;
+    group.bench_function("echo", |b| {
+        b.iter(|| {
+            msg_id += 1;
+            let echo = format!(
+                r#"{{"src":"c1","dest":"n0","body":{{"type":"echo","msg_id":{},"echo":"hello"}}}}"#,
+                msg_id
+            );
+            n.handle(black_box(&echo)).unwrap();
+            rt.block_on(n.flush()).unwrap();
+        })
+    });
+
+    // every new value fans out to the whole cluster, so this is one client
+    // message in and CLUSTER messages out
+    let mut n = node();
+    let mut message = 0;
+    group.bench_function("broadcast_fanout", |b| {
+        b.iter(|| {
+            message += 1;
+            n.handle(&broadcast(message)).unwrap();
+            rt.block_on(n.flush()).unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn gossip(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("gossip");
+    for values in [10, 100] {
+        // a round retransmits everything that's still unacked
+        let sent = (values * (CLUSTER - 1)) as u64;
+        group.throughput(Throughput::Elements(sent));
+        group.bench_function(format!("retransmit_{}", values), |b| {
+            b.iter_batched(
+                || {
+                    let mut n = node();
+                    for message in 0..values {
+                        n.handle(&broadcast(message)).unwrap();
+                    }
+                    rt.block_on(n.flush()).unwrap();
+                    n
+                },
+                |mut n| {
+                    n.gossip();
+                    rt.block_on(n.flush()).unwrap();
+                    n
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialization, handle, gossip);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+use std::process::Command;
+
+// the startup line says which commit and profile a node was built from, so a
+// maelstrom results dir can be matched to the code that produced it
+fn main() {
+    let git = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let profile = std::env::var("PROFILE").unwrap_or_else(|_| "unknown".to_string());
+    println!("cargo:rustc-env=FLY_GIT_HASH={}", git);
+    println!("cargo:rustc-env=FLY_PROFILE={}", profile);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+}
@@ -0,0 +1,148 @@
+use crate::{Error, Node, Result};
+use serde_json::Value;
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration, Instant};
+
+// the node owns all of its state and only ever touches it from its own loop.
+// everything else (the input reader, tests, later whatever background work a
+// workload wants) talks to it through a NodeHandle, which puts a Command in
+// its mailbox. the loop takes them one at a time, so handlers keep their
+// plain &mut self and nothing in here needs a lock
+
+// commands buffered in the mailbox, and how many of them we handle in one go
+// before giving the timers and flush a chance
+const MAILBOX: usize = 1024;
+const MAX_DRAIN: usize = 256;
+
+pub enum Command {
+    // a line of input, same as one off stdin
+    IncomingMsg(String),
+    // fire whatever timers are due, the loop does that on its own as well
+    TimerTick,
+    // everything the node knows, same as dump_state writes to a file
+    AdminDump(oneshot::Sender<Value>),
+}
+
+#[derive(Clone)]
+pub struct NodeHandle {
+    tx: mpsc::Sender<Command>,
+}
+
+pub type Mailbox = mpsc::Receiver<Command>;
+
+// the loop ends once every handle is gone
+pub fn mailbox() -> (NodeHandle, Mailbox) {
+    let (tx, rx) = mpsc::channel(MAILBOX);
+    (NodeHandle { tx }, rx)
+}
+
+impl NodeHandle {
+    // waits for room in the mailbox, that's the backpressure on the input
+    pub async fn incoming(&self, line: String) -> Result<()> {
+        self.send(Command::IncomingMsg(line)).await
+    }
+
+    pub async fn tick(&self) -> Result<()> {
+        self.send(Command::TimerTick).await
+    }
+
+    pub async fn dump(&self) -> Result<Value> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::AdminDump(tx)).await?;
+        rx.await.map_err(|_| Error::NodeGone)
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.tx.send(command).await.map_err(|_| Error::NodeGone)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // runs the node off its mailbox until every handle is dropped
+    pub async fn serve(mut self, mut mailbox: Mailbox) -> Result<()> {
+        loop {
+            // there's always at least the gossip timer, the fallback is just
+            // so there's something to sleep on
+            let deadline = self
+                .next_timer()
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+            tokio::select! {
+                command = mailbox.recv() => {
+                    let Some(command) = command else {
+                        break;
+                    };
+                    self.command(command);
+                    // handle whatever else already piled up back to back
+                    // instead of going through select (and a flush) for every
+                    // single one
+                    for _ in 0..MAX_DRAIN {
+                        let Ok(command) = mailbox.try_recv() else {
+                            break;
+                        };
+                        self.command(command);
+                    }
+                }
+                _ = time::sleep_until(deadline) => {
+                    self.fire_timers();
+                }
+            }
+            self.flush().await?;
+        }
+
+        self.broadcast.log_propagation();
+        Ok(())
+    }
+
+    pub fn command(&mut self, command: Command) {
+        match command {
+            Command::IncomingMsg(line) => self.receive(&line),
+            Command::TimerTick => self.fire_timers(),
+            Command::AdminDump(reply) => match self.state() {
+                // whoever asked may have stopped waiting, that's fine
+                Ok(state) => _ = reply.send(state),
+                Err(e) => eprintln!("dumping state failed: {}", e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test(start_paused = true)]
+    async fn handles_drive_the_node() {
+        let (handle, mailbox) = mailbox();
+        let (output, replies) = tokio::io::duplex(64 * 1024);
+        let node =
+            tokio::spawn(
+                async move { crate::run_actor(mailbox, output, &Config::default()).await },
+            );
+
+        // nothing to dump before init
+        handle.tick().await.unwrap();
+        for line in [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":5}}"#,
+        ] {
+            handle.incoming(line.to_string()).await.unwrap();
+        }
+        let state = handle.dump().await.unwrap();
+        assert_eq!(state["id"], "n1");
+        assert_eq!(state["broadcast"]["messages"], serde_json::json!([5]));
+
+        drop(handle);
+        node.await.unwrap().unwrap();
+        let mut replies = BufReader::new(replies).lines();
+        let mut types = Vec::new();
+        while let Some(line) = replies.next_line().await.unwrap() {
+            let msg = serde_json::from_str::<crate::Msg>(&line).unwrap();
+            types.push(msg.body.extra);
+        }
+        assert_eq!(types.len(), 2);
+        assert!(matches!(types[1], crate::Payload::BroadcastOk));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::middleware::Middleware;
+use crate::Msg;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tokio::time::Instant;
+
+// --audit, every message in and out as one json object a line:
+//
+//   {"at_ms":12,"dir":"in","msg":{"src":"c0","dest":"n1","body":{...}}}
+//
+// at_ms is since the node started, off the monotonic clock. unlike the
+// stderr log it's nothing but messages, so two builds fed the same input can
+// be diffed on it directly. --replay takes it in place of a --record file (the
+// "in" lines are what gets played back) and the simulator can play its client
+// requests, see Simulator::play
+pub struct Audit {
+    out: BufWriter<File>,
+    start: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Entry {
+    pub at_ms: u64,
+    pub dir: Direction,
+    pub msg: Msg,
+}
+
+impl Audit {
+    pub fn create(path: &Path) -> io::Result<Audit> {
+        Ok(Audit {
+            out: BufWriter::new(File::options().create(true).append(true).open(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    // the line is already json, it goes in as is
+    fn log(&mut self, dir: &str, line: &str) {
+        let at = self.start.elapsed().as_millis();
+        // flushing every line so a crashed run still leaves a usable file
+        let logged = writeln!(
+            self.out,
+            r#"{{"at_ms":{},"dir":"{}","msg":{}}}"#,
+            at,
+            dir,
+            line.trim_end()
+        )
+        .and_then(|_| self.out.flush());
+        if let Err(e) = logged {
+            eprintln!("writing the audit file failed: {}", e);
+        }
+    }
+}
+
+impl Middleware for Audit {
+    fn inbound(&mut self, line: &str, _msg: &Msg) -> bool {
+        self.log("in", line);
+        true
+    }
+
+    fn outbound(&mut self, _dest: &str, line: &str) -> bool {
+        self.log("out", line);
+        true
+    }
+}
+
+pub fn parse(line: &str) -> Option<Entry> {
+    serde_json::from_str(line).ok()
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        match parse(&line) {
+            Some(entry) => entries.push(entry),
+            None => eprintln!("skipping malformed audit entry {}", line),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Node;
+
+    #[tokio::test(start_paused = true)]
+    async fn logs_both_ways_and_loads_back() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let audit = Audit::create(&path).unwrap();
+        let mut n = Node::with_middleware(
+            Vec::new(),
+            "n1".to_string(),
+            vec!["n1".to_string()],
+            vec![Box::new(audit)],
+        );
+        tokio::time::advance(tokio::time::Duration::from_millis(7)).await;
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#)
+            .unwrap();
+
+        let entries = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].dir, Direction::In);
+        assert_eq!(entries[0].at_ms, 7);
+        assert_eq!(entries[1].dir, Direction::Out);
+        assert_eq!(entries[1].msg.dest, "c1");
+        assert_eq!(entries[1].msg.body.in_reply_to, Some(1));
+    }
+}
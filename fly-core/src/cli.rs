@@ -0,0 +1,67 @@
+use crate::config::Config;
+use crate::{replay, run, tcp, Workload};
+#[cfg(feature = "dev-tools")]
+use crate::{selftest, sweep};
+use tokio::io::{self, AsyncBufRead};
+use tokio::net::TcpListener;
+
+// everything a challenge binary does. they all speak every message type and
+// work out which challenge they're serving from the traffic unless told with
+// --workload. the binary's own workload is what --selftest runs by default
+// and what a `read` before anything else is answered as
+pub fn main(workload: Workload) -> anyhow::Result<()> {
+    let mut config = Config::from_args(std::env::args().skip(1))?;
+    config.default_workload = Some(workload);
+    if config.selftest.is_some() || config.sweep.is_some() {
+        return dev_tools(config, workload);
+    }
+    serve(config)
+}
+
+#[cfg(feature = "dev-tools")]
+fn dev_tools(config: Config, workload: Workload) -> anyhow::Result<()> {
+    if let Some(n) = config.selftest {
+        let workload = config.workloads.first().copied().unwrap_or(workload);
+        let counter_mode = config.counter.unwrap_or_default();
+        let passed = simulated(selftest::run(n, workload, counter_mode))?;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if let Some(n) = config.sweep {
+        simulated(sweep::run(n))??;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "dev-tools"))]
+fn dev_tools(_config: Config, _workload: Workload) -> anyhow::Result<()> {
+    anyhow::bail!("--selftest and --sweep need a build with --features dev-tools")
+}
+
+// the simulator moves tokio's clock by hand, which only works on a single
+// threaded runtime that starts out paused
+#[cfg(feature = "dev-tools")]
+fn simulated<F: std::future::Future>(f: F) -> anyhow::Result<F::Output> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()?
+        .block_on(f))
+}
+
+#[tokio::main]
+async fn serve(config: Config) -> anyhow::Result<()> {
+    if let Some(addr) = &config.listen {
+        let listener = TcpListener::bind(addr).await?;
+        eprintln!("listening on {}", listener.local_addr()?);
+        tcp::serve(listener, &config).await?;
+        return Ok(());
+    }
+    let stdin = io::BufReader::new(io::stdin());
+    let input: Box<dyn AsyncBufRead + Unpin + Send> = match (&config.record, &config.replay) {
+        (Some(path), _) => Box::new(replay::record(stdin, path).await?),
+        (_, Some(path)) => Box::new(replay::replay(path).await?),
+        _ => Box::new(stdin),
+    };
+    run(input, io::stdout(), &config).await?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use crate::compression::{Compression, COMPRESS_ABOVE};
+use crate::error::invalid;
+use crate::{middleware, Msg, Node, Payload, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::AsyncWrite;
+
+// how a payload gets turned into bytes between our own nodes. maelstrom only
+// routes json lines, so anything binary travels base64'd inside an `encoded`
+// payload and the receiving node unwraps it and handles whatever was inside
+// as if it had come in directly, after inflating it if it was compressed.
+// clients never see any of this: a node only answers in a codec when the
+// request asked for one (see `codec` on read), and only compresses for peers
+// that said they can take it (see compression.rs)
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn encode(&self, payload: &Payload) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<Payload>;
+}
+
+pub struct Json;
+pub struct MsgPack;
+pub struct Cbor;
+
+impl Codec for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, payload: &Payload) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(payload)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Payload> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+impl Codec for MsgPack {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    // named, payloads are internally tagged so they have to be maps
+    fn encode(&self, payload: &Payload) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(payload).map_err(invalid)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Payload> {
+        rmp_serde::from_slice(bytes).map_err(invalid)
+    }
+}
+
+impl Codec for Cbor {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, payload: &Payload) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(payload, &mut bytes).map_err(invalid)?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Payload> {
+        ciborium::from_reader(bytes).map_err(invalid)
+    }
+}
+
+pub fn by_name(name: &str) -> Option<&'static dyn Codec> {
+    match name {
+        "json" => Some(&Json),
+        "msgpack" => Some(&MsgPack),
+        "cbor" => Some(&Cbor),
+        _ => None,
+    }
+}
+
+// what a peer gets: plain json unless it asked for a codec or the payload is
+// big enough to be worth compressing
+pub fn for_peer(
+    codec: Option<&dyn Codec>,
+    compression: Option<Compression>,
+    payload: Payload,
+) -> Result<Payload> {
+    if codec.is_none() && compression.is_none() {
+        return Ok(payload);
+    }
+    let codec = codec.unwrap_or(&Json);
+    let bytes = codec.encode(&payload)?;
+    let compression = compression.filter(|_| bytes.len() > COMPRESS_ABOVE);
+    let bytes = match compression {
+        Some(c) => c.compress(&bytes)?,
+        None if codec.name() == "json" => return Ok(payload),
+        None => bytes,
+    };
+    Ok(Payload::Encoded {
+        codec: codec.name().to_string(),
+        compression: compression.map(|c| c.name().to_string()),
+        data: STANDARD.encode(bytes),
+    })
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // only ever a sync page between our own nodes, the one thing we encode.
+    // whatever's inside skipped everything handle_line does with a message,
+    // so it gets validate (and --paranoid's checks) here before dispatch
+    pub(crate) fn encoded(
+        &mut self,
+        msg: &Msg,
+        codec: &str,
+        compression: &Option<String>,
+        data: &str,
+    ) -> Result<Option<Payload>> {
+        if !self.nodes.contains(&msg.src) {
+            return Ok(None);
+        }
+        let codec = by_name(codec).ok_or_else(|| invalid(format!("unknown codec {}", codec)))?;
+        let mut bytes = STANDARD.decode(data).map_err(invalid)?;
+        if let Some(name) = compression {
+            let compression = Compression::by_name(name)
+                .ok_or_else(|| invalid(format!("unknown compression {}", name)))?;
+            bytes = compression.decompress(&bytes)?;
+        }
+        let payload = codec.decode(&bytes)?;
+        if !matches!(payload, Payload::Read { .. } | Payload::ReadOk { .. }) {
+            let json = serde_json::to_string(&payload)?;
+            let name = middleware::message_type(&json).unwrap_or("?");
+            return Err(invalid(format!("encoded {}, only sync pages are", name)));
+        }
+        let mut inner = msg.clone();
+        inner.body.extra = payload;
+        self.validate(&inner).map_err(invalid)?;
+        if self.paranoid {
+            if let Some(problem) = self.suspicious(&serde_json::to_string(&inner)?, &inner) {
+                self.metrics.paranoid_rejected += 1;
+                return Err(invalid(problem));
+            }
+        }
+        self.dispatch(&inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_through_every_codec() {
+        // big enough to be over COMPRESS_ABOVE in every codec
+        let payload = Payload::ReadOk {
+            messages: Some((0..10_000).collect()),
+            next: Some(9_999),
+            delta: None,
+            ranges: None,
+            value: None,
+        };
+        for name in ["json", "msgpack", "cbor"] {
+            let codec = by_name(name).unwrap();
+            for compression in [None, Some(Compression::Gzip), Some(Compression::Zstd)] {
+                let wrapped = for_peer(Some(codec), compression, payload.clone()).unwrap();
+                if name == "json" && compression.is_none() {
+                    // nothing to gain from wrapping json in json
+                    assert!(matches!(wrapped, Payload::ReadOk { .. }));
+                    continue;
+                }
+                let Payload::Encoded {
+                    codec: used,
+                    compression: compressed,
+                    data,
+                } = wrapped
+                else {
+                    panic!("not wrapped");
+                };
+                assert_eq!(used, name);
+                let mut bytes = STANDARD.decode(data).unwrap();
+                if let Some(c) = compressed {
+                    bytes = Compression::by_name(&c)
+                        .unwrap()
+                        .decompress(&bytes)
+                        .unwrap();
+                }
+                let back = codec.decode(&bytes).unwrap();
+                assert_eq!(
+                    serde_json::to_string(&back).unwrap(),
+                    serde_json::to_string(&payload).unwrap()
+                );
+            }
+        }
+        assert!(by_name("zstd").is_none());
+    }
+
+    #[test]
+    fn only_big_payloads_get_compressed() {
+        let small = Payload::ReadOk {
+            messages: Some(vec![1, 2, 3]),
+            next: None,
+            delta: None,
+            ranges: None,
+            value: None,
+        };
+        let plain = for_peer(None, Some(Compression::Zstd), small).unwrap();
+        assert!(matches!(plain, Payload::ReadOk { .. }));
+
+        let big = Payload::ReadOk {
+            messages: Some((0..10_000).collect()),
+            next: None,
+            delta: None,
+            ranges: None,
+            value: None,
+        };
+        let packed = for_peer(None, Some(Compression::Zstd), big).unwrap();
+        assert!(matches!(packed, Payload::Encoded { compression: Some(ref c), .. } if c == "zstd"));
+    }
+}
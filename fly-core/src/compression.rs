@@ -0,0 +1,99 @@
+use crate::Node;
+use std::io::{self, Read, Write};
+use tokio::io::AsyncWrite;
+
+// payloads between our nodes get compressed once they're bigger than this,
+// below it the base64 on top eats most of what compression saves
+pub const COMPRESS_ABOVE: usize = 1024;
+// and nothing a peer sends us gets to inflate past this
+const MAX_DECOMPRESSED: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+// what we can do, best first. peers tell each other theirs with a hello right
+// after startup and each side goes with the first of its own the other has
+const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Compression> {
+        SUPPORTED.into_iter().find(|c| c.name() == name)
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::encode_all(bytes, 3),
+            Compression::Gzip => {
+                let mut out = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+                out.write_all(bytes)?;
+                out.finish()
+            }
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read> = match self {
+            Compression::Zstd => Box::new(zstd::Decoder::new(bytes)?),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(bytes)),
+        };
+        let mut out = Vec::new();
+        reader.take(MAX_DECOMPRESSED + 1).read_to_end(&mut out)?;
+        if out.len() as u64 > MAX_DECOMPRESSED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed payload too large",
+            ));
+        }
+        Ok(out)
+    }
+}
+
+pub(crate) fn names() -> Vec<String> {
+    SUPPORTED.iter().map(|c| c.name().to_string()).collect()
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // see hello.rs
+    pub(crate) fn learn_compression(&mut self, peer: &str, theirs: &[String]) {
+        match SUPPORTED
+            .into_iter()
+            .find(|c| theirs.iter().any(|t| t == c.name()))
+        {
+            Some(c) => {
+                self.peer_compression.insert(peer.to_string(), c);
+            }
+            None => {
+                self.peer_compression.remove(peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips() {
+        let bytes = (0..10_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        for c in SUPPORTED {
+            let packed = c.compress(&bytes).unwrap();
+            assert!(packed.len() < bytes.len());
+            assert_eq!(c.decompress(&packed).unwrap(), bytes);
+            assert_eq!(Compression::by_name(c.name()), Some(c));
+        }
+        assert!(Compression::Gzip.decompress(b"not gzip").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::codec::{self, Codec};
+use crate::ids;
+use crate::latency::Latency;
+use crate::topology::Shape;
+use crate::workloads;
+use crate::{CounterMode, ReadMode, Workload};
+use anyhow::{anyhow, bail};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Config {
+    // tee every stdin line to this file along with when it arrived
+    pub record: Option<PathBuf>,
+    // read input from a previous --record file instead of stdin
+    pub replay: Option<PathBuf>,
+    // keep at most this many broadcast values in memory, older ones get
+    // spilled to sorted run files under spill_dir (a temp dir by default)
+    pub max_messages_in_memory: Option<usize>,
+    pub spill_dir: Option<PathBuf>,
+    // how generate makes ids: counter (default), ulid or timestamp
+    pub ids: ids::Scheme,
+    // where the counter scheme keeps its epoch across restarts, without it the
+    // epoch is the startup time
+    pub id_epoch_file: Option<PathBuf>,
+    // serve on this address over tcp instead of stdin/stdout
+    pub listen: Option<String>,
+    // binary codec we ask peers to use for sync pages: msgpack, cbor or json
+    pub codec: Option<&'static dyn Codec>,
+    // gossip over this neighbor graph instead of to everyone
+    pub topology: Option<Shape>,
+    // how often unacked rpcs get retransmitted, 300ms by default
+    pub gossip_interval: Option<Duration>,
+    // how long a client waits for an answer before it retries, 5s by default
+    pub client_timeout: Option<Duration>,
+    // hold everything that comes in to tighter limits, for when more than
+    // maelstrom can reach us, see validate.rs
+    pub paranoid: bool,
+    // log every message that takes longer than this to handle, 10ms by default
+    pub slow_handler: Option<Duration>,
+    // messages a second we send that aren't replies, unlimited by default
+    pub gossip_rate: Option<f64>,
+    // hold every outgoing message back this long, for debugging slow links
+    pub inject_latency: Option<Latency>,
+    // run this many nodes in-process against a scripted workload and exit
+    pub selftest: Option<usize>,
+    // run the broadcast simulator at this many nodes over a grid of gossip
+    // intervals, topologies and fanouts, print what each costs and exit. see
+    // sweep.rs
+    pub sweep: Option<usize>,
+    // which challenge we serve, worked out from the traffic if not given.
+    // --selftest defaults to the binary's own
+    // several, comma separated, get served side by side with the first one
+    // answering `read`
+    pub workloads: Vec<Workload>,
+    // the binary's own challenge, for a `read` that shows up before anything
+    // tells us which one we're serving
+    pub default_workload: Option<Workload>,
+    // seq-kv (default) or crdt, where the g-counter keeps its count
+    pub counter: Option<CounterMode>,
+    // how the counter answers `read`: local, quorum or kv-sync
+    pub read_mode: Option<ReadMode>,
+    // how long a seq-kv key read for a local read is reused, 0 turns that off
+    pub kv_cache_ttl: Option<Duration>,
+    // append every message in and out to this file, see audit.rs
+    pub audit: Option<PathBuf>,
+    // throw away this share of what we send, see middleware::Chaos
+    pub drop_rate: Option<f64>,
+    pub drop_seed: u64,
+    pub drop_replies: bool,
+    // cut logged messages at this many bytes, see middleware::Logging
+    pub log_max_bytes: Option<usize>,
+    // only log every nth message of these types
+    pub log_sample: HashMap<String, u64>,
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--record" => config.record = Some(value(&mut args, &arg)?.into()),
+                "--replay" => config.replay = Some(value(&mut args, &arg)?.into()),
+                "--max-messages-in-memory" => {
+                    config.max_messages_in_memory = Some(value(&mut args, &arg)?.parse()?)
+                }
+                "--spill-dir" => config.spill_dir = Some(value(&mut args, &arg)?.into()),
+                "--ids" => config.ids = value(&mut args, &arg)?.parse()?,
+                "--listen" => config.listen = Some(value(&mut args, &arg)?),
+                "--codec" => {
+                    let name = value(&mut args, &arg)?;
+                    config.codec = Some(
+                        codec::by_name(&name).ok_or_else(|| anyhow!("unknown codec {}", name))?,
+                    );
+                }
+                "--selftest" => {
+                    let n = value(&mut args, &arg)?;
+                    let n = n.strip_prefix("n=").unwrap_or(&n).parse()?;
+                    if n == 0 {
+                        bail!("--selftest needs at least one node");
+                    }
+                    config.selftest = Some(n);
+                }
+                "--sweep" => {
+                    let n = value(&mut args, &arg)?;
+                    let n = n.strip_prefix("n=").unwrap_or(&n).parse()?;
+                    if n < 2 {
+                        bail!("--sweep needs at least two nodes");
+                    }
+                    config.sweep = Some(n);
+                }
+                "--topology" => config.topology = Some(value(&mut args, &arg)?.parse()?),
+                "--gossip-interval" => {
+                    let ms = value(&mut args, &arg)?.parse::<u64>()?;
+                    if ms == 0 {
+                        bail!("--gossip-interval is in milliseconds, at least 1");
+                    }
+                    config.gossip_interval = Some(Duration::from_millis(ms));
+                }
+                "--client-timeout" => {
+                    let ms = value(&mut args, &arg)?.parse::<u64>()?;
+                    if ms == 0 {
+                        bail!("--client-timeout is in milliseconds, at least 1");
+                    }
+                    config.client_timeout = Some(Duration::from_millis(ms));
+                }
+                "--paranoid" => config.paranoid = true,
+                "--slow-handler" => {
+                    let ms = value(&mut args, &arg)?.parse::<u64>()?;
+                    config.slow_handler = Some(Duration::from_millis(ms));
+                }
+                "--gossip-rate" => {
+                    let rate = value(&mut args, &arg)?.parse::<f64>()?;
+                    if !(rate >= 1.0 && rate.is_finite()) {
+                        bail!("--gossip-rate needs at least 1 message a second");
+                    }
+                    config.gossip_rate = Some(rate);
+                }
+                "--inject-latency" => {
+                    config.inject_latency = Some(value(&mut args, &arg)?.parse()?)
+                }
+                "--workload" => config.workloads = workloads::parse_list(&value(&mut args, &arg)?)?,
+                "--counter" => config.counter = Some(value(&mut args, &arg)?.parse()?),
+                "--read-mode" => config.read_mode = Some(value(&mut args, &arg)?.parse()?),
+                "--kv-cache-ttl" => {
+                    let ms = value(&mut args, &arg)?.parse::<u64>()?;
+                    config.kv_cache_ttl = Some(Duration::from_millis(ms));
+                }
+                "--audit" => config.audit = Some(value(&mut args, &arg)?.into()),
+                "--drop-rate" => {
+                    let rate = value(&mut args, &arg)?.parse::<f64>()?;
+                    if !(0.0..=1.0).contains(&rate) {
+                        bail!("--drop-rate is a probability, between 0 and 1");
+                    }
+                    config.drop_rate = Some(rate);
+                }
+                "--drop-seed" => config.drop_seed = value(&mut args, &arg)?.parse()?,
+                "--drop-replies" => config.drop_replies = true,
+                "--log-max-bytes" => config.log_max_bytes = Some(value(&mut args, &arg)?.parse()?),
+                "--log-sample" => {
+                    for sample in value(&mut args, &arg)?.split(',') {
+                        let (kind, every) = sample
+                            .split_once('=')
+                            .ok_or_else(|| anyhow!("--log-sample wants type=n, got {}", sample))?;
+                        let every = every.parse::<u64>()?;
+                        if every == 0 {
+                            bail!("--log-sample {}: n has to be at least 1", kind);
+                        }
+                        config.log_sample.insert(kind.to_string(), every);
+                    }
+                }
+                "--id-epoch-file" => config.id_epoch_file = Some(value(&mut args, &arg)?.into()),
+                _ => bail!("unknown argument {}", arg),
+            }
+        }
+        if config.record.is_some() && config.replay.is_some() {
+            bail!("--record and --replay can't be used together");
+        }
+        if config.drop_rate.is_none() && config.drop_replies {
+            bail!("--drop-replies only makes sense with --drop-rate");
+        }
+        if config.read_mode == Some(ReadMode::KvSync) && config.counter == Some(CounterMode::Crdt) {
+            bail!("--read-mode kv-sync needs --counter seq-kv");
+        }
+        if config.workloads.contains(&Workload::Broadcast)
+            && config.workloads.contains(&Workload::Counter)
+        {
+            bail!("--workload can't have both broadcast and g-counter, a client's read can't say which it wants");
+        }
+        if config.selftest.is_some() && config.sweep.is_some() {
+            bail!("--selftest and --sweep can't be used together");
+        }
+        if config.listen.is_some() && config.replay.is_some() {
+            bail!("--listen and --replay can't be used together");
+        }
+        Ok(config)
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> anyhow::Result<String> {
+    args.next().ok_or_else(|| anyhow!("{} needs a value", flag))
+}
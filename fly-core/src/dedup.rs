@@ -0,0 +1,94 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+
+// maelstrom clients retry a request when they don't hear back in time, so the
+// same (src, msg_id) can show up more than once. we remember what we answered
+// the first time and hand out the same answer again instead of applying the
+// request twice. entries go away after `ttl` (nobody retries that late) or,
+// oldest first, once there are more than `cap` of them
+pub struct DedupCache<V> {
+    ttl: Duration,
+    cap: usize,
+    entries: HashMap<(String, u64), V>,
+    // insertion order, which is also expiry order since the ttl is fixed
+    order: VecDeque<((String, u64), Instant)>,
+}
+
+impl<V> DedupCache<V> {
+    pub fn new(ttl: Duration, cap: usize) -> Self {
+        DedupCache {
+            ttl,
+            cap: cap.max(1),
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn get(&self, src: &str, msg_id: u64) -> Option<&V> {
+        self.entries.get(&(src.to_string(), msg_id))
+    }
+
+    // returns how many entries got dropped to make room
+    pub fn insert(&mut self, src: String, msg_id: u64, value: V, now: Instant) -> usize {
+        let key = (src, msg_id);
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.order.push_back((key, now));
+        }
+        self.expire(now);
+        let mut evicted = 0;
+        while self.entries.len() > self.cap {
+            let Some((key, _)) = self.order.pop_front() else {
+                break;
+            };
+            self.entries.remove(&key);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        while let Some((key, at)) = self.order.front() {
+            if now.duration_since(*at) < self.ttl {
+                break;
+            }
+            self.entries.remove(key);
+            self.order.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire() {
+        let mut cache = DedupCache::new(Duration::from_secs(10), 100);
+        cache.insert("c1".into(), 1, "a", Instant::now());
+        tokio::time::advance(Duration::from_secs(5)).await;
+        cache.insert("c1".into(), 2, "b", Instant::now());
+        assert_eq!(cache.get("c1", 1), Some(&"a"));
+        assert_eq!(cache.get("c2", 1), None);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        cache.expire(Instant::now());
+        assert_eq!(cache.get("c1", 1), None);
+        assert_eq!(cache.get("c1", 2), Some(&"b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oldest_go_first_over_cap() {
+        let mut cache = DedupCache::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        assert_eq!(cache.insert("c1".into(), 1, (), now), 0);
+        assert_eq!(cache.insert("c1".into(), 2, (), now), 0);
+        assert_eq!(cache.insert("c2".into(), 1, (), now), 1);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("c1", 1).is_none());
+        assert!(cache.get("c2", 1).is_some());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::error::{invalid, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+// compact encoding for big sets of integers that only our own nodes speak:
+// sort, take the difference to the previous value, write each one as a LEB128
+// varint and base64 the lot so it still fits in a json string. maelstrom's
+// broadcast values are dense small integers, so most deltas end up one byte
+// instead of the 3-7 characters (plus comma) they take in a json array
+pub fn encode(values: &[usize]) -> String {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    let mut bytes = Vec::with_capacity(sorted.len() + 8);
+    let mut prev = 0;
+    for v in sorted {
+        put_varint(&mut bytes, (v - prev) as u64);
+        prev = v;
+    }
+    STANDARD.encode(bytes)
+}
+
+pub fn decode(encoded: &str) -> Result<Vec<usize>> {
+    let mut values = Vec::new();
+    let mut prev = 0usize;
+    for delta in varints(encoded)? {
+        prev = prev
+            .checked_add(delta as usize)
+            .ok_or_else(|| invalid("delta overflows"))?;
+        values.push(prev);
+    }
+    Ok(values)
+}
+
+// LEB128, also what ranges.rs writes
+pub(crate) fn put_varint(bytes: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+// the other way round, base64 included
+pub(crate) fn varints(encoded: &str) -> Result<Vec<u64>> {
+    let bytes = STANDARD.decode(encoded).map_err(invalid)?;
+    let mut values = Vec::new();
+    let (mut v, mut shift) = (0u64, 0u32);
+    for byte in bytes {
+        if shift >= 64 {
+            return Err(invalid("varint too long"));
+        }
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            values.push(v);
+            v = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+        }
+    }
+    if shift != 0 {
+        return Err(invalid("truncated varint"));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_sorted() {
+        let values = vec![300, 0, 5, 1 << 40, 7, 128, 127];
+        let mut sorted = values.clone();
+        sorted.sort_unstable();
+        assert_eq!(decode(&encode(&values)).unwrap(), sorted);
+        assert_eq!(decode(&encode(&[])).unwrap(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn dense_values_take_a_byte_each() {
+        let values = (0..1000).collect::<Vec<_>>();
+        let raw = STANDARD.decode(encode(&values)).unwrap();
+        assert_eq!(raw.len(), 1000);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode("not base64!").is_err());
+        assert!(decode(&STANDARD.encode([0x80])).is_err());
+        assert!(decode(&STANDARD.encode([0xff; 11])).is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use std::io;
+
+// everything that can go wrong handling a message. none of it takes the node
+// down: receive() logs it and drops the message, only failing to write our own
+// output ends a run
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("malformed message: {0}")]
+    Json(#[from] serde_json::Error),
+    // well formed json that still doesn't make sense, a delta that doesn't
+    // decode, a codec we don't know and so on
+    #[error("invalid message: {0}")]
+    Invalid(String),
+    #[error("input ended before init")]
+    NotInitialized,
+    // the node's loop ended while a NodeHandle still wanted something from it
+    #[error("node is gone")]
+    NodeGone,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub(crate) fn invalid(e: impl std::fmt::Display) -> Error {
+    Error::Invalid(e.to_string())
+}
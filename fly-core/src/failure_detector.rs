@@ -0,0 +1,187 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+
+// phi accrual failure detector (hayashibara et al., same approximation akka uses).
+// instead of a yes/no timeout it keeps the distribution of inter-arrival times
+// of acks from each peer and tells you how surprised you should be that nothing
+// arrived since the last one: phi = -log10(P(next ack arrives later than now)).
+// phi 1 means ~10% chance we're wrong when suspecting the peer, 2 means ~1% etc.
+pub struct FailureDetector {
+    window: usize,
+    min_std_dev: f64,
+    acceptable_pause: f64,
+    peers: HashMap<String, History>,
+}
+
+struct History {
+    last: Instant,
+    // millis, most recent at the back
+    intervals: VecDeque<f64>,
+}
+
+impl History {
+    fn mean(&self) -> f64 {
+        self.intervals.iter().sum::<f64>() / self.intervals.len() as f64
+    }
+
+    fn std_dev(&self, mean: f64) -> f64 {
+        let var = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean) * (i - mean))
+            .sum::<f64>()
+            / self.intervals.len() as f64;
+        var.sqrt()
+    }
+}
+
+impl FailureDetector {
+    pub fn new(window: usize, min_std_dev: Duration, acceptable_pause: Duration) -> Self {
+        FailureDetector {
+            window,
+            min_std_dev: min_std_dev.as_secs_f64() * 1000.0,
+            acceptable_pause: acceptable_pause.as_secs_f64() * 1000.0,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn heartbeat(&mut self, peer: &str, now: Instant) {
+        match self.peers.get_mut(peer) {
+            Some(h) => {
+                let interval = now.duration_since(h.last).as_secs_f64() * 1000.0;
+                h.last = now;
+                if h.intervals.len() == self.window {
+                    h.intervals.pop_front();
+                }
+                h.intervals.push_back(interval);
+            }
+            None => {
+                self.peers.insert(
+                    peer.to_string(),
+                    History {
+                        last: now,
+                        intervals: VecDeque::with_capacity(self.window),
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn remove(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+
+    // 0.0 until we've seen at least a couple of acks, the caller has to fall back
+    // to something dumber (counting missed rounds) for peers we know nothing about.
+    // the silence only counts from `since` on if that's later than the last
+    // ack: for a peer we only expect to hear from while we're waiting on it, a
+    // quiet stretch before that says nothing
+    pub fn phi(&self, peer: &str, since: Option<Instant>, now: Instant) -> f64 {
+        let h = match self.peers.get(peer) {
+            Some(h) if h.intervals.len() >= 2 => h,
+            _ => return 0.0,
+        };
+        let quiet_from = since.map_or(h.last, |since| since.max(h.last));
+        let elapsed = now.saturating_duration_since(quiet_from).as_secs_f64() * 1000.0;
+        let mean = h.mean() + self.acceptable_pause;
+        let std_dev = h.std_dev(h.mean()).max(self.min_std_dev);
+
+        // logistic approximation of the normal cdf
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let p_later = if elapsed > mean {
+            e / (1.0 + e)
+        } else {
+            1.0 - 1.0 / (1.0 + e)
+        };
+        -p_later.max(f64::MIN_POSITIVE).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    // a heartbeat every 100ms, exactly
+    fn regular(min_std_dev: Duration, acceptable_pause: Duration) -> (FailureDetector, Instant) {
+        let mut d = FailureDetector::new(100, min_std_dev, acceptable_pause);
+        let mut now = Instant::now();
+        for _ in 0..20 {
+            d.heartbeat("n2", now);
+            now += 100 * MS;
+        }
+        (d, now - 100 * MS)
+    }
+
+    #[test]
+    fn nothing_to_go_on_is_no_suspicion() {
+        let mut d = FailureDetector::new(100, 50 * MS, Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(d.phi("n2", None, now + 1000 * MS), 0.0);
+        d.heartbeat("n2", now);
+        d.heartbeat("n2", now + 100 * MS);
+        assert_eq!(d.phi("n2", None, now + 1000 * MS), 0.0);
+    }
+
+    #[test]
+    fn phi_stays_low_while_heartbeats_keep_coming() {
+        let (d, last) = regular(50 * MS, Duration::ZERO);
+        assert!(d.phi("n2", None, last) < 0.1);
+        assert!(d.phi("n2", None, last + 50 * MS) < 0.1);
+        // right at the mean it's a coin flip, -log10(0.5)
+        let at_mean = d.phi("n2", None, last + 100 * MS);
+        assert!((at_mean - 0.5f64.log10().abs()).abs() < 0.01, "{}", at_mean);
+    }
+
+    #[test]
+    fn phi_climbs_with_the_gap() {
+        let (d, last) = regular(50 * MS, Duration::ZERO);
+        let phis = [150, 200, 300, 400].map(|gap| d.phi("n2", None, last + gap * MS));
+        assert!(phis.windows(2).all(|w| w[0] < w[1]), "{:?}", phis);
+        assert!(phis[3] > 8.0, "{:?}", phis);
+
+        // a heartbeat and it's quiet again
+        let mut d = d;
+        d.heartbeat("n2", last + 400 * MS);
+        assert!(d.phi("n2", None, last + 450 * MS) < 1.0);
+    }
+
+    #[test]
+    fn silence_before_we_were_waiting_doesnt_count() {
+        let (d, last) = regular(50 * MS, Duration::ZERO);
+        let now = last + 2000 * MS;
+        assert!(d.phi("n2", None, now) > 8.0);
+        assert!(d.phi("n2", Some(now - 50 * MS), now) < 0.1);
+        // waiting since before the last ack is the same as not saying
+        assert_eq!(
+            d.phi("n2", Some(last - 500 * MS), now),
+            d.phi("n2", None, now)
+        );
+    }
+
+    #[test]
+    fn min_std_dev_keeps_regular_peers_from_being_judged_too_harshly() {
+        // the intervals never vary, so the floor is all the spread there is
+        let (tight, last) = regular(5 * MS, Duration::ZERO);
+        let (loose, _) = regular(100 * MS, Duration::ZERO);
+        let late = last + 150 * MS;
+        assert!(tight.phi("n2", None, late) > 8.0);
+        assert!(loose.phi("n2", None, late) < 1.0);
+    }
+
+    #[test]
+    fn acceptable_pause_moves_the_mean() {
+        let (strict, last) = regular(50 * MS, Duration::ZERO);
+        let (patient, _) = regular(50 * MS, 300 * MS);
+        let late = last + 400 * MS;
+        assert!(strict.phi("n2", None, late) > 8.0);
+        let patient_phi = patient.phi("n2", None, late);
+        assert!(
+            (patient_phi - 0.5f64.log10().abs()).abs() < 0.01,
+            "{}",
+            patient_phi
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, LinesCodec, LinesCodecError};
+
+// nothing legit gets anywhere near this, a full read_ok of a long broadcast
+// run is a few MB
+const MAX_LINE: usize = 64 * 1024 * 1024;
+
+// what every transport reads: one json message per line, but forgiving about
+// \r\n, whitespace around the message and blank lines. a line over the limit
+// comes back as an error and gets skipped, the next one is read normally.
+// messages split across reads are just buffered until their newline shows up
+pub struct JsonLines(LinesCodec);
+
+impl JsonLines {
+    pub fn new() -> Self {
+        Self::with_max_length(MAX_LINE)
+    }
+
+    pub fn with_max_length(max: usize) -> Self {
+        JsonLines(LinesCodec::new_with_max_length(max))
+    }
+}
+
+impl Default for JsonLines {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn trimmed(line: String) -> Option<String> {
+    match line.trim() {
+        "" => None,
+        t if t.len() == line.len() => Some(line),
+        t => Some(t.to_string()),
+    }
+}
+
+impl Decoder for JsonLines {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        while let Some(line) = self.0.decode(buf)? {
+            if let Some(line) = trimmed(line) {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        while let Some(line) = self.0.decode_eof(buf)? {
+            if let Some(line) = trimmed(line) {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut JsonLines, chunks: &[&str]) -> Vec<Result<String, String>> {
+        let mut buf = BytesMut::new();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            buf.extend_from_slice(chunk.as_bytes());
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(line)) => out.push(Ok(line)),
+                    Ok(None) => break,
+                    Err(e) => out.push(Err(e.to_string())),
+                }
+            }
+        }
+        while let Ok(Some(line)) = codec.decode_eof(&mut buf) {
+            out.push(Ok(line));
+        }
+        out
+    }
+
+    #[test]
+    fn tolerates_crlf_whitespace_and_split_reads() {
+        let out = decode_all(
+            &mut JsonLines::new(),
+            &["{\"a\":", "1}\r\n\r\n  {\"b\":2}  \n", "\t\n{\"c\"", ":3}"],
+        );
+        assert_eq!(
+            out,
+            [
+                Ok(r#"{"a":1}"#.into()),
+                Ok(r#"{"b":2}"#.into()),
+                Ok(r#"{"c":3}"#.into())
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_lines_over_the_limit() {
+        let long = format!("{}\n", "x".repeat(100));
+        let out = decode_all(&mut JsonLines::with_max_length(10), &[&long, "{}\n"]);
+        assert!(out[0].is_err());
+        assert_eq!(out[1..], [Ok("{}".to_string())]);
+    }
+}
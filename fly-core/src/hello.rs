@@ -0,0 +1,106 @@
+use crate::compression;
+use crate::{Msg, Node, Payload, Prepared, Result};
+use tokio::io::AsyncWrite;
+
+// what our nodes tell each other right after startup (and new members when
+// they join): which wire protocol they speak and what they can do on top of
+// plain maelstrom json. anything in FEATURES only gets used towards a peer
+// that said it has it too, so a cluster of mixed binaries keeps working while
+// one of them is rolled out. bump PROTOCOL_VERSION with every wire change,
+// a hello without one is from before versions existed and counts as 0
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// delta: sync pages can come back delta encoded, see delta.rs
+// encoded: payloads wrapped in Encoded, i.e. the binary codecs, see codec.rs
+// ranges: sync pages can come back as ranges, see ranges.rs
+// relay: broadcasts passed on for a peer that can't reach the target itself
+// resync: everything pushed at once after a restart, see status.rs
+pub const FEATURES: [&str; 5] = ["delta", "encoded", "ranges", "relay", "resync"];
+
+// what a peer told us in its hello
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+fn features() -> Vec<String> {
+    FEATURES.iter().map(|f| f.to_string()).collect()
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // fire and forget, older nodes don't know hello and would never answer.
+    // if it gets lost we just don't use anything extra with that peer
+    pub(crate) fn say_hello(&mut self, peers: &[String]) -> Result<()> {
+        let hello = Prepared::new(&Payload::Hello {
+            compression: compression::names(),
+            version: PROTOCOL_VERSION,
+            features: features(),
+        })?;
+        for peer in peers {
+            let msg_id = self.next_msg_id();
+            self.enqueue(peer, msg_id, None, &hello);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn hello(
+        &mut self,
+        msg: &Msg,
+        compression: &[String],
+        version: &u32,
+        features: &[String],
+    ) -> Result<Option<Payload>> {
+        self.learn_capabilities(&msg.src, compression, *version, features);
+        Ok(Some(Payload::HelloOk {
+            compression: compression::names(),
+            version: PROTOCOL_VERSION,
+            features: self::features(),
+        }))
+    }
+
+    pub(crate) fn hello_ok(
+        &mut self,
+        msg: &Msg,
+        compression: &[String],
+        version: &u32,
+        features: &[String],
+    ) -> Result<Option<Payload>> {
+        self.learn_capabilities(&msg.src, compression, *version, features);
+        Ok(None)
+    }
+
+    // whether we can use `feature` towards `peer`. no hello from it (yet)
+    // means no
+    pub(crate) fn peer_supports(&self, peer: &str, feature: &str) -> bool {
+        self.peer_capabilities
+            .get(peer)
+            .is_some_and(|c| c.features.iter().any(|f| f == feature))
+    }
+
+    fn learn_capabilities(
+        &mut self,
+        peer: &str,
+        compression: &[String],
+        version: u32,
+        features: &[String],
+    ) {
+        if !self.nodes.iter().any(|n| n == peer) {
+            return;
+        }
+        self.learn_compression(peer, compression);
+        if version != PROTOCOL_VERSION {
+            eprintln!(
+                "{} speaks protocol {}, we speak {}",
+                peer, version, PROTOCOL_VERSION
+            );
+        }
+        let features = features
+            .iter()
+            .filter(|f| FEATURES.contains(&f.as_str()))
+            .cloned()
+            .collect();
+        self.peer_capabilities
+            .insert(peer.to_string(), Capabilities { version, features });
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// how the generate workload makes its ids. anything works as long as no two
+// calls anywhere in the cluster ever return the same string
+pub trait IdGenerator: Send {
+    fn next_id(&mut self, node_id: &str) -> String;
+}
+
+// {node}-{epoch}-{seq}. node ids are unique and seq only ever goes up, but it
+// starts over when the process does, so every run of the node needs an epoch
+// it hasn't used before (see persisted_epoch and startup_epoch). seq is our
+// own, not the msg_id counter, so ids are dense no matter how much else the
+// node sends
+pub struct NodeCounter {
+    epoch: u64,
+    seq: u64,
+}
+
+impl NodeCounter {
+    pub fn new(epoch: u64) -> Self {
+        NodeCounter { epoch, seq: 0 }
+    }
+}
+
+impl IdGenerator for NodeCounter {
+    fn next_id(&mut self, node_id: &str) -> String {
+        let id = format!("{}-{}-{}", node_id, self.epoch, self.seq);
+        self.seq += 1;
+        id
+    }
+}
+
+// bumps the number stored in `path` and returns the new value, so each start
+// gets the next epoch. the file is replaced with a rename so a crash halfway
+// through leaves either the old or the new value, never an empty file
+pub fn persisted_epoch(path: &Path) -> io::Result<u64> {
+    let last = match fs::read_to_string(path) {
+        Ok(s) => s
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    let epoch = last + 1;
+    let tmp = path.with_extension("tmp");
+    {
+        let file = fs::File::create(&tmp)?;
+        io::Write::write_all(&mut &file, epoch.to_string().as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(epoch)
+}
+
+// without a file to keep it in, the time we started is the next best thing,
+// a restart would have to happen within the same millisecond to collide
+pub fn startup_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// 128 bits of time + randomness, sortable by creation time. the generator
+// keeps ids from the same node strictly increasing even within a millisecond
+#[derive(Default)]
+pub struct Ulid(ulid::Generator);
+
+impl IdGenerator for Ulid {
+    fn next_id(&mut self, _node_id: &str) -> String {
+        match self.0.generate() {
+            Ok(id) => id.to_string(),
+            Err(overflow) => overflow.commit_overflow_increment().to_string(),
+        }
+    }
+}
+
+// {unix millis}-{node}-{n-th id in that millisecond}, snowflake style. if the
+// wall clock goes backwards we stay on the last millisecond we used instead
+#[derive(Default)]
+pub struct Timestamp {
+    last_millis: u128,
+    seq: u64,
+}
+
+impl Timestamp {
+    fn next(&mut self, node_id: &str, now_millis: u128) -> String {
+        if now_millis > self.last_millis {
+            self.last_millis = now_millis;
+            self.seq = 0;
+        } else {
+            self.seq += 1;
+        }
+        format!("{}-{}-{}", self.last_millis, node_id, self.seq)
+    }
+}
+
+impl IdGenerator for Timestamp {
+    fn next_id(&mut self, node_id: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        self.next(node_id, now)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    #[default]
+    Counter,
+    Ulid,
+    Timestamp,
+}
+
+impl Scheme {
+    // the epoch only matters for the counter scheme
+    pub fn generator(self, epoch: u64) -> Box<dyn IdGenerator> {
+        match self {
+            Scheme::Counter => Box::new(NodeCounter::new(epoch)),
+            Scheme::Ulid => Box::new(Ulid::default()),
+            Scheme::Timestamp => Box::new(Timestamp::default()),
+        }
+    }
+
+    // the --ids spelling
+    pub fn name(self) -> &'static str {
+        match self {
+            Scheme::Counter => "counter",
+            Scheme::Ulid => "ulid",
+            Scheme::Timestamp => "timestamp",
+        }
+    }
+}
+
+impl std::str::FromStr for Scheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Scheme> {
+        match s {
+            "counter" => Ok(Scheme::Counter),
+            "ulid" => Ok(Scheme::Ulid),
+            "timestamp" => Ok(Scheme::Timestamp),
+            _ => anyhow::bail!(
+                "unknown id scheme {}, expected counter, ulid or timestamp",
+                s
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn unique(ids: &mut dyn IdGenerator) {
+        let mut seen = HashSet::new();
+        for node in ["n1", "n2"]
+            .iter()
+            .flat_map(|n| std::iter::repeat_n(*n, 1000))
+        {
+            let id = ids.next_id(node);
+            assert!(seen.insert(id.clone()), "{} handed out twice", id);
+        }
+    }
+
+    #[test]
+    fn every_scheme_is_unique() {
+        for scheme in [Scheme::Counter, Scheme::Ulid, Scheme::Timestamp] {
+            unique(scheme.generator(1).as_mut());
+        }
+    }
+
+    #[test]
+    fn counter_ids_are_dense() {
+        let mut ids = NodeCounter::new(7);
+        let generated = (0..3).map(|_| ids.next_id("n1")).collect::<Vec<_>>();
+        assert_eq!(generated, ["n1-7-0", "n1-7-1", "n1-7-2"]);
+    }
+
+    #[test]
+    fn restarts_get_a_new_epoch() {
+        let path = std::env::temp_dir().join(format!("fly-ids-epoch-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let first = persisted_epoch(&path).unwrap();
+        let second = persisted_epoch(&path).unwrap();
+        assert_eq!((first, second), (1, 2));
+        // seq starts over after the "restart", the id doesn't repeat
+        assert_ne!(
+            NodeCounter::new(first).next_id("n1"),
+            NodeCounter::new(second).next_id("n1")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ulids_increase() {
+        let mut ids = Ulid::default();
+        let generated = (0..1000).map(|_| ids.next_id("n1")).collect::<Vec<_>>();
+        assert!(generated.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn timestamps_survive_the_clock_going_back() {
+        let mut ids = Timestamp::default();
+        assert_eq!(ids.next("n1", 1000), "1000-n1-0");
+        assert_eq!(ids.next("n1", 1000), "1000-n1-1");
+        assert_eq!(ids.next("n1", 999), "1000-n1-2");
+        assert_eq!(ids.next("n1", 1001), "1001-n1-0");
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// values we've read from a kv service, good for `ttl` after they were read.
+// only for reads that can be stale anyway: whatever we get from seq-kv
+// without a fence is already allowed to be old, this just makes it old
+// without a round trip. a write of ours drops the key, what we had before
+// it can't be what's in there now. a ttl of zero turns it off
+#[derive(Debug)]
+pub struct KvCache {
+    ttl: Duration,
+    values: HashMap<String, (Instant, u64)>,
+}
+
+// half a counter round, a node's key doesn't change more often than once a
+// round anyway. --kv-cache-ttl
+pub const KV_CACHE_TTL: Duration = Duration::from_millis(500);
+
+impl Default for KvCache {
+    fn default() -> Self {
+        KvCache::new(KV_CACHE_TTL)
+    }
+}
+
+impl KvCache {
+    pub fn new(ttl: Duration) -> Self {
+        KvCache {
+            ttl,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+        self.values.clear();
+    }
+
+    pub fn get(&self, key: &str, now: Instant) -> Option<u64> {
+        let (at, value) = self.values.get(key)?;
+        (now.saturating_duration_since(*at) < self.ttl).then_some(*value)
+    }
+
+    pub fn put(&mut self, key: String, value: u64, now: Instant) {
+        if !self.ttl.is_zero() {
+            self.values.insert(key, (now, value));
+        }
+    }
+
+    pub fn invalidate(&mut self, key: &str) {
+        self.values.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_last_until_the_ttl_or_a_write() {
+        let start = Instant::now();
+        let mut cache = KvCache::new(Duration::from_millis(100));
+        assert_eq!(cache.get("counter-n1", start), None);
+        cache.put("counter-n1".to_string(), 7, start);
+        assert_eq!(cache.get("counter-n1", start), Some(7));
+        let later = start + Duration::from_millis(99);
+        assert_eq!(cache.get("counter-n1", later), Some(7));
+        assert_eq!(
+            cache.get("counter-n1", later + Duration::from_millis(1)),
+            None
+        );
+
+        cache.put("counter-n1".to_string(), 8, later);
+        cache.invalidate("counter-n1");
+        assert_eq!(cache.get("counter-n1", later), None);
+
+        let mut off = KvCache::new(Duration::ZERO);
+        off.put("counter-n1".to_string(), 7, start);
+        assert_eq!(off.get("counter-n1", start), None);
+    }
+}
@@ -0,0 +1,100 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use tokio::time::{Duration, Instant};
+
+// --inject-latency ms[:jitter], a debug knob that makes our own links slow so
+// maelstrom's latency problems can be had locally: every line we write is held
+// back for `base` plus up to `jitter` more. with jitter lines can overtake each
+// other, same as they can on maelstrom's network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    pub base: Duration,
+    pub jitter: Duration,
+}
+
+impl std::str::FromStr for Latency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Latency> {
+        let (base, jitter) = s.split_once(':').unwrap_or((s, "0"));
+        Ok(Latency {
+            base: Duration::from_millis(base.parse()?),
+            jitter: Duration::from_millis(jitter.parse()?),
+        })
+    }
+}
+
+// the lines held back, by when they're due. the counter keeps lines due at
+// the same instant in the order they came in
+pub struct DelayLine {
+    latency: Latency,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<(Instant, u64, String)>>,
+    pushed: u64,
+}
+
+impl DelayLine {
+    pub fn new(latency: Latency) -> Self {
+        DelayLine {
+            latency,
+            rng: StdRng::from_os_rng(),
+            queue: BinaryHeap::new(),
+            pushed: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: String, now: Instant) {
+        let jitter = self.rng.random_range(Duration::ZERO..=self.latency.jitter);
+        self.pushed += 1;
+        self.queue.push(Reverse((
+            now + self.latency.base + jitter,
+            self.pushed,
+            line,
+        )));
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Option<String> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.queue.pop().map(|Reverse((_, _, line))| line)
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((at, _, _))| *at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_base_and_jitter() {
+        let latency = "250:100".parse::<Latency>().unwrap();
+        assert_eq!(latency.base, Duration::from_millis(250));
+        assert_eq!(latency.jitter, Duration::from_millis(100));
+        assert_eq!("50".parse::<Latency>().unwrap().jitter, Duration::ZERO);
+        assert!("fast".parse::<Latency>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lines_come_out_once_due() {
+        let mut delay = DelayLine::new("100:50".parse().unwrap());
+        let start = Instant::now();
+        for i in 0..10 {
+            delay.push(i.to_string(), start);
+        }
+        assert_eq!(delay.pop_due(start + Duration::from_millis(99)), None);
+        let due = delay.next_due().unwrap();
+        assert!(due >= start + Duration::from_millis(100));
+        let mut out = Vec::new();
+        while let Some(line) = delay.pop_due(start + Duration::from_millis(150)) {
+            out.push(line);
+        }
+        out.sort();
+        assert_eq!(out, (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+}
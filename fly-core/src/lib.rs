@@ -0,0 +1,2684 @@
+use futures_util::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::io;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
+use tokio_util::codec::{FramedRead, LinesCodecError};
+
+#[macro_use]
+mod macros;
+pub mod actor;
+pub mod audit;
+pub mod cli;
+pub mod codec;
+pub mod compression;
+pub mod config;
+mod dedup;
+mod delta;
+mod error;
+mod failure_detector;
+pub mod framing;
+pub mod hello;
+pub mod ids;
+mod kvcache;
+pub mod latency;
+mod metrics;
+pub mod middleware;
+#[cfg(any(test, feature = "dev-tools"))]
+pub mod oracle;
+mod ranges;
+mod ratelimit;
+pub mod replay;
+pub mod scheduler;
+#[cfg(any(test, feature = "dev-tools"))]
+pub mod selftest;
+#[cfg(any(test, feature = "dev-tools"))]
+pub mod simulator;
+mod startup;
+pub mod status;
+mod store;
+#[cfg(any(test, feature = "dev-tools"))]
+pub mod sweep;
+pub mod tcp;
+pub mod topology;
+mod validate;
+mod workloads;
+use actor::{Command, Mailbox, NodeHandle};
+use codec::Codec;
+use compression::Compression;
+use config::Config;
+pub use error::{Error, Result};
+use failure_detector::FailureDetector;
+use framing::JsonLines;
+use hello::Capabilities;
+use ids::IdGenerator;
+use latency::{DelayLine, Latency};
+use metrics::Metrics;
+use middleware::{Chaos, Dedup, Logging, Middleware};
+use ratelimit::TokenBucket;
+use scheduler::{Scheduler, Tick, TimerId};
+use status::Status;
+use store::MessageStore;
+use workloads::broadcast::{self, Broadcast};
+use workloads::checksum::{self, Divergence};
+use workloads::counter::{self, Counter};
+pub use workloads::counter::{CounterMode, ReadMode};
+pub use workloads::{Workload, WorkloadState};
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(300);
+
+// maelstrom error codes we send
+pub const NOT_SUPPORTED: u32 = 10;
+pub const MALFORMED_REQUEST: u32 = 12;
+// and the ones seq-kv sends us
+pub const KEY_DOES_NOT_EXIST: u32 = 20;
+pub const PRECONDITION_FAILED: u32 = 22;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Clone, Deserialize, Debug)]
+pub struct Msg {
+    pub src: String,
+    pub dest: String,
+    pub body: Body,
+}
+
+// every message we know about and which workload handles it, the handlers
+// live in workloads/
+workload! {
+    Init { node_id: String, node_ids: Vec<String> } => init,
+    InitOk,
+    // not part of maelstrom, lets a harness change the cluster at runtime
+    Membership { node_ids: Vec<String> } => membership,
+    MembershipOk,
+    // not part of maelstrom either, see workloads/dump.rs
+    DumpState {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    } => dump_state,
+    DumpStateOk { path: String },
+    // nor this, see workloads/params.rs
+    SetParam { name: String, value: String } => set_param,
+    SetParamOk { was: String },
+
+    Echo { echo: String } => echo,
+    EchoOk { echo: String },
+
+    Generate => generate,
+    GenerateOk { id: String },
+
+    Broadcast { message: usize } => broadcast,
+    BroadcastOk => broadcast_ok,
+    // between our nodes: a broadcast the sender couldn't get to `to` itself,
+    // see workloads/broadcast.rs
+    Relay { to: String, message: usize } => relay,
+    RelayOk => broadcast_ok,
+    // see workloads/broadcast.rs for after and limit
+    //
+    // our own nodes also ask for `encoding: "delta"`, in which case the values
+    // come back in `delta` (see delta.rs) and `messages` is empty, or for
+    // `"ranges"`, which comes back in `ranges` (see ranges.rs) and where
+    // `limit` counts ranges rather than values. they can also ask
+    // for the whole answer in a binary `codec` (see codec.rs). anything that
+    // doesn't know about them just ignores the fields and answers in plain json
+    //
+    // the counter's read (and seq-kv's) is the same message, with a `value`
+    // in the reply instead of `messages`. `key` is only on reads we send to
+    // seq-kv, see workloads/counter.rs
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    } => read,
+    ReadOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        messages: Option<Vec<usize>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ranges: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<u64>,
+    } => read_ok,
+    Topology { topology: HashMap<String, Vec<String>> } => topology,
+    TopologyOk,
+
+    Add { delta: u64 } => add,
+    AddOk,
+    // what we send seq-kv, the reads are Read above
+    Write { key: String, value: u64 },
+    WriteOk => write_ok,
+    Cas {
+        key: String,
+        from: u64,
+        to: u64,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk => cas_ok,
+    // --counter crdt, every node's total as the sender knows it. not part of
+    // maelstrom, only between our nodes
+    CounterState { counts: HashMap<String, u64> } => counter_state,
+    // --read-mode quorum asking a peer for the counts it knows, with seq-kv
+    // that's only its own
+    CounterPoll => counter_poll,
+    CounterPollOk { counts: HashMap<String, u64> } => counter_poll_ok,
+    // a hash of everything the sender has applied, see workloads/checksum.rs.
+    // hex, a u64 doesn't survive every json parser
+    Checksum { checksum: String } => checksum,
+
+    // maelstrom's error reply, see the codes below
+    Error { code: u32, text: String } => error,
+
+    // some other payload in a codec other than json and/or compressed, only
+    // between our nodes
+    Encoded {
+        codec: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<String>,
+        data: String,
+    } => encoded,
+    // what a node can do, sent to every peer at startup, see hello.rs
+    Hello {
+        compression: Vec<String>,
+        #[serde(default)]
+        version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    } => hello,
+    HelloOk {
+        compression: Vec<String>,
+        #[serde(default)]
+        version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    } => hello_ok,
+    // who's behind a node id, sent along with hello, see status.rs
+    Status {
+        started: u64,
+        epoch: u64,
+        workloads: Vec<String>,
+    } => status,
+    StatusOk {
+        started: u64,
+        epoch: u64,
+        workloads: Vec<String>,
+    } => status_ok,
+    // a chunk of everything we have, for a peer that restarted, see
+    // Node::push_broadcasts. ranges as in ranges.rs
+    Resync { ranges: String } => resync,
+    ResyncOk => resync_ok,
+}
+
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Delta,
+    Ranges,
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug)]
+pub struct Body {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<u64>,
+
+    #[serde(flatten)]
+    pub extra: Payload,
+}
+
+// a payload serialized once up front. the envelope (src, dest, msg_id, ...)
+// gets spliced in per destination, so fanning a value out to every peer or
+// retransmitting it round after round never runs serde on it again
+struct Prepared {
+    // the payload's json object minus the opening brace, e.g.
+    // "type":"broadcast","message":7}
+    fields: String,
+}
+
+impl Prepared {
+    fn new(payload: &Payload) -> Result<Self> {
+        let mut fields = serde_json::to_string(payload)?;
+        // internally tagged, so it's always a non empty object
+        fields.remove(0);
+        Ok(Prepared { fields })
+    }
+
+    fn is(&self, kind: &str) -> bool {
+        self.fields
+            .strip_prefix(r#""type":""#)
+            .and_then(|rest| rest.strip_prefix(kind))
+            .is_some_and(|rest| rest.starts_with('"'))
+    }
+
+    fn frame(&self, src: &str, dest: &str, msg_id: u64, in_reply_to: Option<u64>) -> String {
+        // Value's Display can't fail, to_string on a &str only can in theory
+        let src = serde_json::Value::from(src).to_string();
+        let dest = serde_json::Value::from(dest).to_string();
+        let mut line = String::with_capacity(self.fields.len() + src.len() + dest.len() + 64);
+        let _ = write!(
+            line,
+            r#"{{"src":{},"dest":{},"body":{{"msg_id":{}"#,
+            src, dest, msg_id
+        );
+        if let Some(in_reply_to) = in_reply_to {
+            let _ = write!(line, r#","in_reply_to":{}"#, in_reply_to);
+        }
+        line.push(',');
+        line.push_str(&self.fields);
+        line.push('}');
+        line
+    }
+}
+
+// an answer to a message. the only way to get one is Msg::reply_with, so it
+// always goes back to whoever sent the original and always carries its msg_id
+// in in_reply_to, the node fills in our own msg_id when it sends it
+pub struct Reply {
+    dest: String,
+    in_reply_to: Option<u64>,
+    extra: Payload,
+}
+
+impl Msg {
+    pub fn reply_with(&self, extra: Payload) -> Reply {
+        Reply {
+            dest: self.src.clone(),
+            in_reply_to: self.body.msg_id,
+            extra,
+        }
+    }
+}
+
+// replies go straight back to whoever asked (clients or peers acking), everything
+// else is background gossip that can wait behind them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    Reply,
+    Gossip,
+}
+
+#[derive(Default)]
+struct Outbox {
+    replies: VecDeque<String>,
+    // with the deadline of the client request it's for, if there is one
+    gossip: VecDeque<(String, Option<Instant>)>,
+    // lines that were still queued at their deadline and never went out
+    expired: u64,
+}
+
+impl Outbox {
+    fn push(&mut self, priority: Priority, line: String, deadline: Option<Instant>) {
+        match priority {
+            Priority::Reply => self.replies.push_back(line),
+            Priority::Gossip => self.gossip.push_back((line, deadline)),
+        }
+    }
+
+    // gossip only goes out as fast as the limiter lets it, if there is one,
+    // replies are never held back. nobody is waiting for what's past its
+    // deadline anymore, that isn't worth a token
+    fn pop(&mut self, limit: Option<&mut TokenBucket>, now: Instant) -> Option<String> {
+        if let Some(line) = self.replies.pop_front() {
+            return Some(line);
+        }
+        while self
+            .gossip
+            .front()
+            .is_some_and(|(_, deadline)| deadline.is_some_and(|d| d <= now))
+        {
+            self.gossip.pop_front();
+            self.expired += 1;
+        }
+        if self.gossip.is_empty() {
+            return None;
+        }
+        if limit.is_some_and(|bucket| !bucket.take(now)) {
+            return None;
+        }
+        self.gossip.pop_front().map(|(line, _)| line)
+    }
+
+    fn len(&self) -> usize {
+        self.replies.len() + self.gossip.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.replies.is_empty() && self.gossip.is_empty()
+    }
+}
+
+// a peer is suspected when the failure detector's phi crosses the threshold or,
+// for peers it hasn't seen enough acks from yet, after this many unanswered
+// retransmission rounds in a row. suspected peers get backed off instead of
+// being hammered every tick
+const PHI_THRESHOLD: f64 = 8.0;
+const SUSPECT_AFTER: u32 = 3;
+const MAX_BACKOFF_ROUNDS: u32 = 32;
+
+#[derive(Default, Debug)]
+struct Peer {
+    misses: u32,
+    wait: u32,
+    // rpcs we've started to it, each pending entry keeps its number across
+    // retransmissions (see ack_marks)
+    sent: u64,
+}
+
+impl Peer {
+    fn suspected(&self, phi: f64) -> bool {
+        self.misses >= SUSPECT_AFTER || phi >= PHI_THRESHOLD
+    }
+
+    // called once per gossip round in which the peer still had unacked rpcs,
+    // returns whether we should retransmit to it in this round
+    fn timed_out(&mut self, phi: f64) -> bool {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return false;
+        }
+        self.misses += 1;
+        if self.suspected(phi) {
+            let exp = self.misses.saturating_sub(SUSPECT_AFTER).min(5);
+            self.wait = (1 << exp).min(MAX_BACKOFF_ROUNDS) - 1;
+        }
+        true
+    }
+
+    fn acked(&mut self) {
+        self.misses = 0;
+        self.wait = 0;
+    }
+}
+
+// past this we write out what we have even in the middle of a flush
+const WRITE_BUFFER: usize = 64 * 1024;
+// a flush taking longer than this means whoever reads our output isn't keeping
+// up, see Node::flush
+const SLOW_FLUSH: Duration = Duration::from_millis(100);
+
+// a peer that's been down for this long is probably not coming back, and if we
+// keep everything we ever failed to deliver a long partition test just eats
+// memory. the cap is the hard bound, the oldest entries go first
+const PENDING_TTL: Duration = Duration::from_secs(120);
+const PENDING_CAP: usize = 100_000;
+
+// infect and die: an rpc gets this many attempts unless it asks for fewer,
+// after that it's dropped and the workload gets a say in what happens instead,
+// see Node::gave_up. backoff for suspected peers spreads the attempts out, so
+// a partition still gets retried for a good while before we give up
+const MAX_ATTEMPTS: u32 = 10;
+
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// a handler that takes longer than this gets logged, --slow-handler
+const SLOW_HANDLER: Duration = Duration::from_millis(10);
+
+// how long we assume a client waits for an answer before it gives up and
+// retries, --client-timeout. work on its behalf stops there
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// lines held back until init shows up, see run_actor
+const EARLY_LINES: usize = 1024;
+
+struct Pending {
+    dest: String,
+    // per peer, see Peer::sent
+    seq: u64,
+    payload: Arc<Prepared>,
+    // first attempt, carried over across retransmissions
+    since: Instant,
+    // the latest attempt, each one goes out under a fresh msg_id so the reply
+    // tells us exactly which one got through
+    last_sent: Instant,
+    attempts: u32,
+    // how many attempts it gets before we give up on it
+    budget: u32,
+}
+
+// pending rpcs by peer, then by msg_id. msg_ids are still unique across the
+// whole node, but a reply's src already says which peer it's from, so an ack
+// only looks in that peer's shard, and whatever is done per peer (retry or
+// not, hand off its hints, forget it when it leaves) takes the shard whole
+// instead of scanning everything
+#[derive(Default)]
+struct PendingTable {
+    peers: HashMap<String, HashMap<u64, Pending>>,
+    len: usize,
+}
+
+impl PendingTable {
+    fn insert(&mut self, msg_id: u64, pending: Pending) {
+        let shard = self.peers.entry(pending.dest.clone()).or_default();
+        if shard.insert(msg_id, pending).is_none() {
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, peer: &str, msg_id: u64) -> Option<Pending> {
+        let shard = self.peers.get_mut(peer)?;
+        let pending = shard.remove(&msg_id)?;
+        if shard.is_empty() {
+            self.peers.remove(peer);
+        }
+        self.len -= 1;
+        Some(pending)
+    }
+
+    // everything pending to one peer
+    fn take(&mut self, peer: &str) -> HashMap<u64, Pending> {
+        let shard = self.peers.remove(peer).unwrap_or_default();
+        self.len -= shard.len();
+        shard
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Pending) -> bool) {
+        for shard in self.peers.values_mut() {
+            shard.retain(|_, p| keep(p));
+        }
+        self.peers.retain(|_, shard| !shard.is_empty());
+        self.len = self.peers.values().map(HashMap::len).sum();
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u64, &Pending)> {
+        self.peers
+            .values()
+            .flat_map(|shard| shard.iter().map(|(msg_id, p)| (*msg_id, p)))
+    }
+
+    // only the simulator looks at them all
+    #[cfg(any(test, feature = "dev-tools"))]
+    fn values(&self) -> impl Iterator<Item = &Pending> {
+        self.peers.values().flat_map(HashMap::values)
+    }
+
+    // when we started waiting on the peer, if we are
+    fn waiting_since(&self, peer: &str) -> Option<Instant> {
+        self.peers.get(peer)?.values().map(|p| p.since).min()
+    }
+
+    fn oldest_seq(&self, peer: &str) -> Option<u64> {
+        self.peers.get(peer)?.values().map(|p| p.seq).min()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+pub struct Node<W> {
+    output: W,
+    outbox: Outbox,
+    write_buf: Vec<u8>,
+    id: String,
+    nodes: Vec<String>,
+    msg_ids: u64,
+
+    ids: Box<dyn IdGenerator>,
+    // what we ask peers to answer sync reads in, None for plain json
+    codec: Option<&'static dyn Codec>,
+    // what we compress big payloads to each peer with, from their hello
+    peer_compression: HashMap<String, Compression>,
+    // everything else from their hello
+    peer_capabilities: HashMap<String, Capabilities>,
+    // ours and theirs, see status.rs
+    status: Status,
+    peer_status: HashMap<String, Status>,
+    // which challenge we're answering, only `read` means something different
+    // depending on it. until workload_known it's just a guess (the binary's),
+    // and only the timers every workload needs are running
+    workload: Workload,
+    workload_known: bool,
+    // every workload whose timers are running, the primary one included.
+    // everything else is dispatched by message type anyway, so one node can
+    // serve several at once
+    serving: Vec<Workload>,
+    broadcast: Broadcast,
+    counter: Counter,
+    divergence: Divergence,
+    pending: PendingTable,
+    pending_ttl: Duration,
+    pending_cap: usize,
+    max_attempts: u32,
+    // --gossip-interval, how often we retransmit and run the counter's round
+    gossip_interval: Duration,
+    gossip_timer: TimerId,
+    client_timeout: Duration,
+    slow_handler: Duration,
+    // --paranoid, see validate.rs
+    paranoid: bool,
+    // --gossip-rate, caps everything that isn't a reply
+    gossip_limit: Option<TokenBucket>,
+    // --inject-latency, holds back everything on its way out
+    delay: Option<DelayLine>,
+    // the last flush was slow, gossip rounds wait until one isn't
+    output_slow: bool,
+    peers: HashMap<String, Peer>,
+    detector: FailureDetector,
+    metrics: Metrics,
+    timers: Scheduler,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub fn new(output: W, id: String, nodes: Vec<String>) -> Self {
+        Self::with_logging(output, id, nodes, Logging::default())
+    }
+
+    // the default middleware, with --log-max-bytes and --log-sample
+    pub fn with_logging(output: W, id: String, nodes: Vec<String>, logging: Logging) -> Self {
+        Self::with_middleware(
+            output,
+            id,
+            nodes,
+            vec![Box::new(logging), Box::new(Dedup::default())],
+        )
+    }
+
+    pub fn with_middleware(
+        output: W,
+        id: String,
+        nodes: Vec<String>,
+        middleware: Vec<Box<dyn Middleware>>,
+    ) -> Self {
+        let mut timers = Scheduler::default();
+        let gossip_timer = timers.every(GOSSIP_INTERVAL, Tick::Gossip);
+        timers.every(STATS_INTERVAL, Tick::Stats);
+        Node {
+            output,
+            outbox: Outbox::default(),
+            write_buf: Vec::with_capacity(WRITE_BUFFER),
+            id,
+            nodes,
+            msg_ids: 0,
+            ids: Box::new(ids::NodeCounter::new(ids::startup_epoch())),
+            codec: None,
+            peer_compression: HashMap::new(),
+            peer_capabilities: HashMap::new(),
+            status: Status::startup(),
+            peer_status: HashMap::new(),
+            workload: Workload::Broadcast,
+            workload_known: false,
+            serving: Vec::new(),
+            broadcast: Broadcast::default(),
+            counter: Counter::default(),
+            divergence: Divergence::default(),
+            pending: PendingTable::default(),
+            pending_ttl: PENDING_TTL,
+            pending_cap: PENDING_CAP,
+            max_attempts: MAX_ATTEMPTS,
+            gossip_interval: GOSSIP_INTERVAL,
+            gossip_timer,
+            client_timeout: CLIENT_TIMEOUT,
+            slow_handler: SLOW_HANDLER,
+            paranoid: false,
+            gossip_limit: None,
+            delay: None,
+            output_slow: false,
+            peers: HashMap::new(),
+            detector: FailureDetector::new(100, Duration::from_millis(50), GOSSIP_INTERVAL),
+            metrics: Metrics::default(),
+            timers,
+            middleware,
+        }
+    }
+
+    // first in line, so it sees everything before any other middleware can
+    // drop it
+    pub fn with_audit(mut self, audit: audit::Audit) -> Self {
+        self.middleware.insert(0, Box::new(audit));
+        self
+    }
+
+    // also first in line, ahead of --audit, so what it drops never shows up
+    // as sent anywhere
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.middleware.insert(0, Box::new(chaos));
+        self
+    }
+
+    pub fn with_workload(mut self, workload: Workload) -> Self {
+        self.start_workload(workload);
+        self
+    }
+
+    // the rest of the timers, for the workloads that need them. the first
+    // workload started is the primary one, the one `read` is answered for,
+    // any after it are served alongside
+    fn start_workload(&mut self, workload: Workload) {
+        if !std::mem::replace(&mut self.workload_known, true) {
+            self.workload = workload;
+        }
+        if self.serving.contains(&workload) {
+            return;
+        }
+        // broadcast and the counter share the checksum round and the hello,
+        // both have state a restarted peer needs pushed
+        let checksummed = self
+            .serving
+            .iter()
+            .any(|w| matches!(w, Workload::Broadcast | Workload::Counter));
+        self.serving.push(workload);
+        match workload {
+            Workload::Broadcast => {
+                self.timers.every(broadcast::SYNC_INTERVAL, Tick::Sync);
+            }
+            Workload::Counter => {
+                self.timers.every(counter::COUNTER_INTERVAL, Tick::Counter);
+            }
+            Workload::Echo | Workload::UniqueIds => {}
+        }
+        if matches!(workload, Workload::Broadcast | Workload::Counter) && !checksummed {
+            self.timers
+                .every(checksum::CHECKSUM_INTERVAL, Tick::Checksum);
+            // not right away, the other nodes may not have been initialized
+            // yet
+            self.timers.after(self.gossip_interval, Tick::Hello);
+        }
+    }
+
+    // without --workload the first message that only one workload gets decides
+    fn detect_workload(&mut self, payload: &Payload) {
+        if self.workload_known {
+            return;
+        }
+        if let Some(workload) = Workload::of(payload) {
+            eprintln!("serving {}", workload.name());
+            self.start_workload(workload);
+        }
+    }
+
+    pub fn with_id_generator(mut self, ids: Box<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    // the one the id generator was given, peers see it in our status
+    pub fn with_epoch(mut self, epoch: u64) -> Self {
+        self.status.epoch = epoch;
+        self
+    }
+
+    // the failure detector expects to hear from peers about this often too
+    pub fn with_gossip_interval(mut self, interval: Duration) -> Self {
+        self.set_gossip_interval(interval);
+        self
+    }
+
+    fn set_gossip_interval(&mut self, interval: Duration) {
+        self.timers.cancel(self.gossip_timer);
+        self.gossip_timer = self.timers.every(interval, Tick::Gossip);
+        self.gossip_interval = interval;
+        self.detector = FailureDetector::new(100, Duration::from_millis(50), interval);
+    }
+
+    pub fn with_client_timeout(mut self, timeout: Duration) -> Self {
+        self.client_timeout = timeout;
+        self
+    }
+
+    pub fn with_paranoid(mut self) -> Self {
+        self.paranoid = true;
+        self
+    }
+
+    pub fn with_slow_handler(mut self, threshold: Duration) -> Self {
+        self.slow_handler = threshold;
+        self
+    }
+
+    // messages per second
+    pub fn with_gossip_limit(mut self, rate: f64) -> Self {
+        self.gossip_limit = Some(TokenBucket::new(rate, Instant::now()));
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.delay = Some(DelayLine::new(latency));
+        self
+    }
+
+    pub fn every(&mut self, period: Duration, tick: Tick) -> TimerId {
+        self.timers.every(period, tick)
+    }
+
+    pub fn after(&mut self, delay: Duration, tick: Tick) -> TimerId {
+        self.timers.after(delay, tick)
+    }
+
+    pub fn cancel(&mut self, timer: TimerId) {
+        self.timers.cancel(timer);
+    }
+
+    // also wakes the loop up for held back gossip once the limiter allows it,
+    // and for delayed lines once they're due
+    pub fn next_timer(&mut self) -> Option<Instant> {
+        let timer = self.timers.next_deadline();
+        let throttled = match &mut self.gossip_limit {
+            Some(bucket) if !self.outbox.gossip.is_empty() => {
+                Some(bucket.next_token(Instant::now()))
+            }
+            _ => None,
+        };
+        let delayed = self.delay.as_ref().and_then(DelayLine::next_due);
+        timer.into_iter().chain(throttled).chain(delayed).min()
+    }
+
+    // runs everything that came due since the last call
+    pub fn fire_timers(&mut self) {
+        let now = Instant::now();
+        while let Some(tick) = self.timers.pop_due(now) {
+            let result = match tick {
+                Tick::Gossip => {
+                    self.gossip();
+                    Ok(())
+                }
+                Tick::Counter => self.counter_round(),
+                Tick::Sync => self.sync(),
+                Tick::Stats => self.log_stats(),
+                Tick::Checksum => self.gossip_checksum(),
+                Tick::Hello => {
+                    let peers = self
+                        .nodes
+                        .iter()
+                        .filter(|n| **n != self.id)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    self.say_hello(&peers)
+                        .and_then(|()| self.say_status(&peers))
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("{:?} timer failed: {}", tick, e);
+            }
+        }
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.msg_ids += 1;
+        self.msg_ids
+    }
+
+    fn suspected(&self, peer: &str) -> bool {
+        let phi = self.phi(peer, Instant::now());
+        self.peers.get(peer).is_some_and(|p| p.suspected(phi)) || phi >= PHI_THRESHOLD
+    }
+
+    // acks only come back for rpcs, and nothing sends heartbeats, so a peer
+    // we've had nothing to say to is just quiet, not failing. phi only counts
+    // while we're waiting on it, and only since we started to
+    fn phi(&self, peer: &str, now: Instant) -> f64 {
+        match self.pending.waiting_since(peer) {
+            Some(since) => self.detector.phi(peer, Some(since), now),
+            None => 0.0,
+        }
+    }
+
+    // returns the rpc's number for the peer, see Peer::sent
+    fn rpc(&mut self, dest: &str, payload: Arc<Prepared>) -> u64 {
+        self.rpc_with_budget(dest, payload, self.max_attempts)
+    }
+
+    // for rpcs that aren't worth max_attempts tries
+    fn rpc_with_budget(&mut self, dest: &str, payload: Arc<Prepared>, budget: u32) -> u64 {
+        let pending = self.new_pending(dest, payload, budget);
+        let seq = pending.seq;
+        self.transmit(pending);
+        seq
+    }
+
+    fn new_pending(&mut self, dest: &str, payload: Arc<Prepared>, budget: u32) -> Pending {
+        let peer = self.peers.entry(dest.to_string()).or_default();
+        peer.sent += 1;
+        let now = Instant::now();
+        Pending {
+            dest: dest.to_string(),
+            seq: peer.sent,
+            payload,
+            since: now,
+            last_sent: now,
+            attempts: 0,
+            budget: budget.min(self.max_attempts),
+        }
+    }
+
+    fn transmit(&mut self, mut pending: Pending) {
+        pending.last_sent = Instant::now();
+        pending.attempts += 1;
+        let msg_id = self.next_msg_id();
+        self.enqueue(&pending.dest, msg_id, None, &pending.payload);
+        self.pending.insert(msg_id, pending);
+    }
+
+    // same as rpc but nothing goes on the wire until the next gossip round that
+    // decides to probe the peer again
+    fn park(&mut self, dest: &str, payload: Arc<Prepared>, budget: u32) -> u64 {
+        let pending = self.new_pending(dest, payload, budget);
+        let seq = pending.seq;
+        let msg_id = self.next_msg_id();
+        self.pending.insert(msg_id, pending);
+        seq
+    }
+
+    pub fn reply(&mut self, reply: Reply) -> Result<()> {
+        let payload = Prepared::new(&reply.extra)?;
+        let msg_id = self.next_msg_id();
+        self.enqueue(&reply.dest, msg_id, reply.in_reply_to, &payload);
+        Ok(())
+    }
+
+    // only queues the message, nothing hits stdout until flush()
+    fn enqueue(&mut self, dest: &str, msg_id: u64, in_reply_to: Option<u64>, payload: &Prepared) {
+        self.enqueue_until(dest, msg_id, in_reply_to, payload, None);
+    }
+
+    // same, for work done on behalf of a client that's waiting. if it's
+    // still queued at the deadline it's dropped instead of sent
+    fn enqueue_until(
+        &mut self,
+        dest: &str,
+        msg_id: u64,
+        in_reply_to: Option<u64>,
+        payload: &Prepared,
+        deadline: Option<Instant>,
+    ) {
+        let priority = if in_reply_to.is_some() {
+            Priority::Reply
+        } else {
+            Priority::Gossip
+        };
+        let s = payload.frame(&self.id, dest, msg_id, in_reply_to);
+        if self.middleware.iter_mut().all(|m| m.outbound(dest, &s)) {
+            self.outbox.push(priority, s, deadline);
+        }
+    }
+
+    // called once per loop iteration, everything queued since the last call goes
+    // out in as few writes as possible instead of write+newline+flush per message
+    pub async fn flush(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let delayed_due = self
+            .delay
+            .as_ref()
+            .and_then(DelayLine::next_due)
+            .is_some_and(|at| at <= now);
+        if self.outbox.is_empty() && !delayed_due {
+            self.output_slow = false;
+            return Ok(());
+        }
+        self.metrics.outbox_high_water =
+            self.metrics.outbox_high_water.max(self.outbox.len() as u64);
+        // with --inject-latency lines only go into the delay line here, after
+        // the rate limit, and come out of it below once they're due
+        while let Some(s) = self.outbox.pop(self.gossip_limit.as_mut(), now) {
+            match &mut self.delay {
+                Some(delay) => delay.push(s, now),
+                None => self.write_line(&s).await?,
+            }
+        }
+        while let Some(s) = self.delay.as_mut().and_then(|d| d.pop_due(now)) {
+            self.write_line(&s).await?;
+        }
+        if !self.write_buf.is_empty() {
+            self.output.write_all(&self.write_buf).await?;
+            self.write_buf.clear();
+        }
+        self.output.flush().await?;
+        // writes are awaited right here, so a slow reader already stalls the
+        // whole loop and nothing piles up behind it. what we can still do is
+        // not add to the next flush with a gossip round
+        self.output_slow = Instant::now().duration_since(now) >= SLOW_FLUSH;
+        Ok(())
+    }
+
+    async fn write_line(&mut self, s: &str) -> io::Result<()> {
+        self.write_buf.extend_from_slice(s.as_bytes());
+        self.write_buf.push(b'\n');
+        if self.write_buf.len() >= WRITE_BUFFER {
+            self.output.write_all(&self.write_buf).await?;
+            self.write_buf.clear();
+        }
+        Ok(())
+    }
+
+    pub fn gossip(&mut self) {
+        self.collect_pending();
+        // still working through what the rate limit held back, retransmitting
+        // now would only queue the same rpcs up behind their last attempt
+        if !self.outbox.gossip.is_empty() {
+            self.metrics.gossip_rounds_throttled += 1;
+            return;
+        }
+        if self.output_slow {
+            self.metrics.gossip_rounds_backpressured += 1;
+            return;
+        }
+
+        // this is really problem 3b (broadcast with partitions)
+        // not sure I like this too much with mem::take but it works fine
+        let drained = std::mem::take(&mut self.pending);
+
+        // whatever is still pending at this point timed out, decide once per peer
+        // whether it's worth retransmitting this round
+        let now = Instant::now();
+        for (dest, shard) in drained.peers {
+            let since = shard.values().map(|p| p.since).min();
+            let phi = self.detector.phi(&dest, since, now);
+            let peer = self.peers.entry(dest.clone()).or_default();
+            let was_suspected = peer.suspected(phi);
+            let retry = peer.timed_out(phi);
+            if !was_suspected && peer.suspected(phi) {
+                eprintln!(
+                    "suspecting {} (phi {:.1}, {} missed rounds)",
+                    dest, phi, peer.misses
+                );
+            }
+
+            for (msg_id, pending) in shard {
+                if !retry {
+                    self.pending.insert(msg_id, pending);
+                } else if pending.attempts >= pending.budget {
+                    self.metrics.rpcs_retired += 1;
+                    self.gave_up(pending);
+                } else {
+                    self.transmit(pending);
+                }
+            }
+        }
+    }
+
+    // an rpc that used up its budget without an answer. the workload that sent
+    // it decides what happens next, the default is nothing: anti-entropy (the
+    // periodic sync) is what gets a value to a peer that missed every attempt
+    fn gave_up(&mut self, pending: Pending) {
+        if self.served().contains(&Workload::Broadcast) {
+            self.broadcast_gave_up(&pending);
+        }
+    }
+
+    // any reply from a peer counts as a sign of life for the failure detector.
+    // returns the number of the rpc it answered, if it was one still pending
+    fn acked(&mut self, peer: &str, in_reply_to: Option<u64>) -> Option<u64> {
+        let now = Instant::now();
+        let answered = match in_reply_to {
+            Some(msg_id) => match self.pending.remove(peer, msg_id) {
+                Some(pending) => Some((msg_id, pending)),
+                // a second answer to a retransmitted rpc, or one to an rpc that
+                // expired, got retired or went with a membership change. or
+                // just not ours at all
+                None => {
+                    eprintln!("reply from {} to {}, which isn't pending", peer, msg_id);
+                    self.metrics.unmatched_replies += 1;
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some((msg_id, pending)) = &answered {
+            // one line per finished rpc, rtt is for the attempt that got
+            // answered and total from the first one
+            eprintln!(
+                "rpc {} to {} rtt={}ms total={}ms attempts={}",
+                msg_id,
+                peer,
+                now.duration_since(pending.last_sent).as_millis(),
+                now.duration_since(pending.since).as_millis(),
+                pending.attempts
+            );
+        }
+        let phi = self.phi(peer, now);
+        self.detector.heartbeat(peer, now);
+        let state = self.peers.entry(peer.to_string()).or_default();
+        let was_suspected = state.suspected(phi);
+        state.acked();
+        if was_suspected {
+            eprintln!("{} is answering again", peer);
+            self.hand_off(peer);
+        }
+        answered.map(|(_, pending)| pending.seq)
+    }
+
+    // whatever got parked for a peer while we suspected it (the hints) goes out
+    // right away once it's back instead of waiting for the next probe
+    fn hand_off(&mut self, peer: &str) {
+        let hints = self.pending.take(peer);
+        let handed_off = hints.len();
+        for (_, pending) in hints {
+            self.transmit(pending);
+        }
+        if handed_off > 0 {
+            eprintln!("handing off {} hints to {}", handed_off, peer);
+            self.metrics.hints_handed_off += handed_off as u64;
+        }
+    }
+
+    // drops what's been retried for too long, then the oldest entries if we're
+    // still over the cap
+    fn collect_pending(&mut self) {
+        let now = Instant::now();
+        let before = self.pending.len();
+        let ttl = self.pending_ttl;
+        self.pending.retain(|p| now.duration_since(p.since) < ttl);
+        self.metrics.pending_expired += (before - self.pending.len()) as u64;
+
+        if self.pending.len() > self.pending_cap {
+            let mut by_age = self
+                .pending
+                .iter()
+                .map(|(msg_id, p)| (p.since, msg_id, p.dest.clone()))
+                .collect::<Vec<_>>();
+            by_age.sort_unstable();
+            let excess = self.pending.len() - self.pending_cap;
+            for (_, msg_id, dest) in &by_age[..excess] {
+                self.pending.remove(dest, *msg_id);
+            }
+            self.metrics.pending_evicted += excess as u64;
+        }
+    }
+
+    // ours plus whatever the middleware counts
+    fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics.clone();
+        metrics.calls_past_deadline = self.outbox.expired;
+        for m in &self.middleware {
+            m.report(&mut metrics);
+        }
+        metrics
+    }
+
+    fn log_stats(&self) -> Result<()> {
+        eprintln!(
+            "stats: {} pending={} outbox={} messages={} acked={}",
+            serde_json::to_string(&self.metrics())?,
+            self.pending.len(),
+            self.outbox.len(),
+            self.broadcast.messages.len(),
+            self.ack_marks()
+        );
+        Ok(())
+    }
+
+    // per peer, the high-water mark of rpcs it has acked with nothing missing
+    // below it, out of how many we've sent, e.g. n2:40/40,n3:12/57 means n3
+    // has been stuck since the 13th. expired, evicted and retired entries count
+    // as done, there's nothing left to wait for on those
+    fn ack_marks(&self) -> String {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_unstable_by_key(|(id, _)| *id);
+        peers
+            .into_iter()
+            .filter(|(_, peer)| peer.sent > 0)
+            .map(|(id, peer)| {
+                let acked = self.pending.oldest_seq(id).map_or(peer.sent, |seq| seq - 1);
+                format!("{}:{}/{}", id, acked, peer.sent)
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn receive(&mut self, line: &str) {
+        if let Err(e) = self.handle(line) {
+            eprintln!("dropping {}: {}", line, e);
+        }
+    }
+
+    // everything a node does happens on one loop, a handler that takes its
+    // time holds up every message behind it. real time, not tokio's, that one
+    // doesn't move in the simulator
+    pub fn handle(&mut self, line: &str) -> Result<()> {
+        let started = std::time::Instant::now();
+        let result = self.handle_line(line);
+        let took = started.elapsed();
+        if took >= self.slow_handler {
+            self.metrics.slow_handlers += 1;
+            let msg_id = serde_json::from_str::<Msg>(line)
+                .ok()
+                .and_then(|msg| msg.body.msg_id);
+            let warning = serde_json::json!({
+                "type": middleware::message_type(line),
+                "msg_id": msg_id,
+                "ms": took.as_secs_f64() * 1000.0,
+            });
+            eprintln!("slow handler: {}", warning);
+        }
+        result
+    }
+
+    fn handle_line(&mut self, line: &str) -> Result<()> {
+        let msg = match serde_json::from_str::<Msg>(line) {
+            Ok(msg) => msg,
+            Err(e) => return self.reject(line, e),
+        };
+        if !self.middleware.iter_mut().all(|m| m.inbound(line, &msg)) {
+            return Ok(());
+        }
+        if self.misrouted(&msg) {
+            return Ok(());
+        }
+
+        if let Some(extra) = self.middleware.iter_mut().find_map(|m| m.answer(&msg)) {
+            return self.reply(msg.reply_with(extra));
+        }
+
+        // a suspected peer sending us anything at all is reachable again, replies
+        // get acked by the workload that sent the request
+        if msg.body.in_reply_to.is_none()
+            && self.nodes.contains(&msg.src)
+            && self.suspected(&msg.src)
+        {
+            self.acked(&msg.src, None);
+        }
+
+        let invalid = match self.validate(&msg) {
+            Err(text) => Some(text),
+            Ok(()) if self.paranoid => {
+                let suspicious = self.suspicious(line, &msg);
+                if suspicious.is_some() {
+                    self.metrics.paranoid_rejected += 1;
+                }
+                suspicious
+            }
+            Ok(()) => None,
+        };
+        if let Some(text) = invalid {
+            eprintln!("rejecting {}: {}", line, text);
+            // answering an answer only starts an argument
+            if msg.body.in_reply_to.is_some() {
+                return Ok(());
+            }
+            let response = Payload::Error {
+                code: MALFORMED_REQUEST,
+                text,
+            };
+            for m in &mut self.middleware {
+                m.handled(&msg, Some(&response));
+            }
+            return self.reply(msg.reply_with(response));
+        }
+
+        self.detect_workload(&msg.body.extra);
+        let response = self.dispatch(&msg)?;
+        for m in &mut self.middleware {
+            m.handled(&msg, response.as_ref());
+        }
+        if let Some(extra) = response {
+            self.reply(msg.reply_with(extra))?;
+        }
+        Ok(())
+    }
+}
+
+// drives a node off any line based input/output pair, main just plugs in
+// stdin/stdout but tests (or another transport) can hand in whatever they like
+pub async fn run<R, W>(input: R, output: W, config: &Config) -> Result<()>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let (handle, mailbox) = actor::mailbox();
+    spawn_reader(input, handle);
+    run_actor(mailbox, output, config).await
+}
+
+// run() minus the input, for when more than the reader wants to talk to the
+// node: everything comes in through the handles of `mailbox`
+pub async fn run_actor<W: AsyncWrite + Unpin>(
+    mut mailbox: Mailbox,
+    output: W,
+    config: &Config,
+) -> Result<()> {
+    // nothing can be handled before init since that's where the node gets its
+    // id. whatever shows up ahead of it (a harness or the simulator not
+    // waiting for init_ok, a peer that's quicker than maelstrom) is held back
+    // and handled right after it, in order. the init itself is then handled
+    // like any other message, same as one a harness sends again later. ticks
+    // and dumps before it have nothing to act on
+    let mut early = Vec::new();
+    let (line, node_id, node_ids) = loop {
+        match mailbox.recv().await {
+            Some(Command::IncomingMsg(line)) => {
+                if let Ok(Msg {
+                    body:
+                        Body {
+                            extra: Payload::Init { node_id, node_ids },
+                            ..
+                        },
+                    ..
+                }) = serde_json::from_str::<Msg>(&line)
+                {
+                    break (line, node_id, node_ids);
+                }
+                if early.len() < EARLY_LINES {
+                    early.push(line);
+                } else {
+                    eprintln!("dropping {}, still waiting for init", line);
+                }
+            }
+            Some(_) => continue,
+            None if early.is_empty() => return Ok(()),
+            None => return Err(Error::NotInitialized),
+        }
+    };
+    let mut n = {
+        let epoch = match &config.id_epoch_file {
+            Some(path) => ids::persisted_epoch(path)?,
+            None => ids::startup_epoch(),
+        };
+        let logging = Logging::new(config.log_max_bytes, config.log_sample.clone());
+        let mut n = Node::with_logging(output, node_id.clone(), node_ids, logging)
+            .with_id_generator(config.ids.generator(epoch))
+            .with_epoch(epoch);
+        for workload in &config.workloads {
+            n = n.with_workload(*workload);
+        }
+        if config.workloads.is_empty() {
+            if let Some(workload) = config.default_workload {
+                n.workload = workload;
+            }
+        }
+        if let Some(mode) = config.counter {
+            n = n.with_counter_mode(mode);
+        }
+        if let Some(mode) = config.read_mode {
+            n = n.with_read_mode(mode);
+        }
+        if let Some(ttl) = config.kv_cache_ttl {
+            n = n.with_kv_cache_ttl(ttl);
+        }
+        if let Some(shape) = &config.topology {
+            n = n.with_topology(shape.clone());
+        }
+        if let Some(interval) = config.gossip_interval {
+            n = n.with_gossip_interval(interval);
+        }
+        if let Some(timeout) = config.client_timeout {
+            n = n.with_client_timeout(timeout);
+        }
+        if config.paranoid {
+            n = n.with_paranoid();
+        }
+        if let Some(threshold) = config.slow_handler {
+            n = n.with_slow_handler(threshold);
+        }
+        if let Some(rate) = config.gossip_rate {
+            n = n.with_gossip_limit(rate);
+        }
+        if let Some(latency) = config.inject_latency {
+            n = n.with_latency(latency);
+        }
+        if let Some(path) = &config.audit {
+            n = n.with_audit(audit::Audit::create(path)?);
+        }
+        if let Some(rate) = config.drop_rate {
+            n = n.with_chaos(Chaos::new(rate, config.drop_seed, config.drop_replies));
+        }
+        n.codec = config.codec;
+        if let Some(limit) = config.max_messages_in_memory {
+            let dir = config.spill_dir.clone().unwrap_or_else(|| {
+                std::env::temp_dir().join(format!("fly-{}-{}", node_id, std::process::id()))
+            });
+            n.broadcast.messages = MessageStore::spilling(limit, dir)?;
+        }
+        n
+    };
+    eprintln!("startup: {}", n.banner(config));
+    n.receive(&line);
+    for line in early {
+        n.receive(&line);
+    }
+    n.flush().await?;
+    n.serve(mailbox).await
+}
+
+// lines get read off the input in their own task, the node's loop only ever
+// looks at its mailbox. that's what keeps serve()'s select! safe: recv() is
+// cancel safe, and a line that's only half there when a timer wins stays in
+// FramedRead's buffer over here instead of in a future select! just dropped
+fn spawn_reader<R>(input: R, node: NodeHandle)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut lines = FramedRead::new(input, JsonLines::new());
+        while let Some(line) = lines.next().await {
+            match line {
+                Ok(line) => {
+                    if node.incoming(line).await.is_err() {
+                        break;
+                    }
+                }
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    eprintln!("skipping a line over the length limit");
+                }
+                // the codec has already taken the line off the buffer, the
+                // next one is read normally
+                Err(LinesCodecError::Io(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                    eprintln!("skipping a line that isn't utf-8");
+                }
+                Err(LinesCodecError::Io(e)) => {
+                    eprintln!("reading input failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvcache::KV_CACHE_TTL;
+    use crate::ranges::Ranges;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time;
+
+    async fn run_script(lines: &[&str]) -> Vec<Msg> {
+        let input = std::io::Cursor::new(lines.join("\n").into_bytes());
+        let mut output = Vec::new();
+        run(input, &mut output, &Config::default()).await.unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Msg>(l).unwrap())
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lines_trickling_in_survive_timer_ticks() {
+        use tokio::io::AsyncWriteExt;
+
+        let (input, mut script) = io::duplex(1024);
+        let lines = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"one"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"two"}}"#,
+        ];
+        // a few bytes at a time with several gossip rounds in between, so the
+        // timer keeps firing with a line only partly read
+        let writer = tokio::spawn(async move {
+            for line in lines {
+                for chunk in format!("{}\n", line).as_bytes().chunks(10) {
+                    script.write_all(chunk).await.unwrap();
+                    time::sleep(GOSSIP_INTERVAL * 2).await;
+                }
+            }
+        });
+        let mut output = Vec::new();
+        let input = io::BufReader::new(input);
+        run(input, &mut output, &Config::default()).await.unwrap();
+        writer.await.unwrap();
+
+        let echoed = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .filter_map(
+                |l| match serde_json::from_str::<Msg>(l).unwrap().body.extra {
+                    Payload::EchoOk { echo } => Some(echo),
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(echoed, ["one", "two"]);
+    }
+
+    // drops echoes on the way in and counts what goes out
+    struct NoEcho(Arc<AtomicUsize>);
+
+    impl Middleware for NoEcho {
+        fn inbound(&mut self, _line: &str, msg: &Msg) -> bool {
+            !matches!(msg.body.extra, Payload::Echo { .. })
+        }
+
+        fn outbound(&mut self, _dest: &str, _line: &str) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn middleware_sees_traffic_both_ways() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let mut n = Node::with_middleware(
+            Vec::new(),
+            "n1".to_string(),
+            vec!["n1".to_string()],
+            vec![Box::new(Logging::default()), Box::new(NoEcho(sent.clone()))],
+        );
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#)
+            .unwrap();
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("generate_ok"));
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retried_requests_are_answered_not_redone() {
+        let mut n = Node::new(Vec::new(), "n1".to_string(), vec!["n1".to_string()]);
+        let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1,"delta":5}}"#;
+        n.handle(add).unwrap();
+        n.handle(add).unwrap();
+        // same msg_id from another client is its own request
+        n.handle(r#"{"src":"c2","dest":"n1","body":{"type":"add","msg_id":1,"delta":5}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert_eq!(out.matches("add_ok").count(), 3);
+        assert_eq!(n.snapshot().unwrap()["g-counter"]["total"], 10);
+        assert_eq!(n.metrics().duplicate_requests, 1);
+        assert_eq!(n.metrics().dedup_entries, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_uses_delta_only_once_the_peer_says_it_can() {
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        // from before versions, compression only
+        n.handle(
+            r#"{"src":"n2","dest":"n1","body":{"type":"hello","msg_id":1,"compression":["gzip"]}}"#,
+        )
+        .unwrap();
+        n.handle(r#"{"src":"n3","dest":"n1","body":{"type":"hello_ok","in_reply_to":1,"compression":[],"version":1,"features":["delta","teleport"]}}"#)
+            .unwrap();
+        n.request_page("n2", None).unwrap();
+        n.request_page("n3", None).unwrap();
+        n.flush().await.unwrap();
+
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        let reads = out
+            .lines()
+            .filter(|l| l.contains(r#""type":"read""#))
+            .collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2);
+        assert!(reads[0].contains(r#""dest":"n2""#) && !reads[0].contains("delta"));
+        assert!(reads[1].contains(r#""dest":"n3""#) && reads[1].contains(r#""encoding":"delta""#));
+        assert!(out.contains(r#""type":"hello_ok""#) && out.contains(r#""version":1"#));
+        assert_eq!(n.peer_capabilities["n2"].version, 0);
+        assert_eq!(n.peer_capabilities["n3"].features, ["delta"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_new_epoch_means_the_peer_restarted() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes).with_epoch(42);
+        let status = |msg_id: u64, epoch: u64| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"status","msg_id":{},"started":{},"epoch":{},"workloads":["broadcast"]}}}}"#,
+                msg_id, epoch, epoch
+            )
+        };
+        n.handle(&status(1, 7)).unwrap();
+        // a partition doesn't change anything, it's the same process
+        n.handle(&status(2, 7)).unwrap();
+        assert_eq!(n.metrics().peer_restarts, 0);
+
+        // something we'd still be retrying to the old process
+        let payload = Arc::new(Prepared::new(&Payload::Broadcast { message: 1 }).unwrap());
+        n.park("n2", payload, MAX_ATTEMPTS);
+        n.handle(&status(3, 8)).unwrap();
+        assert_eq!(n.metrics().peer_restarts, 1);
+        assert_eq!(n.peer_status["n2"].epoch, 8);
+        n.flush().await.unwrap();
+
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert_eq!(out.matches(r#""type":"status_ok""#).count(), 3);
+        assert!(out.contains(r#""epoch":42"#));
+        assert_eq!(out.matches(r#""type":"broadcast""#).count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarted_peers_get_everything_pushed() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n =
+            Node::new(Vec::new(), "n1".to_string(), nodes).with_workload(Workload::Broadcast);
+        for message in (0..700).map(|m| m * 2) {
+            n.broadcast.messages.insert(message).unwrap();
+        }
+        let status = |epoch: u64| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"status","msg_id":{},"started":{},"epoch":{},"workloads":[]}}}}"#,
+                epoch, epoch, epoch
+            )
+        };
+        n.handle(r#"{"src":"n2","dest":"n1","body":{"type":"hello","msg_id":1,"compression":[],"version":1,"features":["resync"]}}"#)
+            .unwrap();
+        n.handle(&status(2)).unwrap();
+        let payload = Arc::new(Prepared::new(&Payload::Broadcast { message: 1 }).unwrap());
+        n.park("n2", payload, MAX_ATTEMPTS);
+        n.broadcast.awaiting.insert(("n2".to_string(), 9), 1);
+        n.handle(&status(3)).unwrap();
+        assert!(n.broadcast.awaiting.is_empty());
+        n.flush().await.unwrap();
+
+        let mut pushed = Ranges::new();
+        for line in String::from_utf8(n.output.clone()).unwrap().lines() {
+            let msg = serde_json::from_str::<Msg>(line).unwrap();
+            match msg.body.extra {
+                Payload::Resync { ranges } => {
+                    for (start, end) in ranges::decode(&ranges).unwrap().iter() {
+                        pushed.insert_range(start, end);
+                    }
+                }
+                Payload::Broadcast { .. } => panic!("the push covers {}", line),
+                _ => {}
+            }
+        }
+        assert_eq!(pushed.len(), 700);
+        // two chunks, both pending until n2 acks them
+        assert_eq!(n.pending.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counter_nodes_notice_restarts_too() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Counter)
+            .with_counter_mode(CounterMode::Crdt)
+            .with_read_mode(ReadMode::Quorum);
+        time::advance(n.gossip_interval).await;
+        n.fire_timers();
+        n.flush().await.unwrap();
+        let out = String::from_utf8(std::mem::take(&mut n.output)).unwrap();
+        assert!(out.contains(r#""type":"hello""#));
+        assert!(out.contains(r#""type":"status""#));
+
+        let status = |epoch: u64| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"status","msg_id":{},"started":{},"epoch":{},"workloads":["g-counter"]}}}}"#,
+                epoch, epoch, epoch
+            )
+        };
+        n.handle(r#"{"src":"n2","dest":"n1","body":{"type":"hello","msg_id":1,"compression":[],"version":1,"features":["resync"]}}"#)
+            .unwrap();
+        n.handle(&status(2)).unwrap();
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1,"delta":5}}"#)
+            .unwrap();
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2}}"#)
+            .unwrap();
+        // the poll went to the old process, the new one gets our counts and
+        // is asked again
+        n.handle(&status(3)).unwrap();
+        n.flush().await.unwrap();
+
+        let sent = String::from_utf8(std::mem::take(&mut n.output))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Msg>(line).unwrap())
+            .filter(|m| m.dest == "n2")
+            .collect::<Vec<_>>();
+        let polls = sent
+            .iter()
+            .filter(|m| matches!(m.body.extra, Payload::CounterPoll))
+            .collect::<Vec<_>>();
+        assert_eq!(polls.len(), 2);
+        let pushed = sent
+            .iter()
+            .position(|m| matches!(m.body.extra, Payload::CounterState { .. }))
+            .unwrap();
+        assert!(sent[pushed].body.msg_id < polls[1].body.msg_id);
+
+        n.handle(&format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"counter_poll_ok","in_reply_to":{},"counts":{{"n1":5,"n2":0}}}}}}"#,
+            polls[1].body.msg_id.unwrap()
+        ))
+        .unwrap();
+        n.flush().await.unwrap();
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert!(out.contains(r#""type":"read_ok""#));
+        assert!(out.contains(r#""value":5"#));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_members_get_ranges_not_single_values() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n =
+            Node::new(Vec::new(), "n1".to_string(), nodes).with_workload(Workload::Broadcast);
+        for message in (0..1000).chain(2000..2010) {
+            n.broadcast.messages.insert(message).unwrap();
+        }
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"membership","msg_id":1,"node_ids":["n1","n2","n3"]}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+
+        let to_n3 = String::from_utf8(n.output.clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Msg>(line).unwrap())
+            .filter(|m| m.dest == "n3")
+            .map(|m| m.body.extra)
+            .collect::<Vec<_>>();
+        assert!(!to_n3.iter().any(|p| matches!(p, Payload::Broadcast { .. })));
+        let pushed = to_n3
+            .iter()
+            .filter_map(|p| match p {
+                Payload::Resync { ranges } => Some(ranges::decode(ranges).unwrap()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pushed.len(), 1);
+        assert_eq!(pushed[0].len(), 1010);
+    }
+
+    #[test]
+    fn prepared_frames_like_serde() {
+        let body = Body {
+            msg_id: Some(3),
+            in_reply_to: Some(2),
+            extra: Payload::Echo {
+                echo: "quote \" and \\ backslash".into(),
+            },
+        };
+        let msg = Msg {
+            src: "n1".into(),
+            dest: "c\"1".into(),
+            body: body.clone(),
+        };
+        let framed = Prepared::new(&body.extra)
+            .unwrap()
+            .frame(&msg.src, &msg.dest, 3, Some(2));
+        assert_eq!(framed, serde_json::to_string(&msg).unwrap());
+    }
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_to_init_and_echo() {
+        let out = run_script(&[
+            INIT,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"echo":"hi"}}"#,
+        ])
+        .await;
+
+        assert_eq!(out.len(), 2);
+        assert!(matches!(out[0].body.extra, Payload::InitOk));
+        assert_eq!(out[0].dest, "c0");
+        assert!(matches!(&out[1].body.extra, Payload::EchoOk { echo } if echo == "hi"));
+        assert_eq!(out[1].body.in_reply_to, Some(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn init_again_is_idempotent() {
+        let out = run_script(&[
+            INIT,
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":2,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":3,"node_id":"n2","node_ids":["n1","n2"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":4,"echo":"still n1"}}"#,
+        ])
+        .await;
+
+        assert!(matches!(out[0].body.extra, Payload::InitOk));
+        assert!(matches!(out[1].body.extra, Payload::InitOk));
+        assert!(matches!(
+            out[2].body.extra,
+            Payload::Error {
+                code: MALFORMED_REQUEST,
+                ..
+            }
+        ));
+        assert_eq!(out[3].src, "n1");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_requests_get_an_error() {
+        let out = run_script(&[
+            INIT,
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":3,"topology":{"n2":["n3"]}}}"#,
+            // not a request, and a peer: dropped
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast"}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast","msg_id":4}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":5,"message":1}}"#,
+        ])
+        .await;
+
+        let errors = out
+            .iter()
+            .filter_map(|m| match &m.body.extra {
+                Payload::Error {
+                    code: MALFORMED_REQUEST,
+                    text,
+                } => Some((m.body.in_reply_to, text.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(errors[0].0, Some(2));
+        assert!(
+            errors[0].1.contains("missing field `message`"),
+            "{}",
+            errors[0].1
+        );
+        assert_eq!(errors[1], (Some(3), "topology has nothing for n1"));
+        assert!(
+            out.iter()
+                .any(|m| m.body.in_reply_to == Some(5)
+                    && matches!(m.body.extra, Payload::BroadcastOk))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn workload_is_detected_from_the_first_request() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        n.workload = Workload::Counter;
+        for line in [
+            INIT,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"hello","msg_id":1,"compression":[]}}"#,
+        ] {
+            n.handle(line).unwrap();
+        }
+        // read and hello don't tell, so the read got the guess's answer
+        assert!(!n.workload_known);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"x"}}"#)
+            .unwrap();
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":4,"message":1}}"#)
+            .unwrap();
+        assert!(n.workload_known);
+        assert_eq!(n.workload, Workload::Echo);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dump_state_writes_pending_rpcs() {
+        let path = std::env::temp_dir().join(format!("dump-state-{}.json", std::process::id()));
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#)
+            .unwrap();
+        n.gossip();
+        time::advance(Duration::from_millis(250)).await;
+        n.handle(&format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"dump_state","msg_id":2,"path":{:?}}}}}"#,
+            path.display().to_string()
+        ))
+        .unwrap();
+
+        let state =
+            serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(&path).unwrap())
+                .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state["broadcast"]["messages"], serde_json::json!([7]));
+        let pending = &state["pending"][0];
+        assert_eq!(pending["dest"], "n2");
+        assert_eq!(pending["attempts"], 1);
+        assert_eq!(pending["age_ms"], 250);
+        assert_eq!(pending["payload"]["message"], 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_before_init_wait_for_it() {
+        let replies = run_script(&[
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"early"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#,
+            INIT,
+        ])
+        .await;
+        assert_eq!(replies.len(), 3);
+        assert!(matches!(replies[0].body.extra, Payload::InitOk));
+        assert!(matches!(&replies[1].body.extra, Payload::EchoOk { echo } if echo == "early"));
+        assert!(matches!(replies[2].body.extra, Payload::GenerateOk { .. }));
+        assert_eq!(replies[2].body.in_reply_to, Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snapshots_restore_into_a_fresh_node() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        for (workload, request) in [
+            (Workload::Broadcast, r#""type":"broadcast","message":7"#),
+            (Workload::Counter, r#""type":"add","delta":3"#),
+        ] {
+            let mut n = Node::new(Vec::new(), "n1".to_string(), nodes.clone())
+                .with_workload(workload)
+                .with_counter_mode(CounterMode::Crdt);
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"msg_id":1,{}}}}}"#,
+                request
+            ))
+            .unwrap();
+            let snapshot = n.snapshot().unwrap();
+
+            let mut restored = Node::new(Vec::new(), "n1".to_string(), nodes.clone())
+                .with_workload(workload)
+                .with_counter_mode(CounterMode::Crdt);
+            restored.restore(&snapshot).unwrap();
+            assert_eq!(restored.snapshot().unwrap(), snapshot);
+        }
+        let echo = Node::new(Vec::new(), "n1".to_string(), nodes).with_workload(Workload::Echo);
+        assert_eq!(echo.snapshot().unwrap(), serde_json::json!({}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_node_serves_several_workloads() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Broadcast)
+            .with_workload(Workload::Counter)
+            .with_counter_mode(CounterMode::Crdt);
+        for request in [
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":3}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"hi"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}"#,
+        ] {
+            n.handle(request).unwrap();
+        }
+        n.flush().await.unwrap();
+
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        for reply in ["broadcast_ok", "add_ok", "echo_ok"] {
+            assert_eq!(out.matches(reply).count(), 1, "{}", reply);
+        }
+        // the first workload is the one read answers for
+        assert!(out.contains(r#""messages":[7]"#));
+        assert_eq!(n.served(), vec![Workload::Broadcast, Workload::Counter]);
+
+        let snapshot = n.snapshot().unwrap();
+        assert_eq!(snapshot["broadcast"]["messages"], serde_json::json!([7]));
+        assert_eq!(snapshot["g-counter"]["total"], 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_reads_sync_even_behind_a_counter() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Counter)
+            .with_workload(Workload::Broadcast)
+            .with_counter_mode(CounterMode::Crdt);
+        for request in [
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":3}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"read","msg_id":3,"encoding":"ranges"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}"#,
+        ] {
+            n.handle(request).unwrap();
+        }
+        n.flush().await.unwrap();
+
+        let replies = String::from_utf8(n.output.clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Msg>(line).unwrap())
+            .filter(|m| m.body.in_reply_to.is_some())
+            .map(|m| (m.dest, m.body.extra))
+            .collect::<Vec<_>>();
+        // the peer wanted the messages, the client the counter
+        assert!(replies.iter().any(|(dest, p)| dest == "n2"
+            && matches!(
+                p,
+                Payload::ReadOk {
+                    ranges: Some(_),
+                    ..
+                }
+            )));
+        assert!(replies
+            .iter()
+            .any(|(dest, p)| dest == "c1" && matches!(p, Payload::ReadOk { value: Some(3), .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn generated_ids_are_unique() {
+        let mut script = vec![INIT];
+        let generates = (2..22)
+            .map(|msg_id| {
+                format!(
+                    r#"{{"src":"c1","dest":"n1","body":{{"type":"generate","msg_id":{}}}}}"#,
+                    msg_id
+                )
+            })
+            .collect::<Vec<_>>();
+        script.extend(generates.iter().map(String::as_str));
+        let out = run_script(&script).await;
+
+        let ids = out
+            .iter()
+            .filter_map(|m| match &m.body.extra {
+                Payload::GenerateOk { id } => Some(id.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ids_dont_skip_with_other_traffic() {
+        let mut n = Node::new(Vec::new(), "n1".to_string(), vec!["n1".into(), "n2".into()])
+            .with_id_generator(Box::new(ids::NodeCounter::new(3)));
+        for msg_id in 1..=4 {
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                msg_id * 2,
+                msg_id
+            ))
+            .unwrap();
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"generate","msg_id":{}}}}}"#,
+                msg_id * 2 + 1
+            ))
+            .unwrap();
+        }
+        n.flush().await.unwrap();
+
+        let ids = String::from_utf8(n.output.clone())
+            .unwrap()
+            .lines()
+            .filter_map(
+                |l| match serde_json::from_str::<Msg>(l).unwrap().body.extra {
+                    Payload::GenerateOk { id } => Some(id),
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["n1-3-0", "n1-3-1", "n1-3-2", "n1-3-3"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retried_requests_get_the_same_reply() {
+        let generate = r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#;
+        let other_client = r#"{"src":"c2","dest":"n1","body":{"type":"generate","msg_id":2}}"#;
+        let out = run_script(&[INIT, generate, generate, other_client]).await;
+
+        let ids = out
+            .iter()
+            .filter_map(|m| match &m.body.extra {
+                Payload::GenerateOk { id } => Some((m.dest.as_str(), m.body.in_reply_to, id)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(ids[2].0, "c2");
+        assert_ne!(ids[2].2, ids[0].2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_pages_through_values() {
+        let mut script = vec![INIT.to_string()];
+        for message in [5, 3, 9, 1, 7] {
+            script.push(format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                message + 10,
+                message
+            ));
+        }
+        script
+            .push(r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2,"limit":2}}"#.into());
+        script.push(
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3,"after":3,"limit":2}}"#
+                .into(),
+        );
+        script.push(
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4,"after":7,"limit":2}}"#
+                .into(),
+        );
+        let out = run_script(&script.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        let pages = out
+            .iter()
+            .filter_map(|m| match &m.body.extra {
+                Payload::ReadOk { messages, next, .. } => Some((messages.clone()?, *next)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pages,
+            vec![
+                (vec![1, 3], Some(3)),
+                (vec![5, 7], Some(7)),
+                (vec![9], None)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delta_reads_only_when_asked() {
+        let out = run_script(&[
+            INIT,
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":300}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":4}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"read","msg_id":4,"encoding":"delta"}}"#,
+        ])
+        .await;
+
+        let reads = out
+            .iter()
+            .filter_map(|m| match &m.body.extra {
+                Payload::ReadOk {
+                    messages, delta, ..
+                } => Some((m.dest.as_str(), messages.clone()?, delta.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2);
+        let (dest, mut plain, none) = reads[0].clone();
+        plain.sort_unstable();
+        assert_eq!((dest, plain, none), ("c1", vec![4, 300], None));
+        let (dest, empty, delta) = reads[1].clone();
+        assert_eq!((dest, empty), ("n2", vec![]));
+        assert_eq!(delta::decode(&delta.unwrap()).unwrap(), vec![4, 300]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ranges_pages_count_ranges() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        for message in (0..1000).chain([5000, 5001, 7000]) {
+            n.broadcast.messages.insert(message).unwrap();
+        }
+        n.handle(r#"{"src":"n2","dest":"n1","body":{"type":"read","msg_id":1,"limit":2,"encoding":"ranges"}}"#)
+            .unwrap();
+        n.handle(r#"{"src":"n2","dest":"n1","body":{"type":"read","msg_id":2,"after":5001,"limit":2,"encoding":"ranges"}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+
+        let pages = String::from_utf8(n.output.clone())
+            .unwrap()
+            .lines()
+            .map(
+                |l| match serde_json::from_str::<Msg>(l).unwrap().body.extra {
+                    Payload::ReadOk { ranges, next, .. } => {
+                        let ranges = ranges::decode(&ranges.unwrap()).unwrap();
+                        (ranges.iter().collect::<Vec<_>>(), next)
+                    }
+                    other => panic!("{:?}", other),
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pages,
+            [
+                (vec![(0, 999), (5000, 5001)], Some(5001)),
+                (vec![(7000, 7000)], None)
+            ]
+        );
+    }
+
+    #[test]
+    fn encoded_payloads_are_only_sync_pages_from_peers() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        let wrapped = |src: &str, in_reply_to: Option<u64>, payload: Payload| {
+            let extra = codec::for_peer(Some(&codec::MsgPack), None, payload).unwrap();
+            serde_json::to_string(&Msg {
+                src: src.to_string(),
+                dest: "n1".to_string(),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to,
+                    extra,
+                },
+            })
+            .unwrap()
+        };
+        let set_param = Payload::SetParam {
+            name: "gossip-interval".to_string(),
+            value: "1".to_string(),
+        };
+        let page = |messages: Vec<usize>| Payload::ReadOk {
+            messages: Some(messages),
+            next: None,
+            delta: None,
+            ranges: None,
+            value: None,
+        };
+
+        n.handle(&wrapped("c1", None, set_param.clone())).unwrap();
+        n.handle(&wrapped("n2", None, set_param)).unwrap_err();
+        assert_eq!(n.gossip_interval, GOSSIP_INTERVAL);
+        n.handle(&wrapped("c1", Some(1), page(vec![1]))).unwrap();
+        n.handle(&wrapped("n2", Some(1), page(vec![2]))).unwrap();
+        assert_eq!(n.broadcast.messages.all().unwrap(), [2]);
+
+        // base64 hides whatever is in there from the checks on the line
+        let mut n = n.with_paranoid();
+        let read = Payload::Read {
+            after: None,
+            limit: None,
+            encoding: None,
+            codec: None,
+            key: Some("counter-\u{7}".to_string()),
+        };
+        n.handle(&wrapped("n2", None, read)).unwrap_err();
+        assert_eq!(n.metrics().paranoid_rejected, 1);
+    }
+
+    #[test]
+    fn ranges_from_a_peer_are_bounded() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        let page = |ranges: &Ranges| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"read_ok","in_reply_to":1,"ranges":"{}"}}}}"#,
+                ranges::encode(ranges)
+            )
+        };
+        let mut everything = Ranges::new();
+        everything.insert_range(0, usize::MAX);
+        n.handle(&page(&everything)).unwrap_err();
+        assert_eq!(n.broadcast.messages.len(), 0);
+
+        // a big but believable page goes in as the one range it is
+        let mut big = Ranges::new();
+        big.insert_range(0, ranges::MAX_DECODED - 1);
+        n.handle(&page(&big)).unwrap();
+        assert_eq!(n.broadcast.messages.len(), ranges::MAX_DECODED);
+    }
+
+    #[tokio::test]
+    async fn resyncs_too_big_to_be_real_get_an_error() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        let mut everything = Ranges::new();
+        everything.insert_range(0, usize::MAX);
+        n.handle(&format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"resync","msg_id":1,"ranges":"{}"}}}}"#,
+            ranges::encode(&everything)
+        ))
+        .unwrap();
+        n.flush().await.unwrap();
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert!(out.contains(r#""code":12"#), "{}", out);
+        assert_eq!(n.broadcast.messages.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gossip_limit_holds_back_only_gossip() {
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes).with_gossip_limit(5.0);
+        for message in 0..10 {
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                message + 1,
+                message
+            ))
+            .unwrap();
+        }
+        let sent = |n: &mut Node<Vec<u8>>| {
+            let out = String::from_utf8(std::mem::take(&mut n.output)).unwrap();
+            let msgs = out
+                .lines()
+                .map(|l| serde_json::from_str::<Msg>(l).unwrap())
+                .collect::<Vec<_>>();
+            let replies = msgs.iter().filter(|m| m.dest == "c1").count();
+            (replies, msgs.len() - replies)
+        };
+
+        n.flush().await.unwrap();
+        assert_eq!(sent(&mut n), (10, 5));
+        // the loop gets woken up for the rest as tokens come back
+        let wake = n.next_timer().unwrap();
+        assert_eq!(wake, Instant::now() + Duration::from_millis(200));
+        time::advance(Duration::from_secs(1)).await;
+        n.flush().await.unwrap();
+        assert_eq!(sent(&mut n), (0, 5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_past_their_deadline_stop_costing_anything() {
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Counter)
+            .with_counter_mode(CounterMode::Crdt)
+            .with_read_mode(ReadMode::Quorum)
+            .with_client_timeout(Duration::from_millis(500))
+            .with_gossip_limit(2.0);
+        for msg_id in 1..=5 {
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"read","msg_id":{}}}}}"#,
+                msg_id
+            ))
+            .unwrap();
+        }
+        // two polls a read, the limiter only lets the first two out
+        n.flush().await.unwrap();
+        time::advance(Duration::from_secs(1)).await;
+        n.fire_timers();
+        n.flush().await.unwrap();
+
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert_eq!(out.matches(r#""type":"counter_poll""#).count(), 2);
+        let metrics = n.metrics();
+        assert_eq!(metrics.reads_past_deadline, 5);
+        assert_eq!(metrics.calls_past_deadline, 8);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn local_reads_reuse_peer_keys_for_a_while() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Counter)
+            .with_read_mode(ReadMode::Local);
+        let read = |msg_id: u64| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"read","msg_id":{}}}}}"#,
+                msg_id
+            )
+        };
+        let sent =
+            |n: &mut Node<Vec<u8>>| String::from_utf8(std::mem::take(&mut n.output)).unwrap();
+
+        n.handle(&read(1)).unwrap();
+        n.flush().await.unwrap();
+        assert!(sent(&mut n).contains(r#""key":"counter-n2""#));
+        n.handle(
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":1,"value":4}}"#,
+        )
+        .unwrap();
+        n.handle(&read(2)).unwrap();
+        n.flush().await.unwrap();
+        let out = sent(&mut n);
+        assert!(!out.contains(r#""key":"counter-n2""#));
+        assert_eq!(out.matches(r#""value":4"#).count(), 2);
+
+        time::advance(KV_CACHE_TTL).await;
+        n.handle(&read(3)).unwrap();
+        n.flush().await.unwrap();
+        assert!(sent(&mut n).contains(r#""key":"counter-n2""#));
+        let metrics = n.metrics();
+        assert_eq!((metrics.kv_cache_hits, metrics.kv_cache_misses), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn adds_past_u64_are_refused() {
+        let mut n = Node::new(Vec::new(), "n1".to_string(), vec!["n1".to_string()])
+            .with_workload(Workload::Counter);
+        let add = |msg_id: u64, delta: u64| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"add","msg_id":{},"delta":{}}}}}"#,
+                msg_id, delta
+            )
+        };
+        n.handle(&add(1, u64::MAX - 1)).unwrap();
+        n.handle(&add(2, 2)).unwrap();
+        n.handle(&add(3, 1)).unwrap();
+        n.flush().await.unwrap();
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        let codes = out
+            .lines()
+            .filter(|l| l.contains(r#""in_reply_to""#))
+            .map(|l| l.contains(r#""code":12"#))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, [false, true, false]);
+        assert_eq!(n.counter.read_now(), u64::MAX);
+    }
+
+    #[test]
+    fn crdt_counts_that_cant_add_up_are_refused() {
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Counter)
+            .with_counter_mode(CounterMode::Crdt);
+        let state = |src: &str, node: &str, count: u64| {
+            format!(
+                r#"{{"src":"{}","dest":"n1","body":{{"type":"counter_state","counts":{{"{}":{}}}}}}}"#,
+                src, node, count
+            )
+        };
+        n.handle(&state("n2", "n2", u64::MAX - 1)).unwrap();
+        n.handle(&state("n3", "n3", 2)).unwrap_err();
+        n.handle(&state("c1", "n3", 1)).unwrap_err();
+        // a bigger count for a node we know about replaces it, it doesn't add
+        n.handle(&state("n3", "n2", u64::MAX)).unwrap();
+        assert_eq!(n.counter.read_now(), u64::MAX);
+        assert_eq!(n.counter.counts.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counter_gossips_on_its_own_timer() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes)
+            .with_workload(Workload::Counter)
+            .with_counter_mode(CounterMode::Crdt);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1,"delta":3}}"#)
+            .unwrap();
+        let counts_sent = |n: &mut Node<Vec<u8>>| {
+            let out = String::from_utf8(std::mem::take(&mut n.output)).unwrap();
+            out.matches(r#""type":"counter_state""#).count()
+        };
+
+        time::advance(GOSSIP_INTERVAL).await;
+        n.fire_timers();
+        n.flush().await.unwrap();
+        assert_eq!(counts_sent(&mut n), 0);
+        time::advance(counter::COUNTER_INTERVAL - GOSSIP_INTERVAL).await;
+        n.fire_timers();
+        n.flush().await.unwrap();
+        assert_eq!(counts_sent(&mut n), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_param_changes_a_running_node() {
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        for (msg_id, name, value) in [
+            (1, "gossip-interval", "100"),
+            (2, "topology", "ring"),
+            (3, "gossip-rate", "0"),
+            (4, "batch-window", "10"),
+        ] {
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"set_param","msg_id":{},"name":"{}","value":"{}"}}}}"#,
+                msg_id, name, value
+            ))
+            .unwrap();
+        }
+        n.flush().await.unwrap();
+
+        let answers = String::from_utf8(std::mem::take(&mut n.output))
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Msg>(l).unwrap().body.extra)
+            .collect::<Vec<_>>();
+        assert!(matches!(&answers[0], Payload::SetParamOk { was } if was == "300"));
+        assert!(matches!(&answers[1], Payload::SetParamOk { was } if was == "all"));
+        assert!(matches!(
+            answers[2],
+            Payload::Error {
+                code: MALFORMED_REQUEST,
+                ..
+            }
+        ));
+        assert!(matches!(
+            answers[3],
+            Payload::Error {
+                code: NOT_SUPPORTED,
+                ..
+            }
+        ));
+        assert_eq!(n.gossip_interval, Duration::from_millis(100));
+        assert!(n.gossip_limit.is_none());
+        assert_eq!(n.broadcast.shape, Some(topology::Shape::Ring));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_for_someone_else_are_dropped() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        n.handle(r#"{"src":"c1","dest":"n2","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#)
+            .unwrap();
+        n.handle(r#"{"src":"n7","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#)
+            .unwrap();
+        n.handle(r#"{"src":"seq-kv","dest":"n1","body":{"type":"write_ok","in_reply_to":3}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+
+        // n7 isn't ours but still gets its answer
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains(r#""dest":"n7""#));
+        let metrics = n.metrics();
+        assert_eq!((metrics.misrouted, metrics.unknown_senders), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn paranoid_nodes_turn_away_suspicious_messages() {
+        let lines = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi\u0007"}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast_ok","in_reply_to":9}}"#,
+        ];
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes.clone());
+        for line in lines {
+            n.handle(line).unwrap();
+        }
+        n.flush().await.unwrap();
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert!(out.contains("echo_ok"));
+        assert_eq!(n.metrics().paranoid_rejected, 0);
+
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes).with_paranoid();
+        for line in lines {
+            n.handle(line).unwrap();
+        }
+        n.flush().await.unwrap();
+        // only the echo asked for an answer
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains(r#""code":12"#));
+        assert!(out.contains("message.body.echo has control characters"));
+        assert_eq!(n.metrics().paranoid_rejected, 2);
+    }
+
+    #[test]
+    fn slow_handlers_are_counted() {
+        let mut n = Node::new(Vec::new(), "n1".to_string(), vec!["n1".to_string()])
+            .with_slow_handler(Duration::from_secs(60));
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#)
+            .unwrap();
+        assert_eq!(n.metrics().slow_handlers, 0);
+
+        // everything takes at least no time at all
+        let mut n = n.with_slow_handler(Duration::ZERO);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#)
+            .unwrap();
+        n.handle("not json").unwrap_err();
+        assert_eq!(n.metrics().slow_handlers, 2);
+    }
+
+    // every peer answered quickly every time, then we had nothing for them
+    // for a while
+    async fn idle_but_healthy(n: &mut Node<Vec<u8>>) {
+        for message in 0..5 {
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                message + 1,
+                message
+            ))
+            .unwrap();
+            n.flush().await.unwrap();
+            let out = String::from_utf8(std::mem::take(&mut n.output)).unwrap();
+            time::advance(Duration::from_millis(20)).await;
+            for line in out.lines() {
+                let msg = serde_json::from_str::<Msg>(line).unwrap();
+                if msg.dest != "c1" {
+                    n.handle(&format!(
+                        r#"{{"src":"{}","dest":"n1","body":{{"type":"broadcast_ok","in_reply_to":{}}}}}"#,
+                        msg.dest,
+                        msg.body.msg_id.unwrap()
+                    ))
+                    .unwrap();
+                }
+            }
+        }
+        time::advance(Duration::from_secs(2)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_then_broadcast_sends_immediately() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        idle_but_healthy(&mut n).await;
+        assert!(!n.suspected("n2"));
+
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":9,"message":9}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        let sent = out
+            .lines()
+            .map(|l| serde_json::from_str::<Msg>(l).unwrap())
+            .any(|m| m.dest == "n2" && matches!(m.body.extra, Payload::Broadcast { message: 9 }));
+        assert!(sent, "{}", out);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_neighbors_dont_make_us_flood() {
+        let nodes = ["n1", "n2", "n3", "n4", "n5"].map(String::from).to_vec();
+        let mut n =
+            Node::new(Vec::new(), "n1".to_string(), nodes).with_topology(topology::Shape::Ring);
+        idle_but_healthy(&mut n).await;
+
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":9,"message":9}}"#)
+            .unwrap();
+        n.flush().await.unwrap();
+        let out = String::from_utf8(n.output.clone()).unwrap();
+        let sent = out
+            .lines()
+            .map(|l| serde_json::from_str::<Msg>(l).unwrap())
+            .filter(|m| m.dest != "c1")
+            .count();
+        assert_eq!(sent, 2, "{}", out);
+        assert_eq!(n.metrics().flooded_around_suspects, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_output_holds_off_gossip() {
+        use tokio::io::AsyncReadExt;
+
+        let (output, mut reader) = io::duplex(64);
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(output, "n1".to_string(), nodes);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#)
+            .unwrap();
+        // nothing reads the pipe for a while, the flush has to wait for it
+        let drain = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(500)).await;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+        });
+        n.flush().await.unwrap();
+        assert_eq!(n.metrics.outbox_high_water, 3);
+
+        n.gossip();
+        assert_eq!(n.metrics.gossip_rounds_backpressured, 1);
+        // a quick flush, even of nothing, lets the next round through
+        n.flush().await.unwrap();
+        n.gossip();
+        assert_eq!(n.metrics.gossip_rounds_backpressured, 1);
+        drop(n);
+        drain.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unmatched_replies_are_counted() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#)
+            .unwrap();
+        for reply in [
+            // the real one, then the same again
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast_ok","in_reply_to":1}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast_ok","in_reply_to":1}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast_ok","in_reply_to":99}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast_ok"}}"#,
+        ] {
+            n.handle(reply).unwrap();
+        }
+        assert_eq!(n.pending.len(), 0);
+        assert_eq!(n.metrics.unmatched_replies, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lasting_divergence_pulls_the_peers_state() {
+        let nodes = ["n1", "n2"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        n.handle(r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#)
+            .unwrap();
+        n.gossip_checksum().unwrap();
+        let own = n.divergence.own.clone().unwrap();
+        let from_n2 = |checksum: &str| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"checksum","msg_id":1,"checksum":"{}"}}}}"#,
+                checksum
+            )
+        };
+        // a match in between starts the count over
+        for checksum in ["beef", "beef", "beef", &own, "beef", "beef", "beef"] {
+            n.handle(&from_n2(checksum)).unwrap();
+        }
+        assert_eq!(n.metrics.divergences, 0);
+        n.output.clear();
+        n.handle(&from_n2("beef")).unwrap();
+        assert_eq!(n.metrics.divergences, 1);
+
+        n.flush().await.unwrap();
+        let out = String::from_utf8(std::mem::take(&mut n.output)).unwrap();
+        assert!(out
+            .lines()
+            .map(|l| serde_json::from_str::<Msg>(l).unwrap())
+            .any(|m| m.dest == "n2" && matches!(m.body.extra, Payload::Read { after: None, .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ack_marks_stop_at_the_first_gap() {
+        let nodes = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut n = Node::new(Vec::new(), "n1".to_string(), nodes);
+        for message in 0..3 {
+            n.handle(&format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                message + 1,
+                message
+            ))
+            .unwrap();
+        }
+        // each broadcast went out as n2, n3, then the reply to c1
+        for (peer, msg_id) in [("n2", 1), ("n2", 7), ("n3", 2), ("n3", 5), ("n3", 8)] {
+            n.handle(&format!(
+                r#"{{"src":"{}","dest":"n1","body":{{"type":"broadcast_ok","in_reply_to":{}}}}}"#,
+                peer, msg_id
+            ))
+            .unwrap();
+        }
+        assert_eq!(n.ack_marks(), "n2:1/3,n3:3/3");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_is_acked_and_forwarded() {
+        let out = run_script(&[
+            INIT,
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":42}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3}}"#,
+        ])
+        .await;
+
+        assert!(out
+            .iter()
+            .any(|m| m.dest == "c1" && matches!(m.body.extra, Payload::BroadcastOk)));
+        assert!(out
+            .iter()
+            .any(|m| m.dest == "n2" && matches!(m.body.extra, Payload::Broadcast { message: 42 })));
+        assert!(out.iter().any(
+            |m| matches!(&m.body.extra, Payload::ReadOk { messages: Some(messages), .. } if messages == &[42])
+        ));
+    }
+
+    #[test]
+    fn banner_echoes_defaults_and_overrides() {
+        let config = Config {
+            gossip_rate: Some(50.0),
+            counter: Some(CounterMode::Crdt),
+            ..Config::default()
+        };
+        let n = Node::new(Vec::new(), "n1".to_string(), vec!["n1".to_string()])
+            .with_counter_mode(CounterMode::Crdt);
+        let banner = n.banner(&config);
+        assert_eq!(banner["git"], env!("FLY_GIT_HASH"));
+        assert_eq!(
+            banner["gossip_interval_ms"],
+            GOSSIP_INTERVAL.as_millis() as u64
+        );
+        assert_eq!(banner["gossip_rate"], 50.0);
+        assert_eq!(banner["topology"], "all");
+        assert_eq!(banner["counter"], "crdt");
+        assert_eq!(banner["read_mode"], "local");
+        assert_eq!(banner["ids"], "counter");
+    }
+}
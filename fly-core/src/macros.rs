@@ -0,0 +1,52 @@
+// declares every message type in one place: generates the serde enum that
+// goes on the wire (internally tagged by `type`, snake_case) and the dispatch
+// table that hands each payload to its handler. a variant with `=> handler`
+// gets routed to `node.handler(msg, fields..)` with its fields by reference,
+// which returns what to answer with. variants without one are ignored, that's
+// usually the *_ok of something we never ask for
+//
+//     workload! {
+//         Echo { echo: String } => echo,
+//         EchoOk { echo: String },
+//     }
+macro_rules! workload {
+    (
+        $(
+            $(#[$vattr:meta])*
+            $variant:ident $({
+                $( $(#[$fattr:meta])* $field:ident : $fty:ty ),* $(,)?
+            })? $(=> $handler:ident)?
+        ),* $(,)?
+    ) => {
+        #[derive(Serialize, Clone, Deserialize, Debug)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        pub enum Payload {
+            $(
+                $(#[$vattr])*
+                $variant $({ $( $(#[$fattr])* $field: $fty ),* })?,
+            )*
+        }
+
+        impl<W: AsyncWrite + Unpin> Node<W> {
+            // routes a message to its workload, returns what to answer with if anything
+            fn dispatch(&mut self, msg: &Msg) -> Result<Option<Payload>> {
+                #[allow(unused_variables)]
+                match &msg.body.extra {
+                    $(
+                        Payload::$variant $({ $($field),* })? => {
+                            workload!(@call self, msg, $($handler)?, $($($field),*)?)
+                        }
+                    )*
+                }
+            }
+        }
+    };
+
+    (@call $node:ident, $msg:ident, $handler:ident, $($field:ident),*) => {
+        $node.$handler($msg $(, $field)*)
+    };
+    (@call $node:ident, $msg:ident, , $($field:ident),*) => {
+        Ok(None)
+    };
+}
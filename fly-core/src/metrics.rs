@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::time::Duration;
+
+// plain counters, the node bumps them inline and dumps them to stderr as a
+// single json line every STATS_INTERVAL so they're easy to grep out of the
+// maelstrom node logs
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Metrics {
+    // pending rpcs dropped because they were retried for longer than the ttl
+    pub pending_expired: u64,
+    // pending rpcs dropped because the table was over its cap
+    pub pending_evicted: u64,
+    // pending rpcs dropped after MAX_ATTEMPTS, sync takes it from there
+    pub rpcs_retired: u64,
+    // replies from peers to msg_ids that weren't pending (anymore)
+    pub unmatched_replies: u64,
+    // client retries answered from the dedup cache instead of being handled again
+    pub duplicate_requests: u64,
+    // dedup entries dropped before their ttl because the cache was full
+    pub dedup_evicted: u64,
+    // answers held for client retries right now
+    pub dedup_entries: u64,
+    // rpcs parked for a suspected peer that went out as soon as it showed up again
+    pub hints_handed_off: u64,
+    // gossip rounds that skipped retransmitting because --gossip-rate was
+    // still holding back the previous round
+    pub gossip_rounds_throttled: u64,
+    // values sent to everyone instead of just the --topology neighbors because
+    // one of those was suspected
+    pub flooded_around_suspects: u64,
+    // peers whose state checksum kept differing from ours, see checksum.rs
+    pub divergences: u64,
+    // gossip rounds skipped because the last flush was slow, i.e. whoever
+    // reads our stdout wasn't keeping up
+    pub gossip_rounds_backpressured: u64,
+    // the most lines ever waiting in the outbox at the start of a flush
+    pub outbox_high_water: u64,
+    // messages --drop-rate threw away on purpose
+    pub chaos_dropped: u64,
+    // peers that came back as a new process, see status.rs
+    pub peer_restarts: u64,
+    // client reads we stopped working on because the client has given up on
+    // them by now, see --client-timeout
+    pub reads_past_deadline: u64,
+    // calls for those that were still queued and never went out
+    pub calls_past_deadline: u64,
+    // messages that took longer than --slow-handler to handle
+    pub slow_handlers: u64,
+    // messages addressed to some other node, dropped
+    pub misrouted: u64,
+    // messages from something that looks like a node but isn't in node_ids
+    pub unknown_senders: u64,
+    // messages --paranoid turned away
+    pub paranoid_rejected: u64,
+    // seq-kv keys a --read-mode local read took from the cache, and the ones
+    // it had to read
+    pub kv_cache_hits: u64,
+    pub kv_cache_misses: u64,
+}
+
+// exact to the millisecond, one count per distinct value. whatever we record
+// is bounded by the pending ttl, so that's at most a couple hundred thousand
+// buckets even on a terrible run
+#[derive(Debug, Default)]
+pub struct Histogram {
+    counts: BTreeMap<u128, u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, d: Duration) {
+        *self.counts.entry(d.as_millis()).or_default() += 1;
+        self.total += 1;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    // nearest rank, p in 0..=100
+    pub fn percentile(&self, p: f64) -> Option<u128> {
+        let rank = ((p / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (ms, count) in &self.counts {
+            seen += count;
+            if seen >= rank {
+                return Some(*ms);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::default();
+        assert_eq!(h.percentile(50.0), None);
+        for ms in 1..=100 {
+            h.record(Duration::from_millis(ms));
+        }
+        assert_eq!(h.percentile(50.0), Some(50));
+        assert_eq!(h.percentile(95.0), Some(95));
+        assert_eq!(h.percentile(99.0), Some(99));
+        assert_eq!(h.percentile(100.0), Some(100));
+    }
+}
@@ -0,0 +1,280 @@
+use crate::dedup::DedupCache;
+use crate::metrics::Metrics;
+use crate::{Msg, Payload};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// hooks around the node's input and output for things that don't belong in the
+// handlers themselves. every middleware sees every message in registration
+// order, and any of them can drop it, in which case the ones after it don't
+// see it at all
+pub trait Middleware: Send {
+    // after parsing, before the node handles it
+    fn inbound(&mut self, _line: &str, _msg: &Msg) -> bool {
+        true
+    }
+
+    // once every inbound() let the message through: a middleware that already
+    // knows the answer hands it back here, it goes out as the reply and the
+    // node doesn't handle the message at all. the first answer wins
+    fn answer(&mut self, _msg: &Msg) -> Option<Payload> {
+        None
+    }
+
+    // what the node answered to a message it handled, None when it didn't
+    // (or not right away)
+    fn handled(&mut self, _msg: &Msg, _reply: Option<&Payload>) {}
+
+    // once the message is framed, before it gets queued for output
+    fn outbound(&mut self, _dest: &str, _line: &str) -> bool {
+        true
+    }
+
+    // adds whatever the middleware counts to the node's stats line
+    fn report(&self, _metrics: &mut Metrics) {}
+}
+
+// everything in and out goes to stderr, which maelstrom keeps per node. on a
+// long run that's hundreds of megabytes, most of it sync pages and gossip, so
+// lines can be cut at `max_bytes` (--log-max-bytes) and a type can be sampled,
+// only every nth message of it logged (--log-sample read_ok=100)
+#[derive(Default)]
+pub struct Logging {
+    max_bytes: Option<usize>,
+    sample: HashMap<String, u64>,
+    // messages of each sampled type so far, in and out
+    seen: HashMap<String, u64>,
+}
+
+impl Logging {
+    pub fn new(max_bytes: Option<usize>, sample: HashMap<String, u64>) -> Self {
+        Logging {
+            max_bytes,
+            sample,
+            seen: HashMap::new(),
+        }
+    }
+
+    // what goes to stderr for `line`, if anything
+    fn format(&mut self, prefix: &str, line: &str) -> Option<String> {
+        let kind = message_type(line).unwrap_or_default();
+        if let Some(every) = self.sample.get(kind) {
+            let seen = self.seen.entry(kind.to_string()).or_default();
+            *seen += 1;
+            if !(*seen - 1).is_multiple_of(*every) {
+                return None;
+            }
+        }
+        match self.max_bytes {
+            Some(max) if line.len() > max => {
+                let mut cut = max;
+                while !line.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                let more = line.len() - cut;
+                Some(format!(
+                    "{}{}... ({} more bytes)",
+                    prefix,
+                    &line[..cut],
+                    more
+                ))
+            }
+            _ => Some(format!("{}{}", prefix, line)),
+        }
+    }
+}
+
+// the first "type" in the line, which is the body's: src and dest come first
+// and are node or client ids
+pub(crate) fn message_type(line: &str) -> Option<&str> {
+    let start = line.find(r#""type":""#)? + r#""type":""#.len();
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
+}
+
+impl Middleware for Logging {
+    fn inbound(&mut self, line: &str, _msg: &Msg) -> bool {
+        if let Some(line) = self.format("", line) {
+            eprintln!("{}", line);
+        }
+        true
+    }
+
+    fn outbound(&mut self, _dest: &str, line: &str) -> bool {
+        if let Some(line) = self.format("out: ", line) {
+            eprintln!("{}", line);
+        }
+        true
+    }
+}
+
+// how long we remember answers to client requests in case the client retries.
+// maelstrom's clients give up on a request after a few seconds, so this is
+// plenty, and the cap keeps a long run from piling them up
+const DEDUP_TTL: Duration = Duration::from_secs(60);
+const DEDUP_CAP: usize = 100_000;
+
+// a client retrying something we've already done gets the same answer again
+// instead of it being done twice. broadcast would get away without this since
+// its values are unique, but an add or anything else whose payloads can repeat
+// can't. only client requests: peers never reuse a msg_id and replies from
+// services like seq-kv aren't requests. reads have no effects and their replies
+// can be huge, so those are just handled again. a request the node answers
+// later on its own doesn't get replayed, a retry of it is dropped
+pub struct Dedup {
+    replied: DedupCache<Option<Payload>>,
+    duplicates: u64,
+    evicted: u64,
+}
+
+impl Dedup {
+    pub fn new(ttl: Duration, cap: usize) -> Self {
+        Dedup {
+            replied: DedupCache::new(ttl, cap),
+            duplicates: 0,
+            evicted: 0,
+        }
+    }
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Dedup::new(DEDUP_TTL, DEDUP_CAP)
+    }
+}
+
+// maelstrom names its clients c1, c2, ...
+fn client_request(msg: &Msg) -> Option<u64> {
+    match msg.body.msg_id {
+        Some(msg_id)
+            if msg.src.starts_with('c')
+                && msg.body.in_reply_to.is_none()
+                && !matches!(msg.body.extra, Payload::Read { .. }) =>
+        {
+            Some(msg_id)
+        }
+        _ => None,
+    }
+}
+
+impl Middleware for Dedup {
+    // drops retries of requests that didn't get an answer the first time
+    fn inbound(&mut self, _line: &str, msg: &Msg) -> bool {
+        let Some(msg_id) = client_request(msg) else {
+            return true;
+        };
+        self.replied.expire(Instant::now());
+        if let Some(None) = self.replied.get(&msg.src, msg_id) {
+            self.duplicates += 1;
+            return false;
+        }
+        true
+    }
+
+    fn answer(&mut self, msg: &Msg) -> Option<Payload> {
+        let msg_id = client_request(msg)?;
+        let reply = self.replied.get(&msg.src, msg_id)?.clone()?;
+        self.duplicates += 1;
+        Some(reply)
+    }
+
+    fn handled(&mut self, msg: &Msg, reply: Option<&Payload>) {
+        if let Some(msg_id) = client_request(msg) {
+            self.evicted +=
+                self.replied
+                    .insert(msg.src.clone(), msg_id, reply.cloned(), Instant::now())
+                    as u64;
+        }
+    }
+
+    fn report(&self, metrics: &mut Metrics) {
+        metrics.duplicate_requests += self.duplicates;
+        metrics.dedup_evicted += self.evicted;
+        metrics.dedup_entries += self.replied.len() as u64;
+    }
+}
+
+// --drop-rate, maelstrom's lossy network without maelstrom: every message we
+// send is thrown away with probability `rate`, so retries and sync can be
+// watched doing their job locally. replies to clients are spared unless
+// --drop-replies says otherwise, a client that never hears back just times
+// out and that tests nothing of ours. seeded (--drop-seed), the same input
+// loses the same messages
+pub struct Chaos {
+    rate: f64,
+    replies: bool,
+    rng: StdRng,
+    dropped: u64,
+}
+
+impl Chaos {
+    pub fn new(rate: f64, seed: u64, replies: bool) -> Self {
+        Chaos {
+            rate,
+            replies,
+            rng: StdRng::seed_from_u64(seed),
+            dropped: 0,
+        }
+    }
+}
+
+impl Middleware for Chaos {
+    fn outbound(&mut self, dest: &str, _line: &str) -> bool {
+        if dest.starts_with('c') && !self.replies {
+            return true;
+        }
+        if self.rng.random_bool(self.rate) {
+            self.dropped += 1;
+            return false;
+        }
+        true
+    }
+
+    fn report(&self, metrics: &mut Metrics) {
+        metrics.chaos_dropped += self.dropped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logging_samples_and_truncates() {
+        let sample = HashMap::from([("read_ok".to_string(), 3)]);
+        let mut logging = Logging::new(Some(40), sample);
+        let read_ok = r#"{"src":"n1","dest":"n2","body":{"type":"read_ok","messages":[1,2,3]}}"#;
+        let logged = (0..9)
+            .filter(|_| logging.format("", read_ok).is_some())
+            .count();
+        assert_eq!(logged, 3);
+        assert_eq!(
+            logging.format("out: ", read_ok).unwrap(),
+            r#"out: {"src":"n1","dest":"n2","body":{"type":"... (29 more bytes)"#
+        );
+        // not sampled, and short enough
+        let echo = r#"{"body":{"type":"echo"}}"#;
+        assert!((0..3).all(|_| logging.format("", echo).as_deref() == Some(echo)));
+    }
+
+    #[test]
+    fn chaos_spares_clients_and_repeats_itself() {
+        let mut chaos = Chaos::new(0.5, 7, false);
+        assert!((0..100).all(|_| chaos.outbound("c1", "")));
+        let first = (0..100)
+            .map(|_| chaos.outbound("n2", ""))
+            .collect::<Vec<_>>();
+        assert!(first.iter().any(|kept| *kept) && first.iter().any(|kept| !kept));
+
+        let mut again = Chaos::new(0.5, 7, true);
+        let lost_to_clients = (0..100).filter(|_| !again.outbound("c1", "")).count();
+        assert!(lost_to_clients > 0);
+        let mut again = Chaos::new(0.5, 7, false);
+        let second = (0..100)
+            .map(|_| again.outbound("n2", ""))
+            .collect::<Vec<_>>();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::simulator::{Fate, Simulator, Traced};
+use crate::workloads::counter::{self, SEQ_KV};
+use crate::{CounterMode, Payload, Workload, MALFORMED_REQUEST};
+use std::collections::BTreeSet;
+
+// trace lines a failure report shows at most
+const TRACE_LINES: usize = 20;
+// values of a set a failure report lists at most
+const VALUES: usize = 10;
+
+// what every node should have ended up with, worked out from nothing but the
+// simulator's history of client requests and answers. a value a client got an
+// ack for has to be there, one nobody asked for can't be, and whatever wasn't
+// acked may or may not have made it. workloads without any state to check
+// (echo, unique ids) always pass. meant for once the cluster has settled,
+// it doesn't know about anything still in flight
+pub fn check(sim: &Simulator) -> Result<(), String> {
+    let serves = |w| sim.nodes.values().any(|node| node.served().contains(&w));
+    let mut problems = Vec::new();
+    if serves(Workload::Broadcast) {
+        broadcast(sim, &mut problems);
+    }
+    if serves(Workload::Counter) {
+        counter(sim, &mut problems);
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+fn broadcast(sim: &Simulator, problems: &mut Vec<String>) {
+    let mut asked = BTreeSet::new();
+    let mut acked = BTreeSet::new();
+    for op in sim.history.values() {
+        if let Payload::Broadcast { message } = op.request {
+            asked.insert(message);
+            if matches!(op.reply, Some(Payload::BroadcastOk)) {
+                acked.insert(message);
+            }
+        }
+    }
+    for id in sim.nodes.keys() {
+        let has = sim.messages(id).into_iter().collect::<BTreeSet<_>>();
+        let missing = acked.difference(&has).copied().collect::<Vec<_>>();
+        if let Some(first) = missing.first() {
+            problems.push(format!(
+                "{} is missing {} of {} acked values: {}",
+                id,
+                missing.len(),
+                acked.len(),
+                list(&missing)
+            ));
+            problems.extend(trace(sim, |t| {
+                carries(&t.msg.body.extra, *first) && (t.msg.dest == *id || t.msg.src == *id)
+            }));
+        }
+        let invented = has.difference(&asked).copied().collect::<Vec<_>>();
+        if !invented.is_empty() {
+            problems.push(format!(
+                "{} has values nobody broadcast: {}",
+                id,
+                list(&invented)
+            ));
+        }
+    }
+}
+
+// the total has to be somewhere between everything acked and everything
+// asked for. with seq-kv that's the sum of the nodes' keys, with the crdt
+// every node's own sum of the counts it knows. an add the node refused, one
+// that would have overflowed, wasn't asked for at all. the sums saturate like
+// the node's do
+fn counter(sim: &Simulator, problems: &mut Vec<String>) {
+    let mut asked = 0u64;
+    let mut acked = 0u64;
+    for op in sim.history.values() {
+        if let Payload::Add { delta } = op.request {
+            match op.reply {
+                Some(Payload::Error {
+                    code: MALFORMED_REQUEST,
+                    ..
+                }) => continue,
+                Some(Payload::AddOk) => acked = acked.saturating_add(delta),
+                _ => {}
+            }
+            asked = asked.saturating_add(delta);
+        }
+    }
+    let crdt = sim
+        .nodes
+        .values()
+        .any(|node| node.counter.mode == CounterMode::Crdt);
+    let totals = if crdt {
+        sim.nodes
+            .iter()
+            .map(|(id, node)| (id.clone(), counter::sum(&node.counter.counts)))
+            .collect::<Vec<_>>()
+    } else {
+        let total = sim
+            .kv
+            .iter()
+            .filter(|(key, _)| key.starts_with("counter-"))
+            .fold(0u64, |total, (_, value)| total.saturating_add(*value));
+        vec![(SEQ_KV.to_string(), total)]
+    };
+    for (id, total) in totals {
+        if total < acked || total > asked {
+            problems.push(format!(
+                "{} has a total of {}, expected between {} acked and {} asked for",
+                id, total, acked, asked
+            ));
+            problems.extend(trace(sim, |t| {
+                (t.msg.dest == id || t.msg.src == id)
+                    && matches!(
+                        t.msg.body.extra,
+                        Payload::CounterState { .. } | Payload::Cas { .. } | Payload::CasOk
+                    )
+            }));
+        }
+    }
+}
+
+fn carries(payload: &Payload, value: usize) -> bool {
+    match payload {
+        Payload::Broadcast { message } | Payload::Relay { message, .. } => *message == value,
+        _ => false,
+    }
+}
+
+// the last TRACE_LINES matching messages, oldest first
+fn trace(sim: &Simulator, matches: impl Fn(&Traced) -> bool) -> Vec<String> {
+    let mut lines = sim
+        .trace
+        .iter()
+        .rev()
+        .filter(|t| matches(t))
+        .take(TRACE_LINES)
+        .map(|t| {
+            let fate = match t.fate {
+                Fate::Sent => "sent",
+                Fate::Duplicated => "duplicated",
+                Fate::Dropped => "dropped",
+                Fate::Cut => "cut",
+            };
+            let body = serde_json::to_string(&t.msg.body).unwrap_or_default();
+            format!(
+                "  {:>7}ms {} -> {} {} {}",
+                t.at.as_millis(),
+                t.msg.src,
+                t.msg.dest,
+                fate,
+                body
+            )
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        lines.push("  nothing about it went over the network".to_string());
+    }
+    lines.reverse();
+    lines
+}
+
+fn list<T: std::fmt::Display>(values: &[T]) -> String {
+    let mut shown = values
+        .iter()
+        .take(VALUES)
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>();
+    if values.len() > VALUES {
+        shown.push(format!("... ({} more)", values.len() - VALUES));
+    }
+    shown.join(", ")
+}
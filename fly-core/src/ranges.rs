@@ -0,0 +1,300 @@
+use crate::delta::{put_varint, varints};
+use crate::error::{invalid, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+// the most values a set we decode may cover. ranges are a few bytes however
+// many values they stand for, so unlike a json array nothing else bounds what
+// a peer can send us. a whole maelstrom run is a few thousand, and a store
+// that spills writes out every one of them
+pub const MAX_DECODED: usize = 1 << 24;
+
+// a set of integers as sorted inclusive ranges that neither overlap nor touch.
+// maelstrom's broadcast values are dense, 0 to however many the run sent, so
+// after a while this is a handful of ranges where a HashSet would have an
+// entry per value. what MessageStore keeps in memory, and what sync pages and
+// checksums are made of
+#[derive(Default, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ranges {
+    // start -> end
+    ranges: BTreeMap<usize, usize>,
+    len: usize,
+}
+
+impl Ranges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= value)
+    }
+
+    // true if the value wasn't there yet
+    pub fn insert(&mut self, value: usize) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+        self.insert_range(value, value);
+        true
+    }
+
+    // start..=end, merged with whatever it overlaps or touches
+    pub fn insert_range(&mut self, mut start: usize, mut end: usize) {
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back() {
+            if e >= end {
+                return;
+            }
+            if e.saturating_add(1) >= start {
+                self.remove(s);
+                start = s;
+            }
+        }
+        while let Some((&s, &e)) = self.ranges.range(start..).next() {
+            if s > end.saturating_add(1) {
+                break;
+            }
+            self.remove(s);
+            end = end.max(e);
+        }
+        self.ranges.insert(start, end);
+        self.len = self.len.saturating_add(span(start, end));
+    }
+
+    fn remove(&mut self, start: usize) {
+        if let Some(end) = self.ranges.remove(&start) {
+            self.len = self.len.saturating_sub(span(start, end));
+        }
+    }
+
+    // values, not ranges
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // how many ranges, which is what the memory goes by
+    pub fn runs(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        self.ranges.last_key_value().map(|(_, end)| *end)
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+        self.len = 0;
+    }
+
+    // (start, end), both inclusive, in order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ranges.iter().map(|(start, end)| (*start, *end))
+    }
+
+    // sorted
+    pub fn values(&self) -> impl Iterator<Item = usize> + '_ {
+        self.iter().flat_map(|(start, end)| start..=end)
+    }
+
+    // everything above `after`, for paging
+    pub fn after(&self, after: usize) -> Ranges {
+        let mut rest = Ranges::new();
+        if let Some((_, &end)) = self.ranges.range(..=after).next_back() {
+            if end > after {
+                rest.insert_range(after + 1, end);
+            }
+        }
+        for (&start, &end) in self
+            .ranges
+            .range((Bound::Excluded(after), Bound::Unbounded))
+        {
+            rest.insert_range(start, end);
+        }
+        rest
+    }
+
+    // split up so that none has more than `runs` ranges or more than
+    // `values` values, a range too big for one goes over several
+    pub fn chunks(&self, runs: usize, values: usize) -> Vec<Ranges> {
+        let mut chunks = Vec::new();
+        let mut part = Ranges::new();
+        for (mut start, end) in self.iter() {
+            loop {
+                let room = values - part.len();
+                let last = end.min(start.saturating_add(room - 1));
+                part.insert_range(start, last);
+                if part.runs() == runs || part.len() == values {
+                    chunks.push(std::mem::take(&mut part));
+                }
+                if last == end {
+                    break;
+                }
+                start = last + 1;
+            }
+        }
+        if part.runs() > 0 {
+            chunks.push(part);
+        }
+        chunks
+    }
+
+    // keeps the first `runs` ranges
+    pub fn truncate(&mut self, runs: usize) {
+        let Some(&cut) = self.ranges.keys().nth(runs) else {
+            return;
+        };
+        for (start, end) in self.ranges.split_off(&cut) {
+            self.len = self.len.saturating_sub(span(start, end));
+        }
+    }
+}
+
+// how many values start..=end holds. all of usize is one more than fits, len
+// just stops there, decode never lets such a range in anyway
+fn span(start: usize, end: usize) -> usize {
+    (end - start).saturating_add(1)
+}
+
+impl Extend<usize> for Ranges {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, values: I) {
+        for value in values {
+            self.insert(value);
+        }
+    }
+}
+
+impl FromIterator<usize> for Ranges {
+    fn from_iter<I: IntoIterator<Item = usize>>(values: I) -> Self {
+        let mut ranges = Ranges::new();
+        ranges.extend(values);
+        ranges
+    }
+}
+
+// same idea as delta::encode, but per range: the gap since the end of the
+// previous one and how many values it has beyond the first, as varints. a
+// page of a dense set is a couple of bytes however many values it covers
+pub fn encode(ranges: &Ranges) -> String {
+    let mut bytes = Vec::with_capacity(ranges.runs() * 2);
+    let mut next = 0;
+    for (start, end) in ranges.iter() {
+        put_varint(&mut bytes, (start - next) as u64);
+        put_varint(&mut bytes, (end - start) as u64);
+        next = end.saturating_add(1);
+    }
+    STANDARD.encode(bytes)
+}
+
+pub fn decode(encoded: &str) -> Result<Ranges> {
+    let varints = varints(encoded)?;
+    if varints.len() % 2 != 0 {
+        return Err(invalid("range without an end"));
+    }
+    let mut ranges = Ranges::new();
+    let mut next = 0usize;
+    let mut values = 0usize;
+    for pair in varints.chunks(2) {
+        let start = next
+            .checked_add(pair[0] as usize)
+            .ok_or_else(|| invalid("range overflows"))?;
+        let end = start
+            .checked_add(pair[1] as usize)
+            .ok_or_else(|| invalid("range overflows"))?;
+        values = values.saturating_add(span(start, end));
+        if values > MAX_DECODED {
+            return Err(invalid(format!(
+                "ranges cover more than {} values",
+                MAX_DECODED
+            )));
+        }
+        ranges.insert_range(start, end);
+        next = end.saturating_add(1);
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_touching_and_overlapping() {
+        let mut ranges = [5, 3, 4, 10, 0, 12, 11].into_iter().collect::<Ranges>();
+        assert_eq!(
+            ranges.iter().collect::<Vec<_>>(),
+            [(0, 0), (3, 5), (10, 12)]
+        );
+        assert!(!ranges.insert(4));
+        ranges.insert_range(1, 9);
+        assert_eq!(ranges.iter().collect::<Vec<_>>(), [(0, 12)]);
+        assert_eq!(ranges.len(), 13);
+        assert!(ranges.contains(12) && !ranges.contains(13));
+    }
+
+    #[test]
+    fn pages() {
+        let ranges = [1, 2, 3, 7, 8, 20].into_iter().collect::<Ranges>();
+        let mut rest = ranges.after(2);
+        assert_eq!(rest.values().collect::<Vec<_>>(), [3, 7, 8, 20]);
+        rest.truncate(2);
+        assert_eq!(rest.values().collect::<Vec<_>>(), [3, 7, 8]);
+        assert_eq!((rest.len(), rest.last()), (3, Some(8)));
+    }
+
+    #[test]
+    fn chunks_stay_under_both_bounds() {
+        let ranges = [0, 2, 4, 6].into_iter().collect::<Ranges>();
+        let chunks = ranges.chunks(3, 100);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.values().collect())
+                .collect::<Vec<Vec<_>>>(),
+            [vec![0, 2, 4], vec![6]]
+        );
+
+        let mut big = Ranges::new();
+        big.insert_range(10, 34);
+        big.insert(40);
+        let chunks = big.chunks(500, 10);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.iter().collect())
+                .collect::<Vec<Vec<_>>>(),
+            [vec![(10, 19)], vec![(20, 29)], vec![(30, 34), (40, 40)]]
+        );
+        assert!(Ranges::new().chunks(500, 10).is_empty());
+    }
+
+    #[test]
+    fn roundtrips() {
+        let ranges = (0..1000)
+            .chain([1 << 40, (1 << 40) + 1])
+            .collect::<Ranges>();
+        let encoded = encode(&ranges);
+        assert!(STANDARD.decode(&encoded).unwrap().len() <= 10);
+        assert_eq!(decode(&encoded).unwrap(), ranges);
+        assert_eq!(decode(&encode(&Ranges::new())).unwrap(), Ranges::new());
+        assert!(decode(&STANDARD.encode([1])).is_err());
+    }
+
+    #[test]
+    fn huge_ranges_dont_decode() {
+        let mut all = Ranges::new();
+        all.insert_range(0, usize::MAX);
+        assert_eq!(all.len(), usize::MAX);
+        assert!(decode(&encode(&all)).is_err());
+
+        let mut most = Ranges::new();
+        most.insert_range(0, MAX_DECODED - 1);
+        assert_eq!(decode(&encode(&most)).unwrap(), most);
+        most.insert_range(MAX_DECODED + 1, MAX_DECODED + 1);
+        assert!(decode(&encode(&most)).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use tokio::time::{Duration, Instant};
+
+// classic token bucket: `rate` tokens a second, holding at most a second's
+// worth, so a quiet node can still send a burst of that size right away
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    // when the next take() is going to succeed
+    pub fn next_token(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            return now;
+        }
+        now + Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_refills_at_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start);
+        assert_eq!((0..20).filter(|_| bucket.take(start)).count(), 10);
+        assert!(!bucket.take(start));
+        assert_eq!(bucket.next_token(start), start + Duration::from_millis(100));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!((0..20).filter(|_| bucket.take(later)).count(), 5);
+        // never more than a second's worth, however long it's been
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| bucket.take(much_later)).count(), 10);
+    }
+}
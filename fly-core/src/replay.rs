@@ -0,0 +1,92 @@
+use crate::audit::{self, Direction};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::time::{self, Duration, Instant};
+
+// the record format is one input line per line, prefixed with the millis since
+// the node started and a tab:
+//
+//   12\t{"src":"c0","dest":"n1","body":{"type":"init",...}}
+//
+// so it's easy to grep/cut by hand too. both sides hand back a reader that the
+// node consumes exactly like stdin, the copying happens in a background task
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+pub async fn record<R>(input: R, path: &Path) -> io::Result<impl AsyncBufRead>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    let mut log = BufWriter::new(File::create(path).await?);
+    let (mut tx, rx) = io::duplex(PIPE_SIZE);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let mut lines = input.lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let at = start.elapsed().as_millis();
+            // flushing every line so a crashed run still leaves a usable log
+            let logged = async {
+                log.write_all(format!("{}\t{}\n", at, line).as_bytes())
+                    .await?;
+                log.flush().await
+            };
+            if let Err(e) = logged.await {
+                eprintln!("recording failed: {}", e);
+            }
+            if tx
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(BufReader::new(rx))
+}
+
+pub async fn replay(path: &Path) -> io::Result<impl AsyncBufRead> {
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let (mut tx, rx) = io::duplex(PIPE_SIZE);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        while let Ok(Some(entry)) = lines.next_line().await {
+            let Some((at, line)) = parse(&entry) else {
+                continue;
+            };
+            time::sleep_until(start + Duration::from_millis(at)).await;
+            if tx
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(BufReader::new(rx))
+}
+
+// a --record line, or an --audit one. of those only what came in gets played
+fn parse(entry: &str) -> Option<(u64, String)> {
+    if entry.starts_with('{') {
+        let entry = audit::parse(entry).or_else(|| {
+            eprintln!("skipping malformed audit entry {}", entry);
+            None
+        })?;
+        if entry.dir != Direction::In {
+            return None;
+        }
+        return Some((entry.at_ms, serde_json::to_string(&entry.msg).ok()?));
+    }
+    let Some((at, line)) = entry.split_once('\t') else {
+        eprintln!("skipping malformed replay entry {}", entry);
+        return None;
+    };
+    let Ok(at) = at.parse::<u64>() else {
+        eprintln!("skipping replay entry with bad timestamp {}", entry);
+        return None;
+    };
+    Some((at, line.to_string()))
+}
@@ -0,0 +1,147 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use tokio::time::{Duration, Instant};
+
+// what a timer hands back to the node when it fires, the node matches on it
+// the same way it matches on payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tick {
+    Gossip,
+    Counter,
+    Sync,
+    Stats,
+    Hello,
+    Checksum,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+// ordered by deadline first, the id breaks ties in registration order
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Timer {
+    at: Instant,
+    id: TimerId,
+    tick: Tick,
+    period: Option<Duration>,
+}
+
+// all of a node's timers in one heap, the main loop sleeps until the earliest
+// deadline and then drains whatever is due. periodic timers that fall behind
+// (a slow flush, a long drain) skip the missed ticks instead of bursting
+#[derive(Default)]
+pub struct Scheduler {
+    heap: BinaryHeap<Reverse<Timer>>,
+    cancelled: HashSet<TimerId>,
+    next_id: u64,
+}
+
+impl Scheduler {
+    pub fn every(&mut self, period: Duration, tick: Tick) -> TimerId {
+        self.schedule(Instant::now() + period, tick, Some(period))
+    }
+
+    pub fn after(&mut self, delay: Duration, tick: Tick) -> TimerId {
+        self.schedule(Instant::now() + delay, tick, None)
+    }
+
+    pub fn cancel(&mut self, id: TimerId) {
+        if self.heap.iter().any(|Reverse(t)| t.id == id) {
+            self.cancelled.insert(id);
+        }
+    }
+
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.skip_cancelled();
+        self.heap.peek().map(|Reverse(t)| t.at)
+    }
+
+    // one due tick per call, in deadline order
+    pub fn pop_due(&mut self, now: Instant) -> Option<Tick> {
+        self.skip_cancelled();
+        if self.heap.peek()?.0.at > now {
+            return None;
+        }
+        let Reverse(mut timer) = self.heap.pop()?;
+        let tick = timer.tick;
+        if let Some(period) = timer.period {
+            timer.at += period;
+            if timer.at <= now {
+                timer.at = now + period;
+            }
+            self.heap.push(Reverse(timer));
+        }
+        Some(tick)
+    }
+
+    fn schedule(&mut self, at: Instant, tick: Tick, period: Option<Duration>) -> TimerId {
+        self.next_id += 1;
+        let id = TimerId(self.next_id);
+        self.heap.push(Reverse(Timer {
+            at,
+            id,
+            tick,
+            period,
+        }));
+        id
+    }
+
+    fn skip_cancelled(&mut self) {
+        while let Some(Reverse(t)) = self.heap.peek() {
+            if !self.cancelled.remove(&t.id) {
+                break;
+            }
+            self.heap.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(s: &mut Scheduler) -> Vec<Tick> {
+        std::iter::from_fn(|| s.pop_due(Instant::now())).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fires_in_deadline_order() {
+        let mut s = Scheduler::default();
+        s.every(Duration::from_millis(300), Tick::Gossip);
+        s.every(Duration::from_millis(1000), Tick::Stats);
+        s.after(Duration::from_millis(500), Tick::Sync);
+        assert!(drain(&mut s).is_empty());
+
+        tokio::time::advance(Duration::from_millis(600)).await;
+        assert_eq!(drain(&mut s), vec![Tick::Gossip, Tick::Sync]);
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert_eq!(drain(&mut s), vec![Tick::Gossip, Tick::Stats]);
+        assert_eq!(
+            s.next_deadline(),
+            Some(Instant::now() + Duration::from_millis(200))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_timers_skip_instead_of_bursting() {
+        let mut s = Scheduler::default();
+        s.every(Duration::from_millis(100), Tick::Gossip);
+        tokio::time::advance(Duration::from_millis(1050)).await;
+        assert_eq!(drain(&mut s), vec![Tick::Gossip]);
+        assert_eq!(
+            s.next_deadline(),
+            Some(Instant::now() + Duration::from_millis(100))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_timers_dont_fire() {
+        let mut s = Scheduler::default();
+        let id = s.every(Duration::from_millis(100), Tick::Gossip);
+        s.after(Duration::from_millis(200), Tick::Sync);
+        s.cancel(id);
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(drain(&mut s), vec![Tick::Sync]);
+        assert_eq!(s.next_deadline(), None);
+    }
+}
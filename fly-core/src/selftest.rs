@@ -0,0 +1,204 @@
+use crate::middleware::Dedup;
+use crate::simulator::Simulator;
+use crate::{CounterMode, Payload, Workload};
+use std::collections::HashSet;
+
+// requests per run, spread round robin over the nodes
+const REQUESTS: usize = 100;
+// gossip rounds broadcast gets to converge before we read
+const ROUNDS: usize = 20;
+
+// wires n nodes together through the simulator, plays a fixed script of client
+// requests at them and checks what the client got back. no maelstrom, no jvm,
+// so it only catches the obvious breakage, not what a real nemesis would find.
+// needs a runtime with the clock paused, same as the simulator tests
+pub async fn run(n: usize, workload: Workload, counter_mode: CounterMode) -> bool {
+    let mut sim = Simulator::new(n);
+    sim.serve(workload);
+    // the simulator's own output is the verdict, not every line on the wire
+    for node in sim.nodes.values_mut() {
+        node.middleware = vec![Box::new(Dedup::default())];
+        node.counter.mode = counter_mode;
+    }
+    let verdict = match workload {
+        Workload::Echo => echo(&mut sim).await,
+        Workload::UniqueIds => unique_ids(&mut sim).await,
+        Workload::Broadcast => broadcast(&mut sim).await,
+        Workload::Counter => counter(&mut sim).await,
+    };
+    match &verdict {
+        Ok(()) => println!("selftest {} n={}: pass", workload.name(), n),
+        Err(e) => println!("selftest {} n={}: FAIL, {}", workload.name(), n, e),
+    }
+    verdict.is_ok()
+}
+
+fn node(i: usize, n: usize) -> String {
+    format!("n{}", i % n)
+}
+
+async fn echo(sim: &mut Simulator) -> Result<(), String> {
+    let n = sim.nodes.len();
+    for i in 0..REQUESTS {
+        let echo = format!("please echo {}", i);
+        sim.client_request("c1", &node(i, n), Payload::Echo { echo });
+    }
+    sim.deliver_all().await.map_err(|e| e.to_string())?;
+    let mut echoed = sim
+        .client_inbox
+        .iter()
+        .filter_map(|m| match &m.body.extra {
+            Payload::EchoOk { echo } => Some(echo.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    echoed.sort();
+    let mut expected = (0..REQUESTS)
+        .map(|i| format!("please echo {}", i))
+        .collect::<Vec<_>>();
+    expected.sort();
+    if echoed != expected {
+        return Err(format!(
+            "{} of {} echoes came back right",
+            echoed.len(),
+            REQUESTS
+        ));
+    }
+    Ok(())
+}
+
+async fn unique_ids(sim: &mut Simulator) -> Result<(), String> {
+    let n = sim.nodes.len();
+    for i in 0..REQUESTS {
+        sim.client_request("c1", &node(i, n), Payload::Generate);
+    }
+    sim.deliver_all().await.map_err(|e| e.to_string())?;
+    let mut seen = HashSet::new();
+    for msg in &sim.client_inbox {
+        if let Payload::GenerateOk { id } = &msg.body.extra {
+            if !seen.insert(id.clone()) {
+                return Err(format!("{} handed out twice", id));
+            }
+        }
+    }
+    if seen.len() != REQUESTS {
+        return Err(format!("{} ids for {} requests", seen.len(), REQUESTS));
+    }
+    Ok(())
+}
+
+async fn broadcast(sim: &mut Simulator) -> Result<(), String> {
+    let n = sim.nodes.len();
+    for message in 0..REQUESTS {
+        sim.client_request("c1", &node(message, n), Payload::Broadcast { message });
+    }
+    sim.settle(ROUNDS).await.map_err(|e| e.to_string())?;
+    let acks = sim
+        .client_inbox
+        .iter()
+        .filter(|m| matches!(m.body.extra, Payload::BroadcastOk))
+        .count();
+    if acks != REQUESTS {
+        return Err(format!("{} of {} broadcasts acked", acks, REQUESTS));
+    }
+
+    // read the way a client would, from every node
+    sim.client_inbox.clear();
+    for i in 0..n {
+        let read = Payload::Read {
+            after: None,
+            limit: None,
+            encoding: None,
+            codec: None,
+            key: None,
+        };
+        sim.client_request("c1", &node(i, n), read);
+    }
+    sim.deliver_all().await.map_err(|e| e.to_string())?;
+    let expected = (0..REQUESTS).collect::<HashSet<_>>();
+    let mut reads = 0;
+    for msg in &sim.client_inbox {
+        if let Payload::ReadOk { messages, .. } = &msg.body.extra {
+            reads += 1;
+            let got = messages.iter().flatten().copied().collect::<HashSet<_>>();
+            if got != expected {
+                return Err(format!(
+                    "{} has {} of {} messages after {} rounds",
+                    msg.src,
+                    got.intersection(&expected).count(),
+                    REQUESTS,
+                    ROUNDS
+                ));
+            }
+        }
+    }
+    if reads != n {
+        return Err(format!("{} of {} reads answered", reads, n));
+    }
+    sim.check()
+}
+
+async fn counter(sim: &mut Simulator) -> Result<(), String> {
+    let n = sim.nodes.len();
+    for i in 0..REQUESTS {
+        let delta = i as u64 + 1;
+        sim.client_request("c1", &node(i, n), Payload::Add { delta });
+    }
+    sim.settle(ROUNDS).await.map_err(|e| e.to_string())?;
+    let acks = sim
+        .client_inbox
+        .iter()
+        .filter(|m| matches!(m.body.extra, Payload::AddOk))
+        .count();
+    if acks != REQUESTS {
+        return Err(format!("{} of {} adds acked", acks, REQUESTS));
+    }
+
+    sim.client_inbox.clear();
+    for i in 0..n {
+        let read = Payload::Read {
+            after: None,
+            limit: None,
+            encoding: None,
+            codec: None,
+            key: None,
+        };
+        sim.client_request("c1", &node(i, n), read);
+    }
+    sim.deliver_all().await.map_err(|e| e.to_string())?;
+    let expected = (1..=REQUESTS as u64).sum::<u64>();
+    let mut reads = 0;
+    for msg in &sim.client_inbox {
+        if let Payload::ReadOk { value, .. } = &msg.body.extra {
+            reads += 1;
+            if *value != Some(expected) {
+                return Err(format!(
+                    "{} read {:?}, expected {}",
+                    msg.src, value, expected
+                ));
+            }
+        }
+    }
+    if reads != n {
+        return Err(format!("{} of {} reads answered", reads, n));
+    }
+    sim.check()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn every_workload_passes() {
+        for workload in [
+            Workload::Echo,
+            Workload::UniqueIds,
+            Workload::Broadcast,
+            Workload::Counter,
+        ] {
+            assert!(run(5, workload, CounterMode::SeqKv).await, "{:?}", workload);
+        }
+        assert!(run(5, Workload::Counter, CounterMode::Crdt).await);
+    }
+}
@@ -0,0 +1,1235 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use tokio::time::{self, Duration, Instant};
+
+use crate::audit::{Direction, Entry};
+use crate::error::invalid;
+use crate::oracle;
+use crate::topology::Shape;
+use crate::workloads::counter::SEQ_KV;
+use crate::{
+    Body, Msg, Node, Payload, Workload, GOSSIP_INTERVAL, KEY_DOES_NOT_EXIST, NOT_SUPPORTED,
+    PRECONDITION_FAILED,
+};
+
+// what the network does to node-to-node traffic, client traffic always goes
+// through untouched like with maelstrom's nemeses. probabilities are per message
+#[derive(Debug, Clone, Default)]
+pub struct Nemesis {
+    pub drop: f64,
+    pub duplicate: f64,
+    // held back for up to max_delay before it shows up on the network again
+    pub delay: f64,
+    pub max_delay: Duration,
+    // delivered out of order, picked at random from whatever is in flight
+    pub reorder: f64,
+    // cut links, both directions
+    pub partitions: Vec<(String, String)>,
+}
+
+impl Nemesis {
+    fn cut(&self, a: &str, b: &str) -> bool {
+        self.partitions
+            .iter()
+            .any(|(x, y)| (x == a && y == b) || (x == b && y == a))
+    }
+}
+
+// a client request and, once it came back, the answer
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub client: String,
+    pub node: String,
+    pub request: Payload,
+    pub reply: Option<Payload>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    Sent,
+    Duplicated,
+    Dropped,
+    Cut,
+}
+
+#[derive(Debug, Clone)]
+pub struct Traced {
+    // since the simulator started
+    pub at: Duration,
+    pub msg: Msg,
+    pub fate: Fate,
+}
+
+// in-process cluster for tests and --selftest. every node writes into a Vec
+// instead of stdout, after each step we parse what it wrote and put it on the
+// in-memory network, and whatever isn't addressed to a node ends up in the
+// client inbox. time is tokio's clock, so whatever drives it needs to run with
+// the clock paused and gossip rounds only happen when it ticks them. seq-kv
+// is a plain map that's never stale, so it's really lin-kv
+pub struct Simulator {
+    pub nodes: BTreeMap<String, Node<Vec<u8>>>,
+    pub client_inbox: Vec<Msg>,
+    pub kv: HashMap<String, u64>,
+    pub nemesis: Nemesis,
+    // node-to-node messages put on the network, dropped ones included
+    pub sent: usize,
+    // every client request by client and msg_id, with the answer once there
+    // is one. msg_ids are only unique per client, a played audit file has
+    // several starting at 1. unlike client_inbox nothing ever clears it, the
+    // oracle goes by it
+    pub history: BTreeMap<(String, u64), Op>,
+    // every node-to-node message and what the network did with it, for
+    // failure reports
+    pub trace: Vec<Traced>,
+    started: Instant,
+    // how far the clock moves per tick, the nodes' gossip interval
+    round: Duration,
+    in_flight: VecDeque<Msg>,
+    delayed: Vec<(Instant, Msg)>,
+    client_msg_ids: u64,
+    rng: StdRng,
+}
+
+impl Simulator {
+    pub fn new(n: usize) -> Self {
+        Self::with_nemesis(n, Nemesis::default(), 0)
+    }
+
+    // the nemesis draws from a seeded rng, so the faults it injects are reproducible
+    pub fn with_nemesis(n: usize, nemesis: Nemesis, seed: u64) -> Self {
+        let ids = (0..n).map(|i| format!("n{}", i)).collect::<Vec<_>>();
+        let nodes = ids
+            .iter()
+            .map(|id| {
+                let node = Node::new(Vec::new(), id.clone(), ids.clone());
+                // most of what runs here is broadcast, and a node that's cut
+                // off from the start has to be syncing before it sees any
+                (id.clone(), node.with_workload(Workload::Broadcast))
+            })
+            .collect();
+        Simulator {
+            nodes,
+            client_inbox: Vec::new(),
+            kv: HashMap::new(),
+            nemesis,
+            sent: 0,
+            history: BTreeMap::new(),
+            trace: Vec::new(),
+            started: Instant::now(),
+            round: GOSSIP_INTERVAL,
+            in_flight: VecDeque::new(),
+            delayed: Vec::new(),
+            client_msg_ids: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // ticks follow the interval, so a round is still one gossip round
+    pub fn with_gossip_interval(mut self, interval: Duration) -> Self {
+        self.round = interval;
+        self.nodes = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .map(|(id, node)| (id, node.with_gossip_interval(interval)))
+            .collect();
+        self
+    }
+
+    // every node answers `read` for this one from now on, with its timers
+    // running alongside broadcast's
+    pub fn serve(&mut self, workload: Workload) {
+        for node in self.nodes.values_mut() {
+            node.start_workload(workload);
+            node.workload = workload;
+        }
+    }
+
+    pub fn with_topology(mut self, shape: Shape) -> Self {
+        self.nodes = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .map(|(id, node)| (id, node.with_topology(shape.clone())))
+            .collect();
+        self
+    }
+
+    pub fn client_request(&mut self, client: &str, dest: &str, extra: Payload) {
+        self.client_msg_ids += 1;
+        self.request(Msg {
+            src: client.to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(self.client_msg_ids),
+                in_reply_to: None,
+                extra,
+            },
+        });
+    }
+
+    fn request(&mut self, msg: Msg) {
+        if let Some(msg_id) = msg.body.msg_id {
+            let op = Op {
+                client: msg.src.clone(),
+                node: msg.dest.clone(),
+                request: msg.body.extra.clone(),
+                reply: None,
+            };
+            self.history.insert((msg.src.clone(), msg_id), op);
+        }
+        self.in_flight.push_back(msg);
+    }
+
+    // what the nodes should have ended up with going by what their clients
+    // were told, see oracle.rs
+    pub fn check(&self) -> Result<(), String> {
+        oracle::check(self)
+    }
+
+    // plays the client requests from an --audit file at the nodes, keeping
+    // their msg_ids and roughly their timing: the clock moves in gossip
+    // rounds until each one is due. init and anything from other nodes or
+    // services is left out, the simulated nodes make their own
+    pub async fn play(&mut self, entries: &[Entry]) -> crate::Result<()> {
+        let start = Instant::now();
+        for entry in entries {
+            let msg = &entry.msg;
+            if entry.dir != Direction::In
+                || self.nodes.contains_key(&msg.src)
+                || msg.body.in_reply_to.is_some()
+                || matches!(msg.body.extra, Payload::Init { .. })
+            {
+                continue;
+            }
+            while start.elapsed() + self.round <= Duration::from_millis(entry.at_ms) {
+                self.deliver_all().await?;
+                self.tick().await?;
+            }
+            self.request(msg.clone());
+        }
+        self.deliver_all().await
+    }
+
+    // keeps delivering until nothing is in flight anymore, including whatever
+    // the deliveries themselves triggered
+    pub async fn deliver_all(&mut self) -> crate::Result<()> {
+        while !self.in_flight.is_empty() {
+            let idx = if self.rng.random_bool(self.nemesis.reorder) {
+                self.rng.random_range(0..self.in_flight.len())
+            } else {
+                0
+            };
+            let msg = self.in_flight.remove(idx).unwrap();
+            self.deliver(msg).await?;
+        }
+        Ok(())
+    }
+
+    // moves the clock by one gossip interval and fires whatever timers came due
+    // on every node, which is always a gossip round and sometimes a sync
+    pub async fn tick(&mut self) -> crate::Result<()> {
+        self.step(self.round).await
+    }
+
+    // same with any amount of time, where nothing might come due at all
+    pub async fn step(&mut self, by: Duration) -> crate::Result<()> {
+        time::advance(by).await;
+        let now = Instant::now();
+        let (due, later) = std::mem::take(&mut self.delayed)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(at, _)| *at <= now);
+        self.delayed = later;
+        self.in_flight.extend(due.into_iter().map(|(_, msg)| msg));
+
+        let ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.nodes.get_mut(&id).unwrap().fire_timers();
+            self.collect(&id).await?;
+        }
+        Ok(())
+    }
+
+    // ticks and delivers for the given number of gossip rounds
+    pub async fn settle(&mut self, rounds: usize) -> crate::Result<()> {
+        for _ in 0..rounds {
+            self.deliver_all().await?;
+            self.tick().await?;
+        }
+        self.deliver_all().await
+    }
+
+    pub fn messages(&self, id: &str) -> HashSet<usize> {
+        self.nodes[id]
+            .broadcast
+            .messages
+            .all()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    // sync reads can legitimately still be parked for a suspected peer when a
+    // test ends, unacked broadcasts can't
+    pub fn pending_broadcasts(&self, id: &str) -> usize {
+        self.nodes[id]
+            .pending
+            .values()
+            .filter(|p| p.payload.is("broadcast"))
+            .count()
+    }
+
+    pub fn assert_converged(&self, expected: &HashSet<usize>) {
+        for id in self.nodes.keys() {
+            assert_eq!(&self.messages(id), expected, "{} didn't converge", id);
+        }
+        self.assert_same_state();
+    }
+
+    // whatever workload the nodes serve, their state has to agree, see
+    // WorkloadState::converged
+    pub fn assert_same_state(&self) {
+        let mut states = self.nodes.iter().map(|(id, node)| {
+            let state = node
+                .served()
+                .into_iter()
+                .filter_map(|w| node.workload_state(w))
+                .map(|s| s.converged().unwrap())
+                .collect::<Vec<_>>();
+            (id, state)
+        });
+        let Some((first, expected)) = states.next() else {
+            return;
+        };
+        for (id, state) in states {
+            assert_eq!(state, expected, "{} and {} differ", id, first);
+        }
+    }
+
+    // unlike a real node's loop, a message a node fails to handle ends the
+    // run: everything on the wire here came from our own nodes and clients
+    async fn deliver(&mut self, msg: Msg) -> crate::Result<()> {
+        match self.nodes.get_mut(&msg.dest) {
+            Some(node) => {
+                node.handle(&serde_json::to_string(&msg)?)?;
+                let dest = msg.dest.clone();
+                self.collect(&dest).await?;
+            }
+            None if msg.dest == SEQ_KV => self.serve_kv(msg),
+            None => {
+                let op = msg
+                    .body
+                    .in_reply_to
+                    .and_then(|id| self.history.get_mut(&(msg.dest.clone(), id)));
+                if let Some(op) = op {
+                    op.reply = Some(msg.body.extra.clone());
+                }
+                self.client_inbox.push(msg);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_kv(&mut self, msg: Msg) {
+        let error = |code, text: &str| Payload::Error {
+            code,
+            text: text.to_string(),
+        };
+        let extra = match &msg.body.extra {
+            Payload::Read { key: Some(key), .. } => match self.kv.get(key) {
+                Some(value) => Payload::ReadOk {
+                    messages: None,
+                    next: None,
+                    delta: None,
+                    ranges: None,
+                    value: Some(*value),
+                },
+                None => error(KEY_DOES_NOT_EXIST, "key does not exist"),
+            },
+            Payload::Write { key, value } => {
+                self.kv.insert(key.clone(), *value);
+                Payload::WriteOk
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.kv.get(key) {
+                Some(value) if value != from => error(PRECONDITION_FAILED, "cas mismatch"),
+                None if !create_if_not_exists => error(KEY_DOES_NOT_EXIST, "key does not exist"),
+                _ => {
+                    self.kv.insert(key.clone(), *to);
+                    Payload::CasOk
+                }
+            },
+            _ => error(NOT_SUPPORTED, "not supported"),
+        };
+        self.client_msg_ids += 1;
+        self.in_flight.push_back(Msg {
+            src: SEQ_KV.to_string(),
+            dest: msg.src,
+            body: Body {
+                msg_id: Some(self.client_msg_ids),
+                in_reply_to: msg.body.msg_id,
+                extra,
+            },
+        });
+    }
+
+    async fn collect(&mut self, id: &str) -> crate::Result<()> {
+        let Some(node) = self.nodes.get_mut(id) else {
+            return Ok(());
+        };
+        node.flush().await?;
+        let out = String::from_utf8(std::mem::take(&mut node.output)).map_err(invalid)?;
+        for line in out.lines() {
+            let msg = serde_json::from_str::<Msg>(line)?;
+            self.transmit(msg);
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, msg: Msg) {
+        let between_nodes = self.nodes.contains_key(&msg.src) && self.nodes.contains_key(&msg.dest);
+        if !between_nodes {
+            self.in_flight.push_back(msg);
+            return;
+        }
+        self.sent += 1;
+        let fate = if self.nemesis.cut(&msg.src, &msg.dest) {
+            Fate::Cut
+        } else if self.rng.random_bool(self.nemesis.drop) {
+            Fate::Dropped
+        } else if self.rng.random_bool(self.nemesis.duplicate) {
+            Fate::Duplicated
+        } else {
+            Fate::Sent
+        };
+        self.trace.push(Traced {
+            at: Instant::now().duration_since(self.started),
+            msg: msg.clone(),
+            fate,
+        });
+        let copies = match fate {
+            Fate::Cut | Fate::Dropped => 0,
+            Fate::Duplicated => 2,
+            Fate::Sent => 1,
+        };
+        for _ in 0..copies {
+            let msg = msg.clone();
+            if !self.nemesis.max_delay.is_zero() && self.rng.random_bool(self.nemesis.delay) {
+                let delay = self
+                    .rng
+                    .random_range(Duration::ZERO..=self.nemesis.max_delay);
+                self.delayed.push((Instant::now() + delay, msg));
+            } else {
+                self.in_flight.push_back(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CounterMode, ReadMode, PENDING_TTL};
+
+    #[tokio::test(start_paused = true)]
+    async fn echo_replies_to_client() {
+        let mut sim = Simulator::new(1);
+        sim.client_request(
+            "c1",
+            "n0",
+            Payload::Echo {
+                echo: "hello".into(),
+            },
+        );
+        sim.deliver_all().await.unwrap();
+
+        assert_eq!(sim.client_inbox.len(), 1);
+        let reply = &sim.client_inbox[0];
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.in_reply_to, Some(1));
+        assert!(matches!(&reply.body.extra, Payload::EchoOk { echo } if echo == "hello"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counter_catches_up_after_a_lost_cas() {
+        let mut sim = Simulator::new(3);
+        sim.serve(Workload::Counter);
+        // as if an earlier cas of n0's landed but the answer never made it back
+        sim.kv.insert("counter-n0".into(), 2);
+        sim.client_request("c1", "n0", Payload::Add { delta: 5 });
+        sim.client_request("c1", "n1", Payload::Add { delta: 1 });
+        sim.settle(3).await.unwrap();
+        assert_eq!(sim.kv["counter-n0"], 5);
+        assert_eq!(sim.kv["counter-n1"], 1);
+
+        sim.client_inbox.clear();
+        let read = Payload::Read {
+            after: None,
+            limit: None,
+            encoding: None,
+            codec: None,
+            key: None,
+        };
+        sim.client_request("c1", "n2", read);
+        sim.deliver_all().await.unwrap();
+        assert_eq!(sim.client_inbox.len(), 1);
+        let reply = &sim.client_inbox[0].body.extra;
+        assert!(
+            matches!(reply, Payload::ReadOk { value: Some(6), .. }),
+            "{:?}",
+            reply
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_converges() {
+        let mut sim = Simulator::new(5);
+        for message in 0..50 {
+            let dest = format!("n{}", message % 5);
+            sim.client_request("c1", &dest, Payload::Broadcast { message });
+        }
+        sim.deliver_all().await.unwrap();
+
+        let expected = (0..50).collect::<HashSet<_>>();
+        for id in sim.nodes.keys() {
+            assert_eq!(sim.messages(id), expected, "{} didn't converge", id);
+        }
+        let acks = sim
+            .client_inbox
+            .iter()
+            .filter(|m| matches!(m.body.extra, Payload::BroadcastOk))
+            .count();
+        assert_eq!(acks, 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acked_broadcasts_are_not_retransmitted() {
+        let mut sim = Simulator::new(3);
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.deliver_all().await.unwrap();
+
+        for node in sim.nodes.values() {
+            assert_eq!(node.pending.len(), 0);
+        }
+        sim.tick().await.unwrap();
+        // the first tick is also when nodes say hello (and send their
+        // status), nothing else goes out
+        assert!(sim
+            .in_flight
+            .iter()
+            .all(|m| matches!(m.body.extra, Payload::Hello { .. } | Payload::Status { .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_to_dead_peer_expires() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(2, nemesis, 0);
+        // only the ttl, no retiring after MAX_ATTEMPTS
+        sim.nodes.get_mut("n0").unwrap().max_attempts = u32::MAX;
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.settle(10).await.unwrap();
+        assert_eq!(sim.pending_broadcasts("n0"), 1);
+
+        let rounds = (PENDING_TTL.as_millis() / GOSSIP_INTERVAL.as_millis()) as usize;
+        sim.settle(rounds).await.unwrap();
+        assert_eq!(sim.pending_broadcasts("n0"), 0);
+        assert!(sim.nodes["n0"].metrics.pending_expired >= 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_reads_get_a_smaller_budget() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(2, nemesis, 0);
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.deliver_all().await.unwrap();
+        while sim.pending_broadcasts("n0") > 0 {
+            sim.settle(1).await.unwrap();
+            let reads = sim.nodes["n0"]
+                .pending
+                .values()
+                .filter(|p| p.payload.is("read"))
+                .collect::<Vec<_>>();
+            assert!(reads.iter().all(|p| p.attempts <= 3));
+        }
+        let n0 = &sim.nodes["n0"];
+        // the broadcast and at least one sync read before it
+        assert!(n0.metrics.rpcs_retired >= 2);
+        assert!(n0.broadcast.awaiting.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rpcs_retire_after_max_attempts() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(2, nemesis, 0);
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.deliver_all().await.unwrap();
+        assert_eq!(sim.pending_broadcasts("n0"), 1);
+        let mut rounds = 0;
+        while sim.pending_broadcasts("n0") > 0 {
+            sim.settle(1).await.unwrap();
+            rounds += 1;
+        }
+        let ttl_rounds = (PENDING_TTL.as_millis() / GOSSIP_INTERVAL.as_millis()) as usize;
+        assert!(rounds < ttl_rounds, "took {} rounds", rounds);
+        assert!(sim.nodes["n0"].metrics.rpcs_retired >= 1);
+        assert_eq!(sim.nodes["n0"].metrics.pending_expired, 0);
+
+        // and anti-entropy still gets it there once the partition heals
+        sim.nemesis.partitions.clear();
+        sim.settle(100).await.unwrap();
+        sim.assert_converged(&HashSet::from([1]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_over_cap_evicts_oldest() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(2, nemesis, 0);
+        sim.nodes.get_mut("n0").unwrap().pending_cap = 5;
+        for message in 0..8 {
+            sim.client_request("c1", "n0", Payload::Broadcast { message });
+            sim.settle(1).await.unwrap();
+        }
+        sim.settle(1).await.unwrap();
+
+        let n0 = &sim.nodes["n0"];
+        assert_eq!(n0.pending.len(), 5);
+        assert_eq!(n0.metrics.pending_evicted, 3);
+        for oldest in 0..3 {
+            let fields = format!(r#""message":{}}}"#, oldest);
+            assert!(n0
+                .pending
+                .values()
+                .all(|p| !p.payload.fields.ends_with(&fields)));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_recovers_values_whose_pending_expired() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(2, nemesis, 0);
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        let rounds = (PENDING_TTL.as_millis() / GOSSIP_INTERVAL.as_millis()) as usize;
+        sim.settle(rounds + 1).await.unwrap();
+        assert_eq!(sim.pending_broadcasts("n0"), 0);
+        assert!(sim.messages("n1").is_empty());
+
+        sim.nemesis.partitions.clear();
+        sim.settle(100).await.unwrap();
+        sim.assert_converged(&HashSet::from([1]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hints_are_handed_off_when_peer_shows_up() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(2, nemesis, 0);
+        for message in 0..3 {
+            sim.client_request("c1", "n0", Payload::Broadcast { message });
+        }
+        sim.settle(20).await.unwrap();
+        assert!(sim.nodes["n0"].suspected("n1"));
+        assert_eq!(sim.pending_broadcasts("n0"), 3);
+
+        // n1 reaches out before n0's backoff gets around to probing it
+        sim.nemesis.partitions.clear();
+        sim.in_flight.push_back(Msg {
+            src: "n1".into(),
+            dest: "n0".into(),
+            body: Body {
+                msg_id: Some(1000),
+                in_reply_to: None,
+                extra: Payload::Broadcast { message: 100 },
+            },
+        });
+        sim.deliver_all().await.unwrap();
+
+        assert_eq!(sim.pending_broadcasts("n0"), 0);
+        // parked sync reads go along with the broadcasts
+        assert!(sim.nodes["n0"].metrics.hints_handed_off >= 3);
+        assert!(sim.messages("n1").is_superset(&HashSet::from([0, 1, 2])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_works_over_binary_codecs() {
+        for name in ["msgpack", "cbor"] {
+            let mut sim = Simulator::new(2);
+            // only n0 has it and nothing is pending, so sync is the only way over
+            sim.nodes
+                .get_mut("n0")
+                .unwrap()
+                .broadcast
+                .messages
+                .insert(5)
+                .unwrap();
+            sim.nodes.get_mut("n1").unwrap().codec = crate::codec::by_name(name);
+            sim.settle(25).await.unwrap();
+            assert_eq!(sim.messages("n1"), HashSet::from([5]), "{}", name);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_converges_over_every_topology() {
+        use crate::topology::Shape;
+        for shape in [Shape::Ring, Shape::Grid, Shape::Tree] {
+            let mut sim = Simulator::new(10);
+            sim.nodes = std::mem::take(&mut sim.nodes)
+                .into_iter()
+                .map(|(id, node)| (id, node.with_topology(shape.clone())))
+                .collect();
+            for message in 0..20 {
+                let dest = format!("n{}", message % 10);
+                sim.client_request("c1", &dest, Payload::Broadcast { message });
+            }
+            // no ticks, so it's the fan-out alone that has to get everywhere
+            sim.deliver_all().await.unwrap();
+            sim.assert_converged(&(0..20).collect());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tree_routes_around_a_suspected_neighbor() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(10, nemesis, 0);
+        sim.nodes = std::mem::take(&mut sim.nodes)
+            .into_iter()
+            .map(|(id, node)| (id, node.with_topology(crate::topology::Shape::Tree)))
+            .collect();
+        // n1 is n0's child and n5..n8 are n1's, the value n0 can't get across
+        // makes it suspect n1
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 0 });
+        sim.settle(6).await.unwrap();
+        assert!(sim.nodes["n0"].suspected("n1"));
+
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.deliver_all().await.unwrap();
+        for id in ["n5", "n6", "n7", "n8"] {
+            assert!(sim.messages(id).contains(&1), "{} was cut off", id);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn values_for_a_lost_neighbor_get_relayed() {
+        let nemesis = Nemesis {
+            partitions: vec![("n0".into(), "n1".into())],
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(10, nemesis, 0);
+        sim.nodes = std::mem::take(&mut sim.nodes)
+            .into_iter()
+            .map(|(id, node)| (id, node.with_topology(crate::topology::Shape::Tree)))
+            .collect();
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 0 });
+        let mut rounds = 0;
+        while !sim.nodes["n0"].broadcast.detours.contains_key("n1") {
+            assert!(rounds < 200, "n0 never gave up on n1");
+            sim.settle(1).await.unwrap();
+            rounds += 1;
+        }
+        let via = sim.nodes["n0"].broadcast.detours["n1"].clone();
+        assert!(via != "n0" && via != "n1");
+
+        // new values for n1 go through the detour too
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.deliver_all().await.unwrap();
+        assert!(sim.messages("n1").contains(&1));
+        assert!(sim.nodes["n0"].metrics.rpcs_retired >= 1);
+
+        sim.nemesis.partitions.clear();
+        sim.settle(20).await.unwrap();
+        assert!(sim.nodes["n0"].broadcast.detours.is_empty());
+        sim.assert_converged(&HashSet::from([0, 1]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn propagation_is_timed_per_value() {
+        let mut sim = Simulator::new(3);
+        for message in 0..10 {
+            sim.client_request("c1", "n0", Payload::Broadcast { message });
+        }
+        sim.deliver_all().await.unwrap();
+        // n0 got them from the client, the others only from n0 and don't
+        // forward back to it
+        assert_eq!(sim.nodes["n0"].broadcast.propagation.len(), 10);
+        assert_eq!(sim.nodes["n1"].broadcast.propagation.len(), 10);
+        assert_eq!(
+            sim.nodes["n0"].broadcast.propagation.percentile(99.0),
+            Some(0)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn big_sync_pages_are_compressed() {
+        let mut sim = Simulator::new(2);
+        // spread out so even the delta encoded page is over COMPRESS_ABOVE
+        let expected = (0..500).map(|i| i * 100_003).collect::<HashSet<_>>();
+        for &v in &expected {
+            sim.nodes
+                .get_mut("n0")
+                .unwrap()
+                .broadcast
+                .messages
+                .insert(v)
+                .unwrap();
+        }
+        sim.settle(25).await.unwrap();
+        assert_eq!(sim.messages("n1"), expected);
+        assert_eq!(
+            sim.nodes["n1"].peer_compression.get("n0"),
+            Some(&crate::compression::Compression::Zstd)
+        );
+    }
+
+    fn lossy() -> Nemesis {
+        Nemesis {
+            drop: 0.3,
+            duplicate: 0.1,
+            delay: 0.2,
+            max_delay: Duration::from_secs(2),
+            reorder: 0.5,
+            partitions: Vec::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_include_what_the_same_node_already_acked() {
+        for seed in 0..5 {
+            let mut sim = Simulator::with_nemesis(3, lossy(), seed);
+            // values on disk have to count too
+            let dir =
+                std::env::temp_dir().join(format!("fly-sim-ryw-{}-{}", seed, std::process::id()));
+            sim.nodes.get_mut("n0").unwrap().broadcast.messages =
+                crate::store::MessageStore::spilling(3, dir).unwrap();
+            let read = || Payload::Read {
+                after: None,
+                limit: None,
+                encoding: None,
+                codec: None,
+                key: None,
+            };
+            // every client sticks to one node. like maelstrom's, a client
+            // reads once it has its ack, so replies racing each other back
+            // don't count. the read goes in with the client's next broadcast
+            // and everyone else's, and those can be handled either side of it
+            let mut sent = HashMap::new();
+            let mut acked = HashMap::<String, HashSet<usize>>::new();
+            let mut expected = HashMap::new();
+            let mut seen = 0;
+            for round in 0..20 {
+                for i in 0..3 {
+                    let (client, dest) = (format!("c{}", i), format!("n{}", i));
+                    if round > 0 {
+                        expected.insert(sim.client_msg_ids + 1, acked[&client].clone());
+                        sim.client_request(&client, &dest, read());
+                    }
+                    let message = round * 3 + i;
+                    sent.insert(sim.client_msg_ids + 1, message);
+                    sim.client_request(&client, &dest, Payload::Broadcast { message });
+                }
+                sim.deliver_all().await.unwrap();
+                if round % 4 == 0 {
+                    sim.tick().await.unwrap();
+                }
+                for reply in &sim.client_inbox[seen..] {
+                    let in_reply_to = reply.body.in_reply_to.unwrap();
+                    match &reply.body.extra {
+                        Payload::BroadcastOk => {
+                            let acked = acked.entry(reply.dest.clone()).or_default();
+                            acked.insert(sent[&in_reply_to]);
+                        }
+                        Payload::ReadOk {
+                            messages: Some(messages),
+                            ..
+                        } => {
+                            let read = messages.iter().copied().collect::<HashSet<_>>();
+                            let missing = &expected[&in_reply_to] - &read;
+                            assert!(missing.is_empty(), "seed {}: missing {:?}", seed, missing);
+                        }
+                        other => panic!("{:?}", other),
+                    }
+                }
+                seen = sim.client_inbox.len();
+            }
+            assert_eq!(acked.values().map(HashSet::len).sum::<usize>(), 60);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oracle_reports_what_a_cut_off_node_is_missing() {
+        let nemesis = Nemesis {
+            partitions: (0..4).map(|i| ("n4".into(), format!("n{}", i))).collect(),
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(5, nemesis, 0);
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 7 });
+        sim.client_request("c1", "n1", Payload::Broadcast { message: 8 });
+        sim.settle(3).await.unwrap();
+
+        let report = sim.check().unwrap_err();
+        assert!(
+            report.starts_with("n4 is missing 2 of 2 acked values: 7, 8\n"),
+            "{}",
+            report
+        );
+        // every attempt at getting the first missing one to n4
+        let trace = report.lines().skip(1).collect::<Vec<_>>();
+        assert!(!trace.is_empty());
+        assert!(
+            trace
+                .iter()
+                .all(|l| l.contains("-> n4 cut") && l.ends_with(r#""message":7}"#)),
+            "{}",
+            report
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oracle_sums_saturate_and_skip_refused_adds() {
+        let mut sim = Simulator::new(3);
+        sim.serve(Workload::Counter);
+        sim.client_request("c1", "n0", Payload::Add { delta: u64::MAX });
+        sim.settle(3).await.unwrap();
+        // n0 refuses this one, it would overflow its own total
+        sim.client_request("c1", "n0", Payload::Add { delta: 1 });
+        sim.client_request("c1", "n1", Payload::Add { delta: 1 });
+        sim.settle(3).await.unwrap();
+        assert!(sim.client_inbox.iter().any(|m| matches!(
+            m.body.extra,
+            Payload::Error {
+                code: crate::MALFORMED_REQUEST,
+                ..
+            }
+        )));
+        sim.check().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_converges_over_lossy_network() {
+        for seed in 0..5 {
+            let mut sim = Simulator::with_nemesis(5, lossy(), seed);
+            for message in 0..30 {
+                let dest = format!("n{}", message % 5);
+                sim.client_request("c1", &dest, Payload::Broadcast { message });
+                sim.deliver_all().await.unwrap();
+            }
+            sim.settle(10).await.unwrap();
+
+            // retries have to get everything across once the network behaves again
+            sim.nemesis = Nemesis::default();
+            sim.settle(100).await.unwrap();
+            sim.assert_converged(&(0..30).collect());
+            sim.check().unwrap();
+            for id in sim.nodes.keys() {
+                assert_eq!(
+                    sim.pending_broadcasts(id),
+                    0,
+                    "seed {} left pending rpcs",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_client_requests_from_an_audit() {
+        let path = std::env::temp_dir().join(format!("play-{}.jsonl", std::process::id()));
+        let mut recorded = Node::new(Vec::new(), "n0".to_string(), vec!["n0".to_string()])
+            .with_audit(crate::audit::Audit::create(&path).unwrap());
+        for message in 0..5 {
+            recorded
+                .handle(&format!(
+                    r#"{{"src":"c1","dest":"n0","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                    message + 1,
+                    message
+                ))
+                .unwrap();
+            time::advance(Duration::from_millis(300)).await;
+        }
+        let entries = crate::audit::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut sim = Simulator::new(3);
+        sim.play(&entries).await.unwrap();
+        sim.settle(5).await.unwrap();
+        sim.assert_converged(&(0..5).collect());
+        let acked = sim
+            .client_inbox
+            .iter()
+            .filter_map(|m| m.body.in_reply_to)
+            .collect::<Vec<_>>();
+        assert_eq!(acked, [1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn played_clients_can_share_msg_ids() {
+        let path = std::env::temp_dir().join(format!("play-two-{}.jsonl", std::process::id()));
+        let mut recorded = Node::new(Vec::new(), "n0".to_string(), vec!["n0".to_string()])
+            .with_audit(crate::audit::Audit::create(&path).unwrap());
+        for (client, message) in [("c1", 0), ("c2", 1), ("c1", 2), ("c2", 3)] {
+            recorded
+                .handle(&format!(
+                    r#"{{"src":"{}","dest":"n0","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                    client,
+                    message / 2 + 1,
+                    message
+                ))
+                .unwrap();
+        }
+        let entries = crate::audit::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut sim = Simulator::new(3);
+        sim.play(&entries).await.unwrap();
+        sim.settle(5).await.unwrap();
+        assert_eq!(sim.history.len(), 4);
+        assert!(sim.history.values().all(|op| op.reply.is_some()));
+        let requests = sim
+            .history
+            .iter()
+            .map(|((client, msg_id), op)| (client.as_str(), *msg_id, op.request.clone()))
+            .filter_map(|(client, msg_id, request)| match request {
+                Payload::Broadcast { message } => Some((client, msg_id, message)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            requests,
+            [("c1", 1, 0), ("c1", 2, 2), ("c2", 1, 1), ("c2", 2, 3)]
+        );
+        sim.check().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crdt_counter_converges_over_lossy_network() {
+        for seed in 0..5 {
+            let mut sim = Simulator::with_nemesis(5, lossy(), seed);
+            sim.serve(Workload::Counter);
+            for node in sim.nodes.values_mut() {
+                node.counter.mode = CounterMode::Crdt;
+            }
+            for i in 0..30 {
+                let dest = format!("n{}", i % 5);
+                sim.client_request("c1", &dest, Payload::Add { delta: i + 1 });
+                sim.deliver_all().await.unwrap();
+            }
+            // no retries and no healing, every round's gossip carries everything
+            sim.settle(30).await.unwrap();
+            assert!(sim.kv.is_empty(), "seed {} went to seq-kv", seed);
+
+            sim.client_inbox.clear();
+            for i in 0..5 {
+                let read = Payload::Read {
+                    after: None,
+                    limit: None,
+                    encoding: None,
+                    codec: None,
+                    key: None,
+                };
+                sim.client_request("c1", &format!("n{}", i), read);
+            }
+            sim.deliver_all().await.unwrap();
+            let values = sim
+                .client_inbox
+                .iter()
+                .map(|m| match m.body.extra {
+                    Payload::ReadOk { value, .. } => value,
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(values, vec![Some(465); 5], "seed {}", seed);
+            sim.assert_same_state();
+            sim.check().unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quorum_reads_see_adds_that_werent_gossiped_yet() {
+        let read = || Payload::Read {
+            after: None,
+            limit: None,
+            encoding: None,
+            codec: None,
+            key: None,
+        };
+        let mut values = Vec::new();
+        for (mode, read_mode) in [
+            (CounterMode::Crdt, ReadMode::Local),
+            (CounterMode::Crdt, ReadMode::Quorum),
+            (CounterMode::SeqKv, ReadMode::Quorum),
+        ] {
+            let mut sim = Simulator::new(3);
+            sim.serve(Workload::Counter);
+            for node in sim.nodes.values_mut() {
+                node.counter.mode = mode;
+                node.counter.read_mode = Some(read_mode);
+            }
+            sim.client_request("c1", "n0", Payload::Add { delta: 5 });
+            sim.client_request("c1", "n2", Payload::Add { delta: 2 });
+            // no ticks, nothing has been gossiped or written to seq-kv
+            sim.deliver_all().await.unwrap();
+            sim.client_inbox.clear();
+            sim.client_request("c1", "n1", read());
+            sim.deliver_all().await.unwrap();
+            values.push(match sim.client_inbox[..] {
+                [Msg {
+                    body:
+                        Body {
+                            extra: Payload::ReadOk { value, .. },
+                            ..
+                        },
+                    ..
+                }] => value,
+                _ => None,
+            });
+        }
+        // a crdt quorum is n1 and one other, n0 answered first
+        assert_eq!(values, [Some(0), Some(5), Some(7)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_converges_after_partition_heals() {
+        let nemesis = Nemesis {
+            partitions: ["n1", "n2", "n3"]
+                .iter()
+                .map(|n| ("n0".to_string(), n.to_string()))
+                .collect(),
+            ..Nemesis::default()
+        };
+        let mut sim = Simulator::with_nemesis(4, nemesis, 0);
+        sim.client_request("c1", "n0", Payload::Broadcast { message: 1 });
+        sim.client_request("c2", "n1", Payload::Broadcast { message: 2 });
+        sim.settle(20).await.unwrap();
+        assert_eq!(sim.messages("n0"), HashSet::from([1]));
+        assert_eq!(sim.messages("n3"), HashSet::from([2]));
+
+        sim.nemesis.partitions.clear();
+        sim.settle(100).await.unwrap();
+        sim.assert_converged(&HashSet::from([1, 2]));
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[derive(Debug, Clone)]
+    enum Step {
+        Broadcast { node: usize },
+        Partition(Vec<(usize, usize)>),
+        Heal,
+        Rounds(usize),
+    }
+
+    fn step(n: usize) -> impl Strategy<Value = Step> {
+        prop_oneof![
+            4 => (0..n).prop_map(|node| Step::Broadcast { node }),
+            1 => prop::collection::vec((0..n, 0..n), 1..n * 2).prop_map(Step::Partition),
+            1 => Just(Step::Heal),
+            2 => (1usize..5).prop_map(Step::Rounds),
+        ]
+    }
+
+    fn scenario() -> impl Strategy<Value = (usize, Vec<Step>, f64, u64)> {
+        (2usize..6).prop_flat_map(|n| {
+            (
+                Just(n),
+                prop::collection::vec(step(n), 1..40),
+                0.0..0.4,
+                any::<u64>(),
+            )
+        })
+    }
+
+    fn run(n: usize, steps: Vec<Step>, drop: f64, seed: u64) -> Result<(), TestCaseError> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let nemesis = Nemesis {
+                drop,
+                duplicate: drop / 2.0,
+                reorder: 0.5,
+                ..Nemesis::default()
+            };
+            let mut sim = Simulator::with_nemesis(n, nemesis, seed);
+            let mut sent = HashSet::new();
+            for step in steps {
+                match step {
+                    Step::Broadcast { node } => {
+                        let message = sent.len();
+                        sent.insert(message);
+                        sim.client_request(
+                            "c1",
+                            &format!("n{}", node),
+                            Payload::Broadcast { message },
+                        );
+                        sim.deliver_all().await.unwrap();
+                    }
+                    Step::Partition(links) => {
+                        sim.nemesis.partitions = links
+                            .into_iter()
+                            .filter(|(a, b)| a != b)
+                            .map(|(a, b)| (format!("n{}", a), format!("n{}", b)))
+                            .collect();
+                    }
+                    Step::Heal => sim.nemesis.partitions.clear(),
+                    Step::Rounds(rounds) => sim.settle(rounds).await.unwrap(),
+                }
+            }
+
+            sim.nemesis = Nemesis::default();
+            sim.settle(150).await.unwrap();
+
+            // every broadcast was acked to the client, so losing any of them
+            // anywhere would be a real bug
+            let acked = sim
+                .client_inbox
+                .iter()
+                .filter(|m| matches!(m.body.extra, Payload::BroadcastOk))
+                .count();
+            prop_assert_eq!(acked, sent.len());
+            for id in sim.nodes.keys() {
+                prop_assert_eq!(&sim.messages(id), &sent, "{} didn't converge", id);
+                prop_assert_eq!(
+                    sim.pending_broadcasts(id),
+                    0,
+                    "{} still has pending rpcs",
+                    id
+                );
+            }
+            Ok(())
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn broadcast_converges_under_random_partitions((n, steps, drop, seed) in scenario()) {
+            run(n, steps, drop, seed)?;
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::config::Config;
+use crate::workloads::{broadcast, checksum, counter};
+use crate::{hello, Node, STATS_INTERVAL};
+use serde_json::{json, Value};
+use tokio::io::AsyncWrite;
+
+// logged once on init. everything that changes how a run behaves goes in
+// here, defaults included, so two results dirs from a parameter sweep can be
+// told apart from their node logs alone
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub(crate) fn banner(&self, config: &Config) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git": env!("FLY_GIT_HASH"),
+            "profile": env!("FLY_PROFILE"),
+            "protocol": hello::PROTOCOL_VERSION,
+            "features": hello::FEATURES,
+            "id": self.id,
+            "nodes": self.nodes.len(),
+            // empty means we work it out from the traffic
+            "workloads": config.workloads.iter().map(|w| w.name()).collect::<Vec<_>>(),
+            "default_workload": self.workload.name(),
+            // new values go out right away, only retransmits wait for a
+            // round, so there's no batch window beyond this
+            "gossip_interval_ms": self.gossip_interval.as_millis(),
+            "sync_interval_ms": broadcast::SYNC_INTERVAL.as_millis(),
+            "counter_interval_ms": counter::COUNTER_INTERVAL.as_millis(),
+            "checksum_interval_ms": checksum::CHECKSUM_INTERVAL.as_millis(),
+            "stats_interval_ms": STATS_INTERVAL.as_millis(),
+            "pending_ttl_ms": self.pending_ttl.as_millis(),
+            "client_timeout_ms": self.client_timeout.as_millis(),
+            "slow_handler_ms": self.slow_handler.as_millis(),
+            "paranoid": self.paranoid,
+            "pending_cap": self.pending_cap,
+            "max_attempts": self.max_attempts,
+            "gossip_rate": config.gossip_rate,
+            // everyone unless a shape is given
+            "topology": config.topology.as_ref().map_or("all", |s| s.name()),
+            "codec": config.codec.map(|c| c.name()),
+            "counter": self.counter.mode.name(),
+            "read_mode": self.counter.read_mode().name(),
+            "kv_cache_ttl_ms": self.counter.cache.ttl().as_millis(),
+            "ids": config.ids.name(),
+            "max_messages_in_memory": config.max_messages_in_memory,
+            "inject_latency_ms": config.inject_latency.map(|l| {
+                json!({ "base": l.base.as_millis(), "jitter": l.jitter.as_millis() })
+            }),
+            "drop_rate": config.drop_rate,
+            "drop_seed": config.drop_seed,
+            "drop_replies": config.drop_replies,
+            "log_max_bytes": config.log_max_bytes,
+            "log_sample": config.log_sample,
+        })
+    }
+}
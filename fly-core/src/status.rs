@@ -0,0 +1,131 @@
+use crate::{ids, Msg, Node, Payload, Prepared, Result};
+use tokio::io::AsyncWrite;
+
+// what our nodes tell each other about the process behind the node id, sent
+// along with the hello: when it started (wall clock millis), the epoch its
+// ids come from (see ids.rs) and which workloads it's serving. a node that
+// crashed and came back has the same id but a new start and epoch, and
+// everything it had in memory is gone. one that was only cut off by a
+// partition tells us the same thing it did before. the failure detector
+// can't tell those apart, both just went quiet for a while
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub started: u64,
+    pub epoch: u64,
+    pub workloads: Vec<String>,
+}
+
+impl Status {
+    pub fn startup() -> Self {
+        let started = ids::startup_epoch();
+        Status {
+            started,
+            epoch: started,
+            workloads: Vec::new(),
+        }
+    }
+
+    fn same_process(&self, other: &Status) -> bool {
+        self.started == other.started && self.epoch == other.epoch
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // fire and forget like hello, an older node ignores it
+    pub(crate) fn say_status(&mut self, peers: &[String]) -> Result<()> {
+        let status = Prepared::new(&self.own_status(false))?;
+        for peer in peers {
+            let msg_id = self.next_msg_id();
+            self.enqueue(peer, msg_id, None, &status);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn status(
+        &mut self,
+        msg: &Msg,
+        started: &u64,
+        epoch: &u64,
+        workloads: &[String],
+    ) -> Result<Option<Payload>> {
+        self.learn_status(&msg.src, *started, *epoch, workloads)?;
+        Ok(Some(self.own_status(true)))
+    }
+
+    pub(crate) fn status_ok(
+        &mut self,
+        msg: &Msg,
+        started: &u64,
+        epoch: &u64,
+        workloads: &[String],
+    ) -> Result<Option<Payload>> {
+        self.learn_status(&msg.src, *started, *epoch, workloads)?;
+        Ok(None)
+    }
+
+    fn own_status(&self, reply: bool) -> Payload {
+        let started = self.status.started;
+        let epoch = self.status.epoch;
+        let workloads = self.serving.iter().map(|w| w.name().to_string()).collect();
+        if reply {
+            Payload::StatusOk {
+                started,
+                epoch,
+                workloads,
+            }
+        } else {
+            Payload::Status {
+                started,
+                epoch,
+                workloads,
+            }
+        }
+    }
+
+    fn learn_status(
+        &mut self,
+        peer: &str,
+        started: u64,
+        epoch: u64,
+        workloads: &[String],
+    ) -> Result<()> {
+        if !self.nodes.iter().any(|n| n == peer) {
+            return Ok(());
+        }
+        let status = Status {
+            started,
+            epoch,
+            workloads: workloads.to_vec(),
+        };
+        let previous = self.peer_status.insert(peer.to_string(), status.clone());
+        if previous.is_some_and(|p| !p.same_process(&status)) {
+            self.peer_restarted(peer, epoch)?;
+        }
+        Ok(())
+    }
+
+    // whatever the detector learned about the old process says nothing about
+    // the new one, and it's not worth backing off from: it's up, it just told
+    // us. if it can take it, it gets our whole state pushed right away, which
+    // makes whatever single values we were still retrying to it redundant.
+    // the rest of what we had pending for it goes out right away too, and
+    // the counter polls its old process was never going to answer
+    fn peer_restarted(&mut self, peer: &str, epoch: u64) -> Result<()> {
+        eprintln!("{} restarted (epoch {}), it lost its state", peer, epoch);
+        self.metrics.peer_restarts += 1;
+        self.detector.remove(peer);
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.acked();
+        }
+        self.divergence.forget(peer);
+        if self.peer_supports(peer, "resync") {
+            self.push_state(peer)?;
+            self.pending
+                .retain(|p| p.dest != peer || !p.payload.is("broadcast"));
+            self.broadcast.forget_peer(peer);
+        }
+        self.repoll(peer)?;
+        self.hand_off(peer);
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+use crate::ranges::Ranges;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// once we've got this many run files they get merged back into one, so a
+// lookup never has to binary search more than a handful of files
+const MAX_RUNS: usize = 8;
+
+// the set of broadcast values we've seen. by default it's all in memory as
+// ranges, see ranges.rs, but it can be capped: once more than `limit` values
+// are in memory they get written out as a run file (raw little endian u64s),
+// lookups binary search the runs and reads merge everything back together
+pub struct MessageStore {
+    memory: Ranges,
+    spill: Option<Spill>,
+}
+
+struct Spill {
+    limit: usize,
+    dir: PathBuf,
+    runs: Vec<Run>,
+    next_run: u64,
+}
+
+struct Run {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    min: u64,
+    max: u64,
+}
+
+impl MessageStore {
+    pub fn new() -> Self {
+        MessageStore {
+            memory: Ranges::new(),
+            spill: None,
+        }
+    }
+
+    pub fn spilling(limit: usize, dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(MessageStore {
+            memory: Ranges::new(),
+            spill: Some(Spill {
+                limit: limit.max(1),
+                dir,
+                runs: Vec::new(),
+                next_run: 0,
+            }),
+        })
+    }
+
+    pub fn contains(&self, value: usize) -> io::Result<bool> {
+        if self.memory.contains(value) {
+            return Ok(true);
+        }
+        match &self.spill {
+            Some(spill) => spill.contains(value as u64),
+            None => Ok(false),
+        }
+    }
+
+    // true if the value wasn't there yet
+    pub fn insert(&mut self, value: usize) -> io::Result<bool> {
+        if self.contains(value)? {
+            return Ok(false);
+        }
+        self.memory.insert(value);
+        if let Some(spill) = &mut self.spill {
+            if self.memory.len() > spill.limit {
+                spill.write_run(&self.memory)?;
+                self.memory.clear();
+            }
+        }
+        Ok(true)
+    }
+
+    // start..=end in one go. only a store that spills has to look at every
+    // value, the runs on disk are values and not ranges, so the caller has to
+    // keep the range within reason (ranges::MAX_DECODED)
+    pub fn insert_range(&mut self, start: usize, end: usize) -> io::Result<()> {
+        if self.spill.is_some() {
+            for value in start..=end {
+                self.insert(value)?;
+            }
+        } else {
+            self.memory.insert_range(start, end);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        let spilled = self
+            .spill
+            .as_ref()
+            .map_or(0, |s| s.runs.iter().map(|r| r.len as usize).sum::<usize>());
+        self.memory.len() + spilled
+    }
+
+    // everything, spilled values sorted first and then whatever is in memory
+    pub fn all(&self) -> io::Result<Vec<usize>> {
+        let mut values = Vec::with_capacity(self.len());
+        if let Some(spill) = &self.spill {
+            for run in &spill.runs {
+                values.extend(run.read_all()?.into_iter().map(|v| v as usize));
+            }
+        }
+        values.extend(self.memory.values());
+        Ok(values)
+    }
+
+    // everything as ranges, spilled values merged in
+    pub fn ranges(&self) -> io::Result<Ranges> {
+        let mut ranges = self.memory.clone();
+        if let Some(spill) = &self.spill {
+            for run in &spill.runs {
+                ranges.extend(run.read_all()?.into_iter().map(|v| v as usize));
+            }
+        }
+        Ok(ranges)
+    }
+}
+
+impl Default for MessageStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Spill {
+    fn contains(&self, value: u64) -> io::Result<bool> {
+        for run in &self.runs {
+            if run.contains(value)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn write_run(&mut self, values: &Ranges) -> io::Result<()> {
+        let path = self.next_path();
+        self.runs
+            .push(Run::write(&path, values.values().map(|v| v as u64))?);
+        if self.runs.len() > MAX_RUNS {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // streaming k-way merge so compacting doesn't pull everything back into
+    // memory, which would defeat the point
+    fn compact(&mut self) -> io::Result<()> {
+        let mut readers = self
+            .runs
+            .iter()
+            .map(|r| File::open(&r.path).map(BufReader::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::new();
+        for (idx, reader) in readers.iter_mut().enumerate() {
+            if let Some(v) = read_u64(reader)? {
+                heap.push(Reverse((v, idx)));
+            }
+        }
+        let path = self.next_path();
+        let mut failed = None;
+        let run = Run::write(
+            &path,
+            std::iter::from_fn(|| {
+                let Reverse((v, idx)) = heap.pop()?;
+                match read_u64(&mut readers[idx]) {
+                    Ok(Some(next)) => heap.push(Reverse((next, idx))),
+                    Ok(None) => {}
+                    Err(e) => {
+                        failed = Some(e);
+                        return None;
+                    }
+                }
+                Some(v)
+            }),
+        )?;
+        if let Some(e) = failed {
+            // keep the old runs, they're still complete
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        for old in std::mem::replace(&mut self.runs, vec![run]) {
+            fs::remove_file(&old.path)?;
+        }
+        Ok(())
+    }
+
+    fn next_path(&mut self) -> PathBuf {
+        self.next_run += 1;
+        self.dir.join(format!("run-{:06}.bin", self.next_run))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(&run.path);
+        }
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+impl Run {
+    // values have to come in sorted
+    fn write(path: &Path, sorted: impl IntoIterator<Item = u64>) -> io::Result<Run> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let (mut len, mut min, mut max) = (0, u64::MAX, 0);
+        for v in sorted {
+            out.write_all(&v.to_le_bytes())?;
+            len += 1;
+            min = min.min(v);
+            max = max.max(v);
+        }
+        out.flush()?;
+        Ok(Run {
+            path: path.to_path_buf(),
+            file: File::open(path)?,
+            len,
+            min,
+            max,
+        })
+    }
+
+    fn contains(&self, value: u64) -> io::Result<bool> {
+        if self.len == 0 || value < self.min || value > self.max {
+            return Ok(false);
+        }
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let v = self.read_at(mid)?;
+            if v == value {
+                return Ok(true);
+            } else if v < value {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(false)
+    }
+
+    fn read_at(&self, idx: u64) -> io::Result<u64> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(idx * 8))?;
+        let mut buf = [0; 8];
+        file.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_all(&self) -> io::Result<Vec<u64>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut values = Vec::with_capacity(self.len as usize);
+        while let Some(v) = read_u64(&mut reader)? {
+            values.push(v);
+        }
+        Ok(values)
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut buf = [0; 8];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(u64::from_le_bytes(buf))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fly-store-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn spills_and_still_finds_everything() {
+        let dir = temp_dir("spill");
+        let mut store = MessageStore::spilling(10, dir.clone()).unwrap();
+        for v in (0..100).rev() {
+            assert!(store.insert(v).unwrap());
+        }
+        for v in 0..100 {
+            assert!(!store.insert(v).unwrap(), "{} inserted twice", v);
+            assert!(store.contains(v).unwrap());
+        }
+        assert!(!store.contains(100).unwrap());
+        assert_eq!(store.len(), 100);
+        assert!(store.memory.len() <= 10);
+
+        let mut all = store.all().unwrap();
+        all.sort_unstable();
+        assert_eq!(all, (0..100).collect::<Vec<_>>());
+
+        drop(store);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn ranges_go_in_whole() {
+        let mut store = MessageStore::new();
+        store.insert_range(10, 1 << 40).unwrap();
+        store.insert_range(0, 10).unwrap();
+        assert_eq!(store.len(), (1 << 40) + 1);
+        assert_eq!(store.memory.runs(), 1);
+
+        let dir = temp_dir("ranges");
+        let mut store = MessageStore::spilling(10, dir).unwrap();
+        store.insert_range(5, 30).unwrap();
+        store.insert_range(0, 40).unwrap();
+        assert_eq!(store.len(), 41);
+    }
+
+    #[test]
+    fn compaction_keeps_runs_bounded() {
+        let dir = temp_dir("compact");
+        let mut store = MessageStore::spilling(3, dir).unwrap();
+        // interleaved so runs overlap and the merge actually has to merge
+        for v in (0..200).map(|i| (i * 37) % 200) {
+            store.insert(v).unwrap();
+        }
+        let spill = store.spill.as_ref().unwrap();
+        assert!(spill.runs.len() <= MAX_RUNS);
+        for run in &spill.runs {
+            let values = run.read_all().unwrap();
+            assert!(values.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert_eq!(store.len(), 200);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::middleware::Dedup;
+use crate::simulator::{Nemesis, Simulator};
+use crate::topology::Shape;
+use crate::{Payload, Result};
+use std::collections::HashSet;
+use tokio::time::{Duration, Instant};
+
+// the grid: gossip interval × topology, and the fanout on its own axis as
+// random:<k> topologies. the built shapes come with theirs, everyone else, 2
+// on a ring, up to 4 on a grid, up to 5 in a tree. there's no batch window to
+// sweep, a new value goes out the moment it arrives and only retransmits wait
+// for the next round
+const INTERVALS_MS: [u64; 4] = [50, 100, 300, 1000];
+const FANOUTS: [usize; 3] = [2, 4, 6];
+// broadcasts per run, all sent at once round robin
+const REQUESTS: usize = 100;
+// how often we look whether a value made it everywhere, and how long it gets
+const STEP: Duration = Duration::from_millis(10);
+const DEADLINE: Duration = Duration::from_secs(30);
+const SEED: u64 = 7;
+
+fn shapes() -> Vec<Option<Shape>> {
+    [
+        None,
+        Some(Shape::Ring),
+        Some(Shape::Grid),
+        Some(Shape::Tree),
+    ]
+    .into_iter()
+    .chain(FANOUTS.map(|k| Some(Shape::Random(k))))
+    .collect()
+}
+
+// every node-to-node message takes up to 200ms, a few get lost. without the
+// losses the gossip interval wouldn't matter at all
+fn nemesis() -> Nemesis {
+    Nemesis {
+        drop: 0.02,
+        delay: 1.0,
+        max_delay: Duration::from_millis(200),
+        ..Nemesis::default()
+    }
+}
+
+#[derive(Debug)]
+pub struct Cell {
+    pub interval: Duration,
+    pub topology: &'static str,
+    pub fanout: f64,
+    pub msgs_per_op: f64,
+    // how long each value took to be on every node, the ones that made it
+    pub latencies: Vec<Duration>,
+}
+
+impl Cell {
+    fn percentile(&self, p: usize) -> Option<Duration> {
+        let i = (self.latencies.len() * p / 100).min(self.latencies.len().checked_sub(1)?);
+        Some(self.latencies[i])
+    }
+}
+
+// runs the broadcast workload once per combination of gossip interval and
+// topology or fanout on the simulator and prints what each cost. the clock is tokio's,
+// so it needs a runtime with the clock paused, same as --selftest
+pub async fn run(n: usize) -> Result<()> {
+    println!(
+        "sweep broadcast n={} requests={} drop={} max_delay={}ms",
+        n,
+        REQUESTS,
+        nemesis().drop,
+        nemesis().max_delay.as_millis()
+    );
+    for interval in INTERVALS_MS.map(Duration::from_millis) {
+        for shape in shapes() {
+            let cell = cell(n, interval, shape).await?;
+            let ms = |d: Option<Duration>| d.map_or("-".to_string(), |d| d.as_millis().to_string());
+            println!(
+                "interval={}ms topology={} fanout={:.1} msgs/op={:.1} converged={}/{} p50={}ms p99={}ms max={}ms",
+                interval.as_millis(),
+                cell.topology,
+                cell.fanout,
+                cell.msgs_per_op,
+                cell.latencies.len(),
+                REQUESTS,
+                ms(cell.percentile(50)),
+                ms(cell.percentile(99)),
+                ms(cell.latencies.last().copied()),
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn cell(n: usize, interval: Duration, shape: Option<Shape>) -> Result<Cell> {
+    let mut sim = Simulator::with_nemesis(n, nemesis(), SEED).with_gossip_interval(interval);
+    let ids = sim.nodes.keys().cloned().collect::<Vec<_>>();
+    let fanout = match &shape {
+        Some(shape) => {
+            let total = ids
+                .iter()
+                .map(|id| shape.neighbors(id, &ids).len())
+                .sum::<usize>();
+            total as f64 / n as f64
+        }
+        None => (n - 1) as f64,
+    };
+    let topology = shape.as_ref().map_or("all", |s| s.name());
+    if let Some(shape) = shape {
+        sim = sim.with_topology(shape);
+    }
+    for node in sim.nodes.values_mut() {
+        node.middleware = vec![Box::new(Dedup::default())];
+    }
+
+    let start = Instant::now();
+    for message in 0..REQUESTS {
+        let dest = &ids[message % n];
+        sim.client_request("c1", dest, Payload::Broadcast { message });
+    }
+    let mut waiting = (0..REQUESTS).collect::<HashSet<_>>();
+    let mut latencies = Vec::new();
+    loop {
+        sim.deliver_all().await?;
+        let everywhere = ids
+            .iter()
+            .map(|id| sim.messages(id))
+            .reduce(|a, b| a.intersection(&b).copied().collect())
+            .unwrap_or_default();
+        waiting.retain(|message| {
+            if everywhere.contains(message) {
+                latencies.push(start.elapsed());
+            }
+            !everywhere.contains(message)
+        });
+        if waiting.is_empty() || start.elapsed() >= DEADLINE {
+            break;
+        }
+        sim.step(STEP).await?;
+    }
+    Ok(Cell {
+        interval,
+        topology,
+        fanout,
+        msgs_per_op: sim.sent as f64 / REQUESTS as f64,
+        latencies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_ring_is_cheaper_and_slower_than_everyone() {
+        let interval = Duration::from_millis(100);
+        let all = cell(5, interval, None).await.unwrap();
+        let ring = cell(5, interval, Some(Shape::Ring)).await.unwrap();
+        assert_eq!(all.latencies.len(), REQUESTS);
+        assert_eq!(ring.latencies.len(), REQUESTS);
+        assert!(ring.msgs_per_op < all.msgs_per_op, "{:?} {:?}", ring, all);
+        assert!(ring.percentile(99) > all.percentile(99));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn more_fanout_costs_more_messages() {
+        let interval = Duration::from_millis(100);
+        let two = cell(9, interval, Some(Shape::Random(2))).await.unwrap();
+        let six = cell(9, interval, Some(Shape::Random(6))).await.unwrap();
+        assert_eq!((two.fanout, six.fanout), (2.0, 6.0));
+        assert_eq!(six.latencies.len(), REQUESTS);
+        assert!(two.msgs_per_op < six.msgs_per_op, "{:?} {:?}", two, six);
+    }
+}
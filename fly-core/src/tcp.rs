@@ -0,0 +1,71 @@
+use crate::config::Config;
+use crate::{replay, run};
+use tokio::io::{self, BufReader};
+use tokio::net::TcpListener;
+
+// --listen: the same line delimited json as on stdin/stdout, over tcp. one
+// connection at a time, and each one is a whole run of the node starting with
+// init, so a script can connect, drive it, hang up and connect again for a
+// fresh node. --record gets overwritten by every new connection
+pub async fn serve(listener: TcpListener, config: &Config) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        eprintln!("connection from {}", peer);
+        let (read, write) = stream.into_split();
+        let input = BufReader::new(read);
+        let result = match &config.record {
+            Some(path) => run(replay::record(input, path).await?, write, config).await,
+            None => run(input, write, config).await,
+        };
+        match result {
+            Ok(()) => eprintln!("{} hung up", peer),
+            Err(e) => eprintln!("connection from {} failed: {}", peer, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Msg, Payload};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn session(addr: std::net::SocketAddr, lines: &[&str]) -> Vec<Msg> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        for line in lines {
+            write
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+        }
+        write.shutdown().await.unwrap();
+        let mut replies = Vec::new();
+        let mut read = BufReader::new(read).lines();
+        while let Some(line) = read.next_line().await.unwrap() {
+            replies.push(serde_json::from_str::<Msg>(&line).unwrap());
+        }
+        replies
+    }
+
+    #[tokio::test]
+    async fn serves_one_node_per_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { serve(listener, &Config::default()).await });
+
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#;
+        for _ in 0..2 {
+            let replies = session(addr, &[init, echo]).await;
+            assert_eq!(replies.len(), 2);
+            assert!(matches!(replies[0].body.extra, Payload::InitOk));
+            assert!(matches!(&replies[1].body.extra, Payload::EchoOk { echo } if echo == "hi"));
+        }
+
+        // a bad first line only ends that connection
+        assert!(session(addr, &[echo]).await.is_empty());
+        assert_eq!(session(addr, &[init]).await.len(), 1);
+    }
+}
@@ -0,0 +1,196 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// children per node for --topology tree
+const TREE_FANOUT: usize = 4;
+
+// --topology: a neighbor graph we build ourselves instead of gossiping to
+// everyone, whatever maelstrom's topology message says. nodes are laid out in
+// the order of the cluster's node_ids
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shape {
+    Ring,
+    // as square as it gets, each node talks to up, down, left and right
+    Grid,
+    Tree,
+    // a ring in an order shuffled the same way on every node, each node
+    // talking to the k/2 on either side. k is the fanout, rounded up to even
+    // so the graph stays symmetric
+    Random(usize),
+    // same format as the topology message, {"n0": ["n1", ...], ...}
+    File(HashMap<String, Vec<String>>),
+}
+
+impl Shape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Ring => "ring",
+            Shape::Grid => "grid",
+            Shape::Tree => "tree",
+            Shape::Random(_) => "random",
+            Shape::File(_) => "file",
+        }
+    }
+
+    pub fn neighbors(&self, id: &str, nodes: &[String]) -> Vec<String> {
+        let n = nodes.len();
+        let Some(i) = nodes.iter().position(|node| node == id) else {
+            return Vec::new();
+        };
+        let mut idx = match self {
+            Shape::Ring => vec![(i + n - 1) % n, (i + 1) % n],
+            Shape::Grid => {
+                let side = (1..=n).find(|s| s * s >= n).unwrap_or(1);
+                let mut idx = Vec::new();
+                if i >= side {
+                    idx.push(i - side);
+                }
+                if i + side < n {
+                    idx.push(i + side);
+                }
+                if i % side > 0 {
+                    idx.push(i - 1);
+                }
+                if i % side < side - 1 && i + 1 < n {
+                    idx.push(i + 1);
+                }
+                idx
+            }
+            Shape::Tree => {
+                let mut idx = (TREE_FANOUT * i + 1..=TREE_FANOUT * i + TREE_FANOUT)
+                    .filter(|c| *c < n)
+                    .collect::<Vec<_>>();
+                if i > 0 {
+                    idx.push((i - 1) / TREE_FANOUT);
+                }
+                idx
+            }
+            Shape::Random(k) => {
+                let mut order = (0..n).collect::<Vec<_>>();
+                order.shuffle(&mut StdRng::seed_from_u64(n as u64));
+                let at = order.iter().position(|j| *j == i).unwrap_or(0);
+                (1..=k.div_ceil(2))
+                    .flat_map(|d| [order[(at + d) % n], order[(at + n - d % n) % n]])
+                    .collect()
+            }
+            Shape::File(graph) => {
+                return graph
+                    .get(id)
+                    .into_iter()
+                    .flatten()
+                    .filter(|node| *node != id && nodes.contains(node))
+                    .cloned()
+                    .collect();
+            }
+        };
+        idx.sort_unstable();
+        idx.dedup();
+        idx.into_iter()
+            .filter(|j| *j != i)
+            .map(|j| nodes[j].clone())
+            .collect()
+    }
+}
+
+impl std::str::FromStr for Shape {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Shape> {
+        match s {
+            "ring" => Ok(Shape::Ring),
+            "grid" => Ok(Shape::Grid),
+            "tree" => Ok(Shape::Tree),
+            _ => {
+                if let Some(path) = s.strip_prefix("file:") {
+                    let graph = serde_json::from_str(&fs::read_to_string(Path::new(path))?)?;
+                    return Ok(Shape::File(graph));
+                }
+                match s.strip_prefix("random:").map(str::parse) {
+                    Some(Ok(k)) if k > 0 => Ok(Shape::Random(k)),
+                    _ => anyhow::bail!(
+                        "unknown topology {}, expected ring, grid, tree, random:<k> or file:<path>",
+                        s
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn cluster(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    // every node reachable from n0 by following neighbors
+    fn connected(shape: &Shape, nodes: &[String]) -> bool {
+        let mut seen = HashSet::from([nodes[0].clone()]);
+        let mut todo = vec![nodes[0].clone()];
+        while let Some(node) = todo.pop() {
+            for next in shape.neighbors(&node, nodes) {
+                if seen.insert(next.clone()) {
+                    todo.push(next);
+                }
+            }
+        }
+        seen.len() == nodes.len()
+    }
+
+    #[test]
+    fn built_shapes_are_connected_and_symmetric() {
+        for shape in [
+            Shape::Ring,
+            Shape::Grid,
+            Shape::Tree,
+            Shape::Random(1),
+            Shape::Random(4),
+        ] {
+            for n in 1..40 {
+                let nodes = cluster(n);
+                assert!(connected(&shape, &nodes), "{} with {}", shape.name(), n);
+                for a in &nodes {
+                    for b in shape.neighbors(a, &nodes) {
+                        assert!(shape.neighbors(&b, &nodes).contains(a));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn neighbors() {
+        let nodes = cluster(9);
+        assert_eq!(Shape::Ring.neighbors("n0", &nodes), ["n1", "n8"]);
+        assert_eq!(
+            Shape::Grid.neighbors("n4", &nodes),
+            ["n1", "n3", "n5", "n7"]
+        );
+        assert_eq!(
+            Shape::Tree.neighbors("n1", &nodes),
+            ["n0", "n5", "n6", "n7", "n8"]
+        );
+        let random = Shape::Random(4);
+        for node in &nodes {
+            assert_eq!(random.neighbors(node, &nodes).len(), 4);
+        }
+        assert_eq!(random.neighbors("n0", &nodes[..3]).len(), 2);
+        assert_ne!(
+            random.neighbors("n0", &nodes),
+            Shape::Ring.neighbors("n0", &nodes)
+        );
+        let file = Shape::File(HashMap::from([(
+            "n0".to_string(),
+            vec!["n3".to_string(), "n42".to_string()],
+        )]));
+        assert_eq!(file.neighbors("n0", &nodes), ["n3"]);
+        assert!(file.neighbors("n1", &nodes).is_empty());
+    }
+}
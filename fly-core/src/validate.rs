@@ -0,0 +1,141 @@
+use crate::{Msg, Node, Payload, Reply, Result, MALFORMED_REQUEST};
+use serde_json::Value;
+use tokio::io::AsyncWrite;
+
+// --paranoid: no array or object in a message gets more entries than this,
+// and no string more bytes. far more than any of ours ever has, a sync page
+// is SYNC_PAGE values
+const MAX_ITEMS: usize = 10_000;
+const MAX_STRING: usize = 1 << 20;
+
+// serde already makes sure every field a type needs is there and has the
+// right type, this is what it can't know about. a request that fails either
+// gets a malformed-request error back instead of being dropped without a word,
+// so a client with a bug finds out from the reply rather than from timeouts
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub(crate) fn validate(&self, msg: &Msg) -> std::result::Result<(), String> {
+        match &msg.body.extra {
+            Payload::Init { node_id, node_ids } if !node_ids.contains(node_id) => {
+                Err(format!("{} isn't in node_ids {:?}", node_id, node_ids))
+            }
+            Payload::Membership { node_ids } if !node_ids.contains(&self.id) => {
+                Err(format!("{} isn't in node_ids {:?}", self.id, node_ids))
+            }
+            // we don't use the suggested topology, but one without us in it
+            // was meant for some other cluster
+            Payload::Topology { topology } if !topology.contains_key(&self.id) => {
+                Err(format!("topology has nothing for {}", self.id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // --paranoid, on top of validate: for when the other end isn't
+    // necessarily maelstrom's harness, like --listen open to a network. no
+    // answers to requests we never sent, no huge collections or strings and
+    // no control characters in strings. the line itself is already utf-8,
+    // the reader skips anything that isn't
+    pub(crate) fn suspicious(&self, line: &str, msg: &Msg) -> Option<String> {
+        if let Some(in_reply_to) = msg.body.in_reply_to {
+            if in_reply_to > self.msg_ids {
+                return Some(format!(
+                    "reply to {}, we've only sent {}",
+                    in_reply_to, self.msg_ids
+                ));
+            }
+        }
+        // it deserialized as a Msg a moment ago
+        let value = serde_json::from_str::<Value>(line).ok()?;
+        oversized(&value).map(|problem| format!("message{}", problem))
+    }
+
+    // maelstrom only ever hands us what's addressed to us, anything else is a
+    // harness or routing bug that handling it as ours would only hide. a
+    // sender that looks like a node but isn't one of ours (removed, or from
+    // some other cluster) still gets handled, it's just counted
+    pub(crate) fn misrouted(&mut self, msg: &Msg) -> bool {
+        if msg.dest != self.id {
+            eprintln!(
+                "dropping a message for {} from {}, we're {}",
+                msg.dest, msg.src, self.id
+            );
+            self.metrics.misrouted += 1;
+            return true;
+        }
+        if looks_like_node(&msg.src) && !self.nodes.contains(&msg.src) {
+            eprintln!("{} isn't one of our nodes {:?}", msg.src, self.nodes);
+            self.metrics.unknown_senders += 1;
+        }
+        false
+    }
+
+    // a line that didn't deserialize. if it still looks like a request (a
+    // sender, a type and a msg_id to answer) it gets told what was wrong with
+    // it, anything else is just dropped. so is whatever our own nodes send,
+    // a newer one using a type we don't know yet is normal during a rollout
+    pub(crate) fn reject(&mut self, line: &str, e: serde_json::Error) -> Result<()> {
+        let Ok(value) = serde_json::from_str::<Value>(line) else {
+            return Err(e.into());
+        };
+        let body = &value["body"];
+        let (Some(src), Some(kind), Some(msg_id), None) = (
+            value["src"].as_str(),
+            body["type"].as_str(),
+            body["msg_id"].as_u64(),
+            body.get("in_reply_to"),
+        ) else {
+            return Err(e.into());
+        };
+        if self.nodes.iter().any(|n| n == src) {
+            return Err(e.into());
+        }
+        eprintln!("malformed {} from {}: {}", kind, src, e);
+        self.reply(Reply {
+            dest: src.to_string(),
+            in_reply_to: Some(msg_id),
+            extra: Payload::Error {
+                code: MALFORMED_REQUEST,
+                text: format!("malformed {}: {}", kind, e),
+            },
+        })
+    }
+}
+
+// maelstrom's naming: n1 is a node, c1 a client, services have names
+fn looks_like_node(id: &str) -> bool {
+    id.strip_prefix('n')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// where in the value the first problem is, and what it is
+fn oversized(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => bad_string(s),
+        Value::Array(items) if items.len() > MAX_ITEMS => {
+            Some(format!(" has {} items", items.len()))
+        }
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(i, item)| oversized(item).map(|p| format!("[{}]{}", i, p))),
+        Value::Object(fields) if fields.len() > MAX_ITEMS => {
+            Some(format!(" has {} fields", fields.len()))
+        }
+        Value::Object(fields) => fields.iter().find_map(|(name, field)| {
+            bad_string(name)
+                .map(|p| format!(" has a field name that{}", p))
+                .or_else(|| oversized(field).map(|p| format!(".{}{}", name, p)))
+        }),
+        _ => None,
+    }
+}
+
+fn bad_string(s: &str) -> Option<String> {
+    if s.len() > MAX_STRING {
+        Some(format!(" is {} bytes", s.len()))
+    } else if s.chars().any(char::is_control) {
+        Some(" has control characters".to_string())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,580 @@
+use super::counter::SEQ_KV;
+use super::WorkloadState;
+use crate::metrics::Histogram;
+use crate::ranges::Ranges;
+use crate::store::MessageStore;
+use crate::topology::Shape;
+use crate::{
+    codec, delta, ranges, Encoding, Msg, Node, Payload, Pending, Prepared, Result, Workload,
+    MALFORMED_REQUEST,
+};
+use rand::seq::IndexedRandom;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio::time::{Duration, Instant};
+
+// anti-entropy: every SYNC_INTERVAL we pull one peer's whole set, a page at a
+// time, which is what catches values whose pending rpcs expired or got
+// evicted. peers take turns
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(3);
+const SYNC_PAGE: usize = 500;
+// a page nobody answered gets asked for again by a later sync anyway
+const SYNC_ATTEMPTS: u32 = 3;
+
+// problem 3
+#[derive(Default)]
+pub struct Broadcast {
+    pub messages: MessageStore,
+    syncs: u64,
+    // with --topology new values only go to these, everyone otherwise
+    pub(crate) shape: Option<Shape>,
+    neighbors: Option<Vec<String>>,
+    // propagation latency, from the moment a value first reaches us until the
+    // last peer we forwarded it to acked it. spreading has when that was and
+    // how many acks are still missing, awaiting which value each of those
+    // (peer, rpc number) pairs carries
+    spreading: HashMap<usize, (Instant, usize)>,
+    pub(crate) awaiting: HashMap<(String, u64), usize>,
+    pub(crate) propagation: Histogram,
+    // neighbors we gave up on, and who relays their values until they're
+    // back, see broadcast_gave_up
+    pub(crate) detours: HashMap<String, String>,
+}
+
+// every value we have, sorted so two nodes' snapshots compare equal
+impl WorkloadState for Broadcast {
+    fn snapshot(&self) -> Result<Value> {
+        let mut messages = self.messages.all()?;
+        messages.sort_unstable();
+        Ok(json!({ "messages": messages }))
+    }
+
+    fn restore(&mut self, snapshot: &Value) -> Result<()> {
+        let messages = Vec::<usize>::deserialize(&snapshot["messages"])?;
+        for message in messages {
+            self.messages.insert(message)?;
+        }
+        Ok(())
+    }
+}
+
+impl Broadcast {
+    fn peer_acked(&mut self, peer: &str, seq: u64, now: Instant) {
+        let Some(message) = self.awaiting.remove(&(peer.to_string(), seq)) else {
+            return;
+        };
+        let Some((since, missing)) = self.spreading.get_mut(&message) else {
+            return;
+        };
+        *missing -= 1;
+        if *missing == 0 {
+            self.propagation.record(now.duration_since(*since));
+            self.spreading.remove(&message);
+        }
+    }
+
+    // a restarted peer got everything pushed instead, the values it had
+    // still to ack stop waiting on it
+    pub(crate) fn forget_peer(&mut self, peer: &str) {
+        let lost = self
+            .awaiting
+            .keys()
+            .filter(|(p, _)| p == peer)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in lost {
+            let Some(message) = self.awaiting.remove(&key) else {
+                continue;
+            };
+            if let Some((_, missing)) = self.spreading.get_mut(&message) {
+                *missing -= 1;
+                if *missing == 0 {
+                    self.spreading.remove(&message);
+                }
+            }
+        }
+    }
+
+    // values some peer never acked (it left, the rpc expired) would sit here
+    // forever otherwise
+    fn forget_older_than(&mut self, cutoff: Instant) {
+        self.spreading.retain(|_, (since, _)| *since >= cutoff);
+        let spreading = &self.spreading;
+        self.awaiting.retain(|_, m| spreading.contains_key(m));
+    }
+
+    pub fn log_propagation(&self) {
+        let h = &self.propagation;
+        if let (Some(p50), Some(p95), Some(p99)) =
+            (h.percentile(50.0), h.percentile(95.0), h.percentile(99.0))
+        {
+            eprintln!(
+                "broadcast propagation over {} values: p50={}ms p95={}ms p99={}ms",
+                h.len(),
+                p50,
+                p95,
+                p99
+            );
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub(crate) fn broadcast(&mut self, msg: &Msg, message: &usize) -> Result<Option<Payload>> {
+        // since we're guaranteed that messages are unique
+        // and we broadcast to every node...
+        // if I already have something in my memory it means I already broadcast it properly
+        // so it works but it's horrible although simple
+        if self.broadcast.messages.insert(*message)? {
+            // one copy of the payload shared by every peer's pending entry.
+            // not the one we got, that can be a relay
+            let payload = Arc::new(Prepared::new(&Payload::Broadcast { message: *message })?);
+            let mut missing = 0;
+            let targets = match &self.broadcast.neighbors {
+                // a suspected neighbor cuts off everything behind it (the whole
+                // subtree with --topology tree), so while there is one values
+                // go to everyone directly. they still get parked for it too.
+                // suspected takes missed rounds or phi while we're waiting on
+                // it, a neighbor we've just had nothing for doesn't count
+                Some(neighbors) if neighbors.iter().any(|n| *n != msg.src && self.suspected(n)) => {
+                    self.metrics.flooded_around_suspects += 1;
+                    self.nodes.clone()
+                }
+                Some(neighbors) => neighbors.clone(),
+                None => self.nodes.clone(),
+            };
+            for node in targets {
+                if node == self.id || node == msg.src {
+                    continue;
+                }
+                let suspected = self.suspected(&node);
+                if !suspected {
+                    self.route_back(&node);
+                }
+                let (dest, seq) = match self.broadcast.detours.get(&node).cloned() {
+                    Some(via) => {
+                        let relay = Payload::Relay {
+                            to: node,
+                            message: *message,
+                        };
+                        let seq = self.rpc(&via, Arc::new(Prepared::new(&relay)?));
+                        (via, seq)
+                    }
+                    None if suspected => {
+                        let seq = self.park(&node, payload.clone(), self.max_attempts);
+                        (node, seq)
+                    }
+                    None => {
+                        let seq = self.rpc(&node, payload.clone());
+                        (node, seq)
+                    }
+                };
+                self.broadcast.awaiting.insert((dest, seq), *message);
+                missing += 1;
+            }
+            if missing > 0 {
+                let spreading = (Instant::now(), missing);
+                self.broadcast.spreading.insert(*message, spreading);
+            }
+        }
+        // only now that the value is in the set, spilled or not: a read the
+        // client sends once it has this ack has to include it. a failed insert
+        // returns early, and the client retries instead
+        Ok(Some(Payload::BroadcastOk))
+    }
+
+    // also what a relay gets acked with
+    pub(crate) fn broadcast_ok(&mut self, msg: &Msg) -> Result<Option<Payload>> {
+        if let Some(seq) = self.acked(&msg.src, msg.body.in_reply_to) {
+            self.broadcast.peer_acked(&msg.src, seq, Instant::now());
+        }
+        self.route_back(&msg.src);
+        Ok(None)
+    }
+
+    // a peer couldn't get `message` to `to` and asks us to. it's a value
+    // like any other for us too, and `to` gets it whether or not it was new
+    pub(crate) fn relay(
+        &mut self,
+        msg: &Msg,
+        to: &str,
+        message: &usize,
+    ) -> Result<Option<Payload>> {
+        self.broadcast(msg, message)?;
+        if to != self.id && self.nodes.iter().any(|n| n == to) {
+            let payload = Arc::new(Prepared::new(&Payload::Broadcast { message: *message })?);
+            if self.suspected(to) {
+                self.park(to, payload, self.max_attempts);
+            } else {
+                self.rpc(to, payload);
+            }
+        }
+        Ok(Some(Payload::RelayOk))
+    }
+
+    // both optional, a plain maelstrom read gets everything in one go. with a
+    // limit values come back sorted, and `next` in the reply is what to pass
+    // as `after` to get the following page
+    pub(crate) fn read(
+        &mut self,
+        msg: &Msg,
+        after: &Option<usize>,
+        limit: &Option<usize>,
+        encoding: &Option<Encoding>,
+        codec: &Option<String>,
+        _key: &Option<String>,
+    ) -> Result<Option<Payload>> {
+        // counter nodes get the same read, see counter.rs. a client's read
+        // can't say which one it means so it gets the primary workload, but
+        // a peer's read or one with sync parameters is always a sync
+        let sync = self.nodes.contains(&msg.src)
+            || after.is_some()
+            || limit.is_some()
+            || encoding.is_some()
+            || codec.is_some();
+        if self.workload == Workload::Counter
+            && !(sync && self.serving.contains(&Workload::Broadcast))
+        {
+            return self.read_sum(msg);
+        }
+        if *encoding == Some(Encoding::Ranges) {
+            return self.read_ranges(msg, after, limit, codec);
+        }
+        let mut messages = match self.broadcast.messages.all() {
+            Ok(messages) => messages,
+            // no reply, the client times out and tries again
+            Err(e) => {
+                eprintln!("reading messages failed: {}", e);
+                return Ok(None);
+            }
+        };
+        let mut next = None;
+        if after.is_some() || limit.is_some() {
+            messages.sort_unstable();
+            if let Some(after) = *after {
+                messages.drain(..messages.partition_point(|m| *m <= after));
+            }
+            if let Some(limit) = *limit {
+                if messages.len() > limit {
+                    messages.truncate(limit.max(1));
+                    next = messages.last().copied();
+                }
+            }
+        }
+        let delta = encoding.map(|_| delta::encode(&std::mem::take(&mut messages)));
+        let read_ok = Payload::ReadOk {
+            messages: Some(messages),
+            next,
+            delta,
+            ranges: None,
+            value: None,
+        };
+        self.encoded_for(msg, codec, read_ok)
+    }
+
+    // the same page as ranges, `limit` is how many of them
+    fn read_ranges(
+        &mut self,
+        msg: &Msg,
+        after: &Option<usize>,
+        limit: &Option<usize>,
+        codec: &Option<String>,
+    ) -> Result<Option<Payload>> {
+        let mut ranges = match self.broadcast.messages.ranges() {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                eprintln!("reading messages failed: {}", e);
+                return Ok(None);
+            }
+        };
+        if let Some(after) = *after {
+            ranges = ranges.after(after);
+        }
+        let mut next = None;
+        if let Some(limit) = *limit {
+            if ranges.runs() > limit {
+                ranges.truncate(limit.max(1));
+                next = ranges.last();
+            }
+        }
+        let read_ok = Payload::ReadOk {
+            messages: Some(Vec::new()),
+            next,
+            delta: None,
+            ranges: Some(ranges::encode(&ranges)),
+            value: None,
+        };
+        self.encoded_for(msg, codec, read_ok)
+    }
+
+    // a codec we don't know gets plain json, same as an old node would
+    fn encoded_for(
+        &self,
+        msg: &Msg,
+        codec: &Option<String>,
+        payload: Payload,
+    ) -> Result<Option<Payload>> {
+        let codec = codec.as_deref().and_then(codec::by_name);
+        let compression = self.peer_compression.get(&msg.src).copied();
+        Ok(Some(codec::for_peer(codec, compression, payload)?))
+    }
+
+    // a page of some peer's set we asked for while syncing, clients don't send
+    // us read_oks. seq-kv does, for the counter
+    pub(crate) fn read_ok(
+        &mut self,
+        msg: &Msg,
+        messages: &Option<Vec<usize>>,
+        next: &Option<usize>,
+        delta: &Option<String>,
+        ranges: &Option<String>,
+        value: &Option<u64>,
+    ) -> Result<Option<Payload>> {
+        if msg.src == SEQ_KV {
+            return self.kv_read_ok(msg, value);
+        }
+        if !self.nodes.contains(&msg.src) {
+            return Ok(None);
+        }
+        self.acked(&msg.src, msg.body.in_reply_to);
+        self.route_back(&msg.src);
+        let decoded = match delta {
+            Some(delta) => delta::decode(delta)?,
+            None => Vec::new(),
+        };
+        let ranges = match ranges {
+            Some(ranges) => ranges::decode(ranges)?,
+            None => Ranges::new(),
+        };
+        for message in messages.iter().flatten().chain(&decoded) {
+            self.broadcast.messages.insert(*message)?;
+        }
+        for (start, end) in ranges.iter() {
+            self.broadcast.messages.insert_range(start, end)?;
+        }
+        if next.is_some() {
+            self.request_page(&msg.src, *next)?;
+        }
+        Ok(None)
+    }
+
+    // we gossip to everyone, or over --topology if we were given one, the
+    // suggested topology isn't used either way
+    pub(crate) fn topology(
+        &mut self,
+        _msg: &Msg,
+        _topology: &HashMap<String, Vec<String>>,
+    ) -> Result<Option<Payload>> {
+        Ok(Some(Payload::TopologyOk))
+    }
+
+    pub fn with_topology(mut self, shape: Shape) -> Self {
+        self.set_topology(Some(shape));
+        self
+    }
+
+    // None is back to everyone
+    pub(crate) fn set_topology(&mut self, shape: Option<Shape>) {
+        self.broadcast.shape = shape;
+        self.broadcast.neighbors = None;
+        self.refresh_neighbors();
+    }
+
+    // the graph is laid over the cluster's node ids, so it's redone whenever
+    // membership changes
+    pub(crate) fn refresh_neighbors(&mut self) {
+        let Some(shape) = &self.broadcast.shape else {
+            return;
+        };
+        let neighbors = shape.neighbors(&self.id, &self.nodes);
+        eprintln!("{} topology, neighbors {:?}", shape.name(), neighbors);
+        self.broadcast.neighbors = Some(neighbors);
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|n| **n != self.id)
+            .cloned()
+            .collect::<Vec<_>>();
+        if peers.is_empty() {
+            return Ok(());
+        }
+        let cutoff = Instant::now().checked_sub(self.pending_ttl);
+        if let Some(cutoff) = cutoff {
+            self.broadcast.forget_older_than(cutoff);
+        }
+        let peer = peers[self.broadcast.syncs as usize % peers.len()].clone();
+        self.broadcast.syncs += 1;
+        self.request_page(&peer, None)
+    }
+
+    // ranges, delta and a binary codec only if the peer said in its hello
+    // that it can, an older binary would answer plain json anyway but a newer
+    // one might have changed what they mean
+    pub(crate) fn request_page(&mut self, peer: &str, after: Option<usize>) -> Result<()> {
+        let encoding = if self.peer_supports(peer, "ranges") {
+            Some(Encoding::Ranges)
+        } else if self.peer_supports(peer, "delta") {
+            Some(Encoding::Delta)
+        } else {
+            None
+        };
+        let payload = Arc::new(Prepared::new(&Payload::Read {
+            after,
+            limit: Some(SYNC_PAGE),
+            encoding,
+            codec: self
+                .codec
+                .filter(|_| self.peer_supports(peer, "encoded"))
+                .map(|c| c.name().to_string()),
+            key: None,
+        })?);
+        // a suspected peer only gets it when the backoff decides to probe it
+        // again, but it does need to get something: once whatever was pending
+        // to it expired, this is the only thing that tells us it's back
+        if self.suspected(peer) {
+            self.park(peer, payload, SYNC_ATTEMPTS);
+        } else {
+            self.rpc_with_budget(peer, payload, SYNC_ATTEMPTS);
+        }
+        Ok(())
+    }
+
+    // a value some peer never acked, see Node::gave_up. nothing's lost, the
+    // peer's next sync against us will find it. but with --topology that peer
+    // is a neighbor and everything behind it waits for that sync too, so we
+    // hand the value to some other peer that can still reach it. that peer
+    // stays the route for new values until the neighbor answers us again, be
+    // it an ack or a sync page
+    pub(crate) fn broadcast_gave_up(&mut self, pending: &Pending) {
+        let dest = &pending.dest;
+        let Some(message) = self.broadcast.awaiting.remove(&(dest.clone(), pending.seq)) else {
+            return;
+        };
+        let is_neighbor = self
+            .broadcast
+            .neighbors
+            .as_ref()
+            .is_some_and(|neighbors| neighbors.contains(dest));
+        // a relay that didn't get through isn't rerouted again
+        let via = if is_neighbor && pending.payload.is("broadcast") {
+            self.detour(dest)
+        } else {
+            None
+        };
+        let Some(via) = via else {
+            eprintln!(
+                "gave up on {} to {} after {} attempts",
+                message, dest, pending.attempts
+            );
+            return;
+        };
+        eprintln!(
+            "gave up on {} to {} after {} attempts, relaying through {}",
+            message, dest, pending.attempts, via
+        );
+        let relay = Payload::Relay {
+            to: dest.clone(),
+            message,
+        };
+        match Prepared::new(&relay) {
+            Ok(relay) => {
+                let seq = self.rpc(&via, Arc::new(relay));
+                self.broadcast.awaiting.insert((via, seq), message);
+            }
+            Err(e) => eprintln!("relaying {} failed: {}", message, e),
+        }
+    }
+
+    // the current detour around `peer` if it's still good, a random peer that
+    // isn't suspected and knows relay otherwise
+    fn detour(&mut self, peer: &str) -> Option<String> {
+        if let Some(via) = self.broadcast.detours.get(peer) {
+            if !self.suspected(via) {
+                return Some(via.clone());
+            }
+        }
+        let candidates = self
+            .nodes
+            .iter()
+            .filter(|n| **n != self.id && *n != peer)
+            .filter(|n| !self.suspected(n) && self.peer_supports(n, "relay"))
+            .collect::<Vec<_>>();
+        let via = candidates.choose(&mut rand::rng())?.to_string();
+        self.broadcast.detours.insert(peer.to_string(), via.clone());
+        Some(via)
+    }
+
+    fn route_back(&mut self, peer: &str) {
+        if let Some(via) = self.broadcast.detours.remove(peer) {
+            eprintln!("{} is back, no more relaying through {}", peer, via);
+        }
+    }
+
+    // everything we have for a peer that came back without any of it, see
+    // status.rs. sync would get there too, but one page of one peer every
+    // SYNC_INTERVAL. SYNC_PAGE ranges per rpc, retried like any other
+    pub(super) fn push_broadcasts(&mut self, peer: &str) -> Result<()> {
+        let ranges = self.broadcast.messages.ranges()?;
+        let chunks = ranges.chunks(SYNC_PAGE, ranges::MAX_DECODED);
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        for part in &chunks {
+            let resync = Payload::Resync {
+                ranges: ranges::encode(part),
+            };
+            self.rpc(peer, Arc::new(Prepared::new(&resync)?));
+        }
+        eprintln!(
+            "pushed {} ranges to {} in {} chunks",
+            ranges.runs(),
+            peer,
+            chunks.len()
+        );
+        Ok(())
+    }
+
+    pub(crate) fn resync(&mut self, msg: &Msg, ranges: &str) -> Result<Option<Payload>> {
+        if !self.nodes.contains(&msg.src) {
+            return Ok(None);
+        }
+        // a request, so unlike a bad sync page this one gets an answer
+        let ranges = match ranges::decode(ranges) {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                return Ok(Some(Payload::Error {
+                    code: MALFORMED_REQUEST,
+                    text: e.to_string(),
+                }))
+            }
+        };
+        for (start, end) in ranges.iter() {
+            self.broadcast.messages.insert_range(start, end)?;
+        }
+        Ok(Some(Payload::ResyncOk))
+    }
+
+    pub(crate) fn resync_ok(&mut self, msg: &Msg) -> Result<Option<Payload>> {
+        self.acked(&msg.src, msg.body.in_reply_to);
+        Ok(None)
+    }
+
+    // new members get everything we've seen so far, pushed like to a peer
+    // that restarted. we haven't heard its hello yet, but a member added at
+    // runtime runs this same code, and one that doesn't still gets it all
+    // from anti-entropy
+    pub(super) fn catch_up(&mut self, added: &[String]) -> Result<()> {
+        for node in added {
+            if let Err(e) = self.push_broadcasts(node) {
+                eprintln!("catching {} up failed: {}", node, e);
+            }
+        }
+        Ok(())
+    }
+}
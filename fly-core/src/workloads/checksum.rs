@@ -0,0 +1,90 @@
+use crate::{Msg, Node, Payload, Prepared, Result};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio::time::Duration;
+
+// every CHECKSUM_INTERVAL each node tells everyone a hash of everything it has
+// applied (broadcast values, crdt counter totals). while values are still
+// spreading hashes differ all the time, but a peer whose hash hasn't matched
+// ours for more than DIVERGED_ROUNDS rounds in a row is one gossip and sync
+// somehow never reconciled us with, and that's a bug worth a loud line. we
+// also pull its whole set right away instead of waiting for sync to get to it
+pub const CHECKSUM_INTERVAL: Duration = Duration::from_secs(5);
+const DIVERGED_ROUNDS: u32 = 3;
+
+#[derive(Default)]
+pub struct Divergence {
+    // ours as of the last round
+    pub(crate) own: Option<String>,
+    // how many rounds in a row each peer's didn't match it
+    rounds: HashMap<String, u32>,
+}
+
+impl Divergence {
+    pub fn forget(&mut self, peer: &str) {
+        self.rounds.remove(peer);
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // DefaultHasher isn't stable across rust versions, but every node in a run
+    // is the same binary
+    fn state_checksum(&self) -> Result<String> {
+        let messages = self.broadcast.messages.ranges()?;
+        let mut counts = self.counter.counts.iter().collect::<Vec<_>>();
+        counts.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        messages.hash(&mut hasher);
+        counts.hash(&mut hasher);
+        Ok(format!("{:016x}", hasher.finish()))
+    }
+
+    pub(crate) fn gossip_checksum(&mut self) -> Result<()> {
+        let own = self.state_checksum()?;
+        let payload = Arc::new(Prepared::new(&Payload::Checksum {
+            checksum: own.clone(),
+        })?);
+        self.divergence.own = Some(own);
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|n| **n != self.id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for peer in peers {
+            let msg_id = self.next_msg_id();
+            self.enqueue(&peer, msg_id, None, &payload);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn checksum(&mut self, msg: &Msg, checksum: &str) -> Result<Option<Payload>> {
+        if !self.nodes.contains(&msg.src) {
+            return Ok(None);
+        }
+        let Some(own) = &self.divergence.own else {
+            return Ok(None);
+        };
+        if own == checksum {
+            self.divergence.forget(&msg.src);
+            return Ok(None);
+        }
+        let rounds = self.divergence.rounds.entry(msg.src.clone()).or_default();
+        *rounds += 1;
+        if *rounds <= DIVERGED_ROUNDS {
+            return Ok(None);
+        }
+        eprintln!(
+            "diverged from {} for {} rounds ({} here, {} there), pulling its state",
+            msg.src, rounds, own, checksum
+        );
+        self.metrics.divergences += 1;
+        self.divergence.forget(&msg.src);
+        // the crdt counter needs nothing extra, every peer pushes us all of
+        // its counts every counter round anyway
+        self.request_page(&msg.src, None)?;
+        Ok(None)
+    }
+}
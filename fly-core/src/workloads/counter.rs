@@ -0,0 +1,631 @@
+use super::WorkloadState;
+use crate::error::invalid;
+use crate::kvcache::KvCache;
+use crate::{
+    Msg, Node, Payload, Prepared, Result, KEY_DOES_NOT_EXIST, MALFORMED_REQUEST,
+    PRECONDITION_FAILED,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio::time::{Duration, Instant};
+
+pub const SEQ_KV: &str = "seq-kv";
+
+// a kv call nobody answered in this long is given up on: our own key gets
+// written again on the next round, a read just doesn't get an answer and the
+// client tries again
+const KV_TIMEOUT: Duration = Duration::from_secs(1);
+
+// how often our key gets written if it's behind, or every peer gets our
+// counts with the crdt. its own timer, so neither waits on broadcast's
+// retransmission rounds or makes them any busier
+pub const COUNTER_INTERVAL: Duration = Duration::from_secs(1);
+
+// where the count lives, --counter picks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CounterMode {
+    // problem 4 the way it's meant to be done: every node has its own key in
+    // seq-kv with everything that was added through it, and a read is the sum
+    // of all of them. nobody else writes our key, so we always know what it
+    // should hold and only have to catch seq-kv up to it
+    #[default]
+    SeqKv,
+    // no seq-kv at all, a g-counter crdt: the same per node totals, but every
+    // node keeps all of them and gossips the lot to everyone each round.
+    // merging is a max per node, so lost, duplicated or reordered gossip
+    // doesn't matter as long as some round eventually gets through
+    Crdt,
+}
+
+impl CounterMode {
+    // the --counter spelling
+    pub fn name(self) -> &'static str {
+        match self {
+            CounterMode::SeqKv => "seq-kv",
+            CounterMode::Crdt => "crdt",
+        }
+    }
+}
+
+impl std::str::FromStr for CounterMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<CounterMode> {
+        match s {
+            "seq-kv" => Ok(CounterMode::SeqKv),
+            "crdt" => Ok(CounterMode::Crdt),
+            _ => anyhow::bail!("unknown counter mode {}, expected seq-kv or crdt", s),
+        }
+    }
+}
+
+// how fresh a `read` has to be, --read-mode picks. by default it's kv-sync
+// with seq-kv and local for the crdt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    // whatever we already know. for the crdt that's our merged counts, with
+    // seq-kv there's nothing local but our own key so it's the other keys
+    // read without the fence write first, and can be stale
+    Local,
+    // ask the other nodes for their counts and answer once a majority of us
+    // has. with seq-kv only a node itself knows its total so that's all of
+    // them, and a read doesn't get an answer while any node is unreachable
+    Quorum,
+    // write a fence to seq-kv before reading the keys, see read_sum. seq-kv
+    // mode only
+    KvSync,
+}
+
+impl ReadMode {
+    // the --read-mode spelling
+    pub fn name(self) -> &'static str {
+        match self {
+            ReadMode::Local => "local",
+            ReadMode::Quorum => "quorum",
+            ReadMode::KvSync => "kv-sync",
+        }
+    }
+}
+
+impl std::str::FromStr for ReadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<ReadMode> {
+        match s {
+            "local" => Ok(ReadMode::Local),
+            "quorum" => Ok(ReadMode::Quorum),
+            "kv-sync" => Ok(ReadMode::KvSync),
+            _ => anyhow::bail!("unknown read mode {}, expected local, quorum or kv-sync", s),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Counter {
+    pub(crate) mode: CounterMode,
+    pub(crate) read_mode: Option<ReadMode>,
+    // everything added through us
+    total: u64,
+    // what our key holds as far as we know
+    stored: u64,
+    // whether there's a cas (or the read after a failed one) out for our key,
+    // only ever one so they can't overtake each other
+    writing: bool,
+    // kv calls and polls waiting for an answer, by their msg_id
+    calls: HashMap<u64, (Instant, Call)>,
+    // the other nodes' keys, for --read-mode local
+    pub(crate) cache: KvCache,
+    sums: HashMap<u64, Sum>,
+    sum_ids: u64,
+    // crdt mode, every node's total as far as we've heard
+    pub(crate) counts: HashMap<String, u64>,
+}
+
+enum Call {
+    // our key from `stored` to this
+    Cas(u64),
+    // our key again, a cas found something else in it than we thought
+    Resync,
+    // the write a sum starts with
+    Fence(u64),
+    // one node's key for a sum
+    Key(u64, String),
+    // one peer's counts for a quorum read
+    Poll(u64, String),
+}
+
+// a client read waiting on the other nodes' keys or counts
+struct Sum {
+    client: Msg,
+    missing: usize,
+    value: u64,
+    // the client has retried by then, nobody is reading this answer anymore
+    deadline: Instant,
+}
+
+// the mode isn't state, it comes from --counter
+impl WorkloadState for Counter {
+    fn snapshot(&self) -> Result<Value> {
+        Ok(json!({
+            "total": self.total,
+            "stored": self.stored,
+            "counts": self.counts,
+        }))
+    }
+
+    fn restore(&mut self, snapshot: &Value) -> Result<()> {
+        self.total = u64::deserialize(&snapshot["total"])?;
+        self.stored = u64::deserialize(&snapshot["stored"])?;
+        self.counts = HashMap::deserialize(&snapshot["counts"])?;
+        Ok(())
+    }
+
+    // with seq-kv nothing on the nodes has to agree, the keys live in seq-kv
+    fn converged(&self) -> Result<Value> {
+        Ok(match self.mode {
+            CounterMode::SeqKv => Value::Null,
+            CounterMode::Crdt => json!(self.counts),
+        })
+    }
+}
+
+impl Counter {
+    // what's still in flight, for dump_state
+    pub(crate) fn in_flight(&self) -> Value {
+        json!({
+            "mode": self.mode.name(),
+            "read_mode": self.read_mode().name(),
+            "writing": self.writing,
+            "kv_calls": self.calls.len(),
+            "sums": self.sums.len(),
+        })
+    }
+
+    // the answer without asking anyone
+    pub(crate) fn read_now(&self) -> u64 {
+        match self.mode {
+            CounterMode::SeqKv => self.total,
+            CounterMode::Crdt => sum(&self.counts),
+        }
+    }
+
+    pub(crate) fn read_mode(&self) -> ReadMode {
+        self.read_mode.unwrap_or(match self.mode {
+            CounterMode::SeqKv => ReadMode::KvSync,
+            CounterMode::Crdt => ReadMode::Local,
+        })
+    }
+}
+
+fn key(node: &str) -> String {
+    format!("counter-{}", node)
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub fn with_counter_mode(mut self, mode: CounterMode) -> Self {
+        self.counter.mode = mode;
+        self
+    }
+
+    pub fn with_read_mode(mut self, mode: ReadMode) -> Self {
+        self.counter.read_mode = Some(mode);
+        self
+    }
+
+    pub fn with_kv_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.counter.cache.set_ttl(ttl);
+        self
+    }
+
+    // acked as soon as we've counted it, our key (or the other nodes) catch up
+    // in the background. a total past u64 can't be counted, that add is
+    // refused and nothing changes
+    pub(crate) fn add(&mut self, _msg: &Msg, delta: &u64) -> Result<Option<Payload>> {
+        let Some(total) = self.counter.total.checked_add(*delta) else {
+            return Ok(Some(Payload::Error {
+                code: MALFORMED_REQUEST,
+                text: format!("adding {} to {} overflows", delta, self.counter.total),
+            }));
+        };
+        self.counter.total = total;
+        match self.counter.mode {
+            CounterMode::SeqKv => self.write_own_key()?,
+            CounterMode::Crdt => {
+                self.counter
+                    .counts
+                    .insert(self.id.clone(), self.counter.total);
+            }
+        }
+        Ok(Some(Payload::AddOk))
+    }
+
+    // seq-kv is only sequentially consistent, a plain read can hand us any old
+    // value. writing something first puts the reads that follow after that
+    // write in seq-kv's order, so they see at least everything that came before
+    // it. our own key doesn't need reading at all, we know better
+    pub(crate) fn read_sum(&mut self, msg: &Msg) -> Result<Option<Payload>> {
+        let read_mode = self.counter.read_mode();
+        if self.counter.mode == CounterMode::Crdt && read_mode != ReadMode::Quorum {
+            return Ok(Some(sum_ok(sum(&self.counter.counts))));
+        }
+        let peers = self.nodes.iter().filter(|n| **n != self.id).count();
+        let missing = match (read_mode, self.counter.mode) {
+            // we're one of the majority ourselves
+            (ReadMode::Quorum, CounterMode::Crdt) => self.nodes.len() / 2,
+            _ => peers,
+        };
+        if missing == 0 {
+            return Ok(Some(sum_ok(self.counter.read_now())));
+        }
+        self.counter.sum_ids += 1;
+        let sum = self.counter.sum_ids;
+        self.counter.sums.insert(
+            sum,
+            Sum {
+                client: msg.clone(),
+                missing,
+                value: self.counter.total,
+                deadline: Instant::now() + self.client_timeout,
+            },
+        );
+        match read_mode {
+            ReadMode::Local => self.read_keys(sum, true)?,
+            ReadMode::Quorum => {
+                for peer in self.peers_but_us() {
+                    self.call(&peer, &Payload::CounterPoll, Call::Poll(sum, peer.clone()))?;
+                }
+            }
+            ReadMode::KvSync => {
+                let fence = Payload::Write {
+                    key: format!("fence-{}", self.id),
+                    value: sum,
+                };
+                self.kv(&fence, Call::Fence(sum))?;
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn write_ok(&mut self, msg: &Msg) -> Result<Option<Payload>> {
+        match self.answered(msg) {
+            Some(Call::Fence(sum)) if self.counter.sums.contains_key(&sum) => {
+                self.read_keys(sum, false)?
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    // a fenced read has to go to seq-kv for every key, what it gets back is
+    // still good for a local read for a while
+    fn read_keys(&mut self, sum: u64, cached: bool) -> Result<()> {
+        let now = Instant::now();
+        for node in self.peers_but_us() {
+            let key = key(&node);
+            if cached {
+                if let Some(value) = self.counter.cache.get(&key, now) {
+                    self.metrics.kv_cache_hits += 1;
+                    self.summed(sum, value)?;
+                    continue;
+                }
+                self.metrics.kv_cache_misses += 1;
+            }
+            self.kv(&kv_read(key.clone()), Call::Key(sum, key))?;
+        }
+        Ok(())
+    }
+
+    // --read-mode quorum asking us
+    pub(crate) fn counter_poll(&mut self, _msg: &Msg) -> Result<Option<Payload>> {
+        // with seq-kv we only know our own
+        let mut counts = match self.counter.mode {
+            CounterMode::SeqKv => HashMap::new(),
+            CounterMode::Crdt => self.counter.counts.clone(),
+        };
+        counts.insert(self.id.clone(), self.counter.total);
+        Ok(Some(Payload::CounterPollOk { counts }))
+    }
+
+    // with seq-kv a peer's answer is just its own total, the crdt merges the
+    // lot and answers from that once enough are in
+    pub(crate) fn counter_poll_ok(
+        &mut self,
+        msg: &Msg,
+        counts: &HashMap<String, u64>,
+    ) -> Result<Option<Payload>> {
+        let Some(Call::Poll(sum, _)) = self.answered(msg) else {
+            return Ok(None);
+        };
+        match self.counter.mode {
+            CounterMode::SeqKv => {
+                let total = counts.get(&msg.src).copied().unwrap_or(0);
+                self.summed(sum, total)?;
+            }
+            CounterMode::Crdt => {
+                self.counter_state(msg, counts)?;
+                self.summed(sum, 0)?;
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn cas_ok(&mut self, msg: &Msg) -> Result<Option<Payload>> {
+        if let Some(Call::Cas(to)) = self.answered(msg) {
+            self.counter.stored = to;
+            self.counter.writing = false;
+            self.write_own_key()?;
+        }
+        Ok(None)
+    }
+
+    // seq-kv answering one of our reads, see broadcast::read_ok for the others
+    pub(super) fn kv_read_ok(&mut self, msg: &Msg, value: &Option<u64>) -> Result<Option<Payload>> {
+        let value = value.unwrap_or(0);
+        match self.answered(msg) {
+            Some(Call::Key(sum, key)) => self.read_key(sum, key, value)?,
+            Some(Call::Resync) => self.resynced(value)?,
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn error(&mut self, msg: &Msg, code: &u32, text: &str) -> Result<Option<Payload>> {
+        if msg.src != SEQ_KV {
+            return Ok(None);
+        }
+        match (self.answered(msg), *code) {
+            // a key nobody has written yet, i.e. 0
+            (Some(Call::Key(sum, key)), KEY_DOES_NOT_EXIST) => self.read_key(sum, key, 0)?,
+            (Some(Call::Resync), KEY_DOES_NOT_EXIST) => self.resynced(0)?,
+            // only we write our key, so whatever is in there is an earlier cas
+            // of ours that timed out on us but did land. find out which
+            (Some(Call::Cas(_)), PRECONDITION_FAILED) => {
+                self.kv(&kv_read(key(&self.id)), Call::Resync)?;
+            }
+            (call, code) => {
+                eprintln!("seq-kv error {}: {}", code, text);
+                match call {
+                    Some(Call::Cas(_) | Call::Resync) => self.counter.writing = false,
+                    Some(Call::Fence(sum) | Call::Key(sum, _) | Call::Poll(sum, _)) => {
+                        self.counter.sums.remove(&sum);
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // only counts a peer of ours could have: merged with ours they still have
+    // to add up to something a u64 holds, since no node's adds can take it
+    // past that
+    pub(crate) fn counter_state(
+        &mut self,
+        msg: &Msg,
+        counts: &HashMap<String, u64>,
+    ) -> Result<Option<Payload>> {
+        if !self.nodes.contains(&msg.src) {
+            return Err(invalid(format!("counts from {}, not one of ours", msg.src)));
+        }
+        let merged = self
+            .counter
+            .counts
+            .iter()
+            .filter(|(node, _)| !counts.contains_key(*node))
+            .map(|(_, count)| *count)
+            .chain(counts.iter().map(|(node, count)| {
+                let known = self.counter.counts.get(node).copied().unwrap_or(0);
+                known.max(*count)
+            }))
+            .try_fold(0u64, |total, count| total.checked_add(count));
+        if merged.is_none() {
+            return Err(invalid(format!("counts from {} overflow", msg.src)));
+        }
+        for (node, count) in counts {
+            let known = self.counter.counts.entry(node.clone()).or_default();
+            *known = (*known).max(*count);
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn counter_round(&mut self) -> Result<()> {
+        self.expire_calls();
+        match self.counter.mode {
+            CounterMode::SeqKv => self.write_own_key(),
+            CounterMode::Crdt => self.gossip_counts(),
+        }
+    }
+
+    // every peer gets all the counts every round, nothing is acked or retried.
+    // same as gossip() we hold off while the rate limit is still working
+    // through the last round or stdout is slow, the next one carries
+    // everything anyway
+    fn gossip_counts(&mut self) -> Result<()> {
+        if self.counter.counts.is_empty() || !self.outbox.gossip.is_empty() || self.output_slow {
+            return Ok(());
+        }
+        let payload = Arc::new(Prepared::new(&Payload::CounterState {
+            counts: self.counter.counts.clone(),
+        })?);
+        for peer in self.peers_but_us() {
+            let msg_id = self.next_msg_id();
+            self.enqueue(&peer, msg_id, None, &payload);
+        }
+        Ok(())
+    }
+
+    // the next counter round would get there too, this just doesn't wait for it
+    pub(super) fn push_counts(&mut self, peer: &str) -> Result<()> {
+        if self.counter.mode != CounterMode::Crdt || self.counter.counts.is_empty() {
+            return Ok(());
+        }
+        let payload = Prepared::new(&Payload::CounterState {
+            counts: self.counter.counts.clone(),
+        })?;
+        let msg_id = self.next_msg_id();
+        self.enqueue(peer, msg_id, None, &payload);
+        Ok(())
+    }
+
+    // a poll that went to a peer's old process is never answered, the new
+    // one gets asked again. after our counts, if they were pushed
+    pub(crate) fn repoll(&mut self, peer: &str) -> Result<()> {
+        let lost = self
+            .counter
+            .calls
+            .iter()
+            .filter(|(_, (_, call))| matches!(call, Call::Poll(_, p) if p == peer))
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+        for msg_id in lost {
+            if let Some((_, call)) = self.counter.calls.remove(&msg_id) {
+                self.call(peer, &Payload::CounterPoll, call)?;
+            }
+        }
+        Ok(())
+    }
+
+    // every counter round: gives up on calls that have been out too long, and
+    // on reads whose client has given up on us. with seq-kv our key then gets
+    // written again if it's behind
+    fn expire_calls(&mut self) {
+        let now = Instant::now();
+        let reads = self.counter.sums.len();
+        self.counter.sums.retain(|_, s| s.deadline > now);
+        self.metrics.reads_past_deadline += (reads - self.counter.sums.len()) as u64;
+        let expired = self
+            .counter
+            .calls
+            .iter()
+            .filter(|(_, (since, _))| now.duration_since(*since) >= KV_TIMEOUT)
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+        for msg_id in expired {
+            match self.counter.calls.remove(&msg_id) {
+                Some((_, Call::Cas(_) | Call::Resync)) => self.counter.writing = false,
+                Some((_, Call::Fence(sum) | Call::Key(sum, _) | Call::Poll(sum, _))) => {
+                    self.counter.sums.remove(&sum);
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn write_own_key(&mut self) -> Result<()> {
+        let counter = &self.counter;
+        if counter.writing || counter.total <= counter.stored {
+            return Ok(());
+        }
+        let cas = Payload::Cas {
+            key: key(&self.id),
+            from: counter.stored,
+            to: counter.total,
+            create_if_not_exists: true,
+        };
+        let to = counter.total;
+        self.kv(&cas, Call::Cas(to))?;
+        self.counter.writing = true;
+        Ok(())
+    }
+
+    fn resynced(&mut self, stored: u64) -> Result<()> {
+        self.counter.stored = stored;
+        self.counter.writing = false;
+        self.write_own_key()
+    }
+
+    fn read_key(&mut self, sum: u64, key: String, value: u64) -> Result<()> {
+        self.counter.cache.put(key, value, Instant::now());
+        self.summed(sum, value)
+    }
+
+    fn summed(&mut self, sum: u64, value: u64) -> Result<()> {
+        let Some(s) = self.counter.sums.get_mut(&sum) else {
+            return Ok(());
+        };
+        // every key fits on its own, the sum of them doesn't have to
+        s.value = s.value.saturating_add(value);
+        s.missing -= 1;
+        if s.missing == 0 {
+            let s = self.counter.sums.remove(&sum).unwrap();
+            let value = match self.counter.mode {
+                CounterMode::SeqKv => s.value,
+                // a quorum read, everything polled is merged in by now
+                CounterMode::Crdt => self.counter.read_now(),
+            };
+            self.reply(s.client.reply_with(sum_ok(value)))?;
+        }
+        Ok(())
+    }
+
+    fn kv(&mut self, payload: &Payload, call: Call) -> Result<()> {
+        if let Payload::Write { key, .. } | Payload::Cas { key, .. } = payload {
+            self.counter.cache.invalidate(key);
+        }
+        self.call(SEQ_KV, payload, call)
+    }
+
+    // a call for a client read is only worth sending while someone's still
+    // waiting for it, and while we are. the lines of the others, our own key's
+    // writes, are sent whenever they get their turn
+    fn call(&mut self, dest: &str, payload: &Payload, call: Call) -> Result<()> {
+        let payload = Prepared::new(payload)?;
+        let msg_id = self.next_msg_id();
+        let now = Instant::now();
+        let deadline = match call {
+            Call::Fence(sum) | Call::Key(sum, _) | Call::Poll(sum, _) => self
+                .counter
+                .sums
+                .get(&sum)
+                .map(|s| s.deadline.min(now + KV_TIMEOUT)),
+            Call::Cas(_) | Call::Resync => None,
+        };
+        self.enqueue_until(dest, msg_id, None, &payload, deadline);
+        self.counter.calls.insert(msg_id, (now, call));
+        Ok(())
+    }
+
+    fn peers_but_us(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|n| **n != self.id)
+            .cloned()
+            .collect()
+    }
+
+    fn answered(&mut self, msg: &Msg) -> Option<Call> {
+        let msg_id = msg.body.in_reply_to?;
+        self.counter.calls.remove(&msg_id).map(|(_, call)| call)
+    }
+}
+
+fn kv_read(key: String) -> Payload {
+    Payload::Read {
+        after: None,
+        limit: None,
+        encoding: None,
+        codec: None,
+        key: Some(key),
+    }
+}
+
+// every node's count, the merged crdt's value. counter_state keeps this from
+// overflowing, restore doesn't
+pub(crate) fn sum(counts: &HashMap<String, u64>) -> u64 {
+    counts
+        .values()
+        .fold(0u64, |total, count| total.saturating_add(*count))
+}
+
+fn sum_ok(value: u64) -> Payload {
+    Payload::ReadOk {
+        messages: None,
+        next: None,
+        delta: None,
+        ranges: None,
+        value: Some(value),
+    }
+}
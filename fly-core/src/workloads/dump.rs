@@ -0,0 +1,98 @@
+use super::WorkloadState;
+use crate::{Msg, Node, Payload, Result};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // not part of maelstrom: everything the node knows, as pretty json in a
+    // file, for picking apart what went wrong after a failed run. the answer
+    // says where it went. written right here in the handler, so the node
+    // stops for as long as that takes
+    pub(crate) fn dump_state(
+        &mut self,
+        _msg: &Msg,
+        path: &Option<String>,
+    ) -> Result<Option<Payload>> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let millis = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis());
+                std::env::temp_dir().join(format!("fly-{}-{}.json", self.id, millis))
+            }
+        };
+        let state = self.state()?;
+        std::fs::write(&path, serde_json::to_string_pretty(&state)?)?;
+        eprintln!("dumped state to {}", path.display());
+        Ok(Some(Payload::DumpStateOk {
+            path: path.display().to_string(),
+        }))
+    }
+
+    pub(crate) fn state(&self) -> Result<Value> {
+        let now = Instant::now();
+
+        let mut pending = self
+            .pending
+            .iter()
+            .map(|(msg_id, p)| {
+                // the payload as it goes on the wire, see Prepared
+                let payload = serde_json::from_str::<Value>(&format!("{{{}", p.payload.fields))
+                    .unwrap_or(Value::Null);
+                json!({
+                    "msg_id": msg_id,
+                    "dest": p.dest,
+                    "seq": p.seq,
+                    "age_ms": now.duration_since(p.since).as_millis() as u64,
+                    "last_sent_ms_ago": now.duration_since(p.last_sent).as_millis() as u64,
+                    "attempts": p.attempts,
+                    "payload": payload,
+                })
+            })
+            .collect::<Vec<_>>();
+        pending.sort_unstable_by_key(|p| p["msg_id"].as_u64());
+
+        let peers = self
+            .peers
+            .iter()
+            .map(|(id, peer)| {
+                let phi = self.phi(id, now);
+                let state = json!({
+                    "sent": peer.sent,
+                    "misses": peer.misses,
+                    "phi": if phi.is_finite() { json!(phi) } else { Value::Null },
+                    "suspected": self.suspected(id),
+                    "compression": self.peer_compression.get(id).map(|c| c.name()),
+                    "version": self.peer_capabilities.get(id).map(|c| c.version),
+                    "features": self.peer_capabilities.get(id).map(|c| &c.features),
+                    "started": self.peer_status.get(id).map(|s| s.started),
+                    "epoch": self.peer_status.get(id).map(|s| s.epoch),
+                });
+                (id.clone(), state)
+            })
+            .collect::<serde_json::Map<_, _>>();
+
+        Ok(json!({
+            "id": self.id,
+            "nodes": self.nodes,
+            "started": self.status.started,
+            "epoch": self.status.epoch,
+            "workload": self.workload.name(),
+            "workload_known": self.workload_known,
+            "serving": self.served().iter().map(|w| w.name()).collect::<Vec<_>>(),
+            "broadcast": self.broadcast.snapshot()?,
+            "detours": self.broadcast.detours,
+            "counter": self.counter.snapshot()?,
+            "counter_in_flight": self.counter.in_flight(),
+            "pending": pending,
+            "peers": peers,
+            "acked": self.ack_marks(),
+            "outbox": self.outbox.len(),
+            "metrics": self.metrics(),
+        }))
+    }
+}
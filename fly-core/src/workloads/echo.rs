@@ -0,0 +1,11 @@
+use crate::{Msg, Node, Payload, Result};
+use tokio::io::AsyncWrite;
+
+// problem 1
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub(crate) fn echo(&mut self, _msg: &Msg, echo: &str) -> Result<Option<Payload>> {
+        Ok(Some(Payload::EchoOk {
+            echo: echo.to_string(),
+        }))
+    }
+}
@@ -0,0 +1,10 @@
+use crate::{Msg, Node, Payload, Result};
+use tokio::io::AsyncWrite;
+
+// problem 2, the actual scheme is whatever IdGenerator the node was built with
+impl<W: AsyncWrite + Unpin> Node<W> {
+    pub(crate) fn generate(&mut self, _msg: &Msg) -> Result<Option<Payload>> {
+        let id = self.ids.next_id(&self.id);
+        Ok(Some(Payload::GenerateOk { id }))
+    }
+}
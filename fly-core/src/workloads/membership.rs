@@ -0,0 +1,72 @@
+use crate::{Msg, Node, Payload, Result, MALFORMED_REQUEST};
+use tokio::io::AsyncWrite;
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // run() builds the node from the first init and then hands it to us like
+    // any later one, so this has to be idempotent. the id can't change though,
+    // everything we've handed out and every peer's view of us depends on it
+    pub(crate) fn init(
+        &mut self,
+        _msg: &Msg,
+        node_id: &str,
+        node_ids: &[String],
+    ) -> Result<Option<Payload>> {
+        if node_id != self.id {
+            return Ok(Some(Payload::Error {
+                code: MALFORMED_REQUEST,
+                text: format!(
+                    "already initialized as {}, can't become {}",
+                    self.id, node_id
+                ),
+            }));
+        }
+        if node_ids != self.nodes {
+            self.set_members(node_ids)?;
+        }
+        Ok(Some(Payload::InitOk))
+    }
+
+    pub(crate) fn membership(
+        &mut self,
+        _msg: &Msg,
+        node_ids: &[String],
+    ) -> Result<Option<Payload>> {
+        self.set_members(node_ids)?;
+        Ok(Some(Payload::MembershipOk))
+    }
+
+    // swap the cluster for a new one: forget everything about nodes that left,
+    // including whatever we were still retrying to them, and bring the new ones
+    // up to speed with everything we've seen so far
+    fn set_members(&mut self, node_ids: &[String]) -> Result<()> {
+        let removed = self
+            .nodes
+            .iter()
+            .filter(|n| !node_ids.contains(n))
+            .cloned()
+            .collect::<Vec<_>>();
+        let added = node_ids
+            .iter()
+            .filter(|n| **n != self.id && !self.nodes.contains(n))
+            .cloned()
+            .collect::<Vec<_>>();
+        eprintln!("membership change, added {:?} removed {:?}", added, removed);
+
+        for node in &removed {
+            self.pending.take(node);
+            self.divergence.forget(node);
+            self.peers.remove(node);
+            self.detector.remove(node);
+            self.peer_compression.remove(node);
+            self.peer_capabilities.remove(node);
+            self.peer_status.remove(node);
+            self.broadcast.detours.remove(node);
+            self.broadcast.detours.retain(|_, via| via != node);
+        }
+        self.nodes = node_ids.to_vec();
+        self.refresh_neighbors();
+        self.say_hello(&added)?;
+        self.say_status(&added)?;
+        self.catch_up(&added)
+    }
+}
@@ -0,0 +1,165 @@
+// one module per maelstrom workload. each adds its handlers to Node (so they
+// get the rpc machinery and mutable access to everything) and keeps whatever
+// state it needs in its own struct on the node. which message goes to which
+// handler is declared in the workload! list in lib.rs
+use crate::{Node, Payload, Result};
+use serde_json::Value;
+use tokio::io::AsyncWrite;
+
+pub mod broadcast;
+pub mod checksum;
+pub mod counter;
+mod dump;
+mod echo;
+mod generate;
+mod membership;
+mod params;
+
+// what a workload keeps in its struct on the node, as json. dump_state shows
+// it, the simulator compares it across nodes, and a node that saved its
+// snapshot can be put back where it was with restore
+pub trait WorkloadState {
+    // everything it'd need to pick up where it left off. what's only in
+    // flight (rpcs, kv calls) isn't part of it, those get retried anyway
+    fn snapshot(&self) -> Result<Value>;
+
+    fn restore(&mut self, snapshot: &Value) -> Result<()>;
+
+    // the part every node should end up agreeing on once things settle
+    fn converged(&self) -> Result<Value> {
+        self.snapshot()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // everything we serve, the primary first
+    pub(crate) fn served(&self) -> Vec<Workload> {
+        let mut served = vec![self.workload];
+        served.extend(self.serving.iter().filter(|w| **w != self.workload));
+        served
+    }
+
+    // the state of every workload we serve that keeps any, by name
+    pub fn snapshot(&self) -> Result<Value> {
+        let mut snapshot = serde_json::Map::new();
+        for workload in self.served() {
+            if let Some(state) = self.workload_state(workload) {
+                snapshot.insert(workload.name().to_string(), state.snapshot()?);
+            }
+        }
+        Ok(Value::Object(snapshot))
+    }
+
+    // workloads the snapshot has nothing for are left alone
+    pub fn restore(&mut self, snapshot: &Value) -> Result<()> {
+        for workload in self.served() {
+            let Some(part) = snapshot.get(workload.name()) else {
+                continue;
+            };
+            if let Some(state) = self.workload_state_mut(workload) {
+                state.restore(part)?;
+            }
+        }
+        Ok(())
+    }
+
+    // everything a restarted peer lost, for every workload we serve that
+    // keeps anything. seq-kv keeps the counter's for it
+    pub(crate) fn push_state(&mut self, peer: &str) -> Result<()> {
+        for workload in self.served() {
+            match workload {
+                Workload::Broadcast => self.push_broadcasts(peer)?,
+                Workload::Counter => self.push_counts(peer)?,
+                Workload::Echo | Workload::UniqueIds => {}
+            }
+        }
+        Ok(())
+    }
+
+    // echo and unique-ids don't keep anything
+    pub(crate) fn workload_state(&self, workload: Workload) -> Option<&dyn WorkloadState> {
+        match workload {
+            Workload::Broadcast => Some(&self.broadcast),
+            Workload::Counter => Some(&self.counter),
+            Workload::Echo | Workload::UniqueIds => None,
+        }
+    }
+
+    fn workload_state_mut(&mut self, workload: Workload) -> Option<&mut dyn WorkloadState> {
+        match workload {
+            Workload::Broadcast => Some(&mut self.broadcast),
+            Workload::Counter => Some(&mut self.counter),
+            Workload::Echo | Workload::UniqueIds => None,
+        }
+    }
+}
+
+// the challenge a node is serving. --workload or the first message that
+// belongs to one decides, see Node::detect_workload. also what --selftest runs.
+// --workload can name several, see Node::start_workload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    Broadcast,
+    Counter,
+}
+
+impl Workload {
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::Echo => "echo",
+            Workload::UniqueIds => "unique-ids",
+            Workload::Broadcast => "broadcast",
+            Workload::Counter => "g-counter",
+        }
+    }
+
+    // the requests only one workload gets. init, read and the *_ok replies
+    // don't tell, and neither does anything our own nodes send each other
+    // (a read or a hello can come before the first broadcast)
+    pub fn of(payload: &Payload) -> Option<Workload> {
+        match payload {
+            Payload::Echo { .. } => Some(Workload::Echo),
+            Payload::Generate => Some(Workload::UniqueIds),
+            Payload::Broadcast { .. }
+            | Payload::Relay { .. }
+            | Payload::Topology { .. }
+            | Payload::Resync { .. } => Some(Workload::Broadcast),
+            Payload::Add { .. }
+            | Payload::CounterState { .. }
+            | Payload::CounterPoll
+            | Payload::CounterPollOk { .. } => Some(Workload::Counter),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Workload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Workload> {
+        match s {
+            "echo" => Ok(Workload::Echo),
+            "unique-ids" | "generate" => Ok(Workload::UniqueIds),
+            "broadcast" => Ok(Workload::Broadcast),
+            "g-counter" | "counter" => Ok(Workload::Counter),
+            _ => anyhow::bail!(
+                "unknown workload {}, expected echo, unique-ids, broadcast or g-counter",
+                s
+            ),
+        }
+    }
+}
+
+// --workload echo,unique-ids
+pub fn parse_list(s: &str) -> anyhow::Result<Vec<Workload>> {
+    let mut workloads = Vec::new();
+    for name in s.split(',') {
+        let workload = name.trim().parse()?;
+        if !workloads.contains(&workload) {
+            workloads.push(workload);
+        }
+    }
+    Ok(workloads)
+}
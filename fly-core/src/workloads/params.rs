@@ -0,0 +1,95 @@
+use crate::ratelimit::TokenBucket;
+use crate::{Msg, Node, Payload, Result, MALFORMED_REQUEST, NOT_SUPPORTED};
+use anyhow::bail;
+use tokio::io::AsyncWrite;
+use tokio::time::{Duration, Instant};
+
+impl<W: AsyncWrite + Unpin> Node<W> {
+    // not part of maelstrom: changes a setting on a running node, so finding
+    // out which one a failing run is sensitive to doesn't take a restart each
+    // time. names and values are the command line's, minus the dashes, and
+    // the answer has the old value to put back. fanout is the topology's,
+    // there's no batch window, new values go out right away
+    pub(crate) fn set_param(
+        &mut self,
+        _msg: &Msg,
+        name: &str,
+        value: &str,
+    ) -> Result<Option<Payload>> {
+        let Some(was) = self.param(name) else {
+            return Ok(Some(Payload::Error {
+                code: NOT_SUPPORTED,
+                text: format!("no parameter {}", name),
+            }));
+        };
+        if let Err(e) = self.apply_param(name, value) {
+            return Ok(Some(Payload::Error {
+                code: MALFORMED_REQUEST,
+                text: format!("{} {}: {}", name, value, e),
+            }));
+        }
+        eprintln!("{} set to {}, was {}", name, value, was);
+        Ok(Some(Payload::SetParamOk { was }))
+    }
+
+    fn param(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "gossip-interval" => self.gossip_interval.as_millis().to_string(),
+            "gossip-rate" => self
+                .gossip_limit
+                .as_ref()
+                .map_or("unlimited".to_string(), |b| b.rate().to_string()),
+            "topology" => self
+                .broadcast
+                .shape
+                .as_ref()
+                .map_or("all", |s| s.name())
+                .to_string(),
+            "client-timeout" => self.client_timeout.as_millis().to_string(),
+            "slow-handler" => self.slow_handler.as_millis().to_string(),
+            "kv-cache-ttl" => self.counter.cache.ttl().as_millis().to_string(),
+            _ => return None,
+        })
+    }
+
+    fn apply_param(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match name {
+            "gossip-interval" => self.set_gossip_interval(millis(value)?),
+            "gossip-rate" => {
+                self.gossip_limit = match value {
+                    "unlimited" => None,
+                    rate => {
+                        let rate = rate.parse::<f64>()?;
+                        if !(rate >= 1.0 && rate.is_finite()) {
+                            bail!("needs at least 1 message a second");
+                        }
+                        Some(TokenBucket::new(rate, Instant::now()))
+                    }
+                }
+            }
+            "topology" => {
+                let shape = match value {
+                    "all" => None,
+                    shape => Some(shape.parse()?),
+                };
+                self.set_topology(shape);
+            }
+            "client-timeout" => self.client_timeout = millis(value)?,
+            "slow-handler" => self.slow_handler = Duration::from_millis(value.parse()?),
+            "kv-cache-ttl" => self
+                .counter
+                .cache
+                .set_ttl(Duration::from_millis(value.parse()?)),
+            _ => bail!("no parameter {}", name),
+        }
+        Ok(())
+    }
+}
+
+fn millis(value: &str) -> anyhow::Result<Duration> {
+    let ms = value.parse::<u64>()?;
+    if ms == 0 {
+        bail!("milliseconds, at least 1");
+    }
+    Ok(Duration::from_millis(ms))
+}
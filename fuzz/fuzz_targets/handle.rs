@@ -0,0 +1,17 @@
+#![no_main]
+
+use fly_core::Node;
+use libfuzzer_sys::fuzz_target;
+
+// raw bytes straight into the handler, errors are fine, panics aren't
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    let nodes = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+    let mut node = Node::new(tokio::io::sink(), "n1".to_string(), nodes);
+    for line in line.lines() {
+        let _ = node.handle(line);
+    }
+    node.gossip();
+});
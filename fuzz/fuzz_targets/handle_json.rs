@@ -0,0 +1,115 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use fly_core::Node;
+use libfuzzer_sys::fuzz_target;
+use serde_json::{json, Map, Value};
+
+// random bytes almost never get past the json parser, so this one builds
+// messages that are well formed json with a known type and then messes with
+// the fields, which is what actually reaches the match arms in handle
+const TYPES: &[&str] = &[
+    "init",
+    "init_ok",
+    "echo",
+    "echo_ok",
+    "generate",
+    "generate_ok",
+    "broadcast",
+    "broadcast_ok",
+    "relay",
+    "relay_ok",
+    "read",
+    "read_ok",
+    "topology",
+    "topology_ok",
+    "membership",
+    "membership_ok",
+    "set_param",
+    "set_param_ok",
+    "encoded",
+    "hello",
+    "hello_ok",
+    "status",
+    "status_ok",
+    "resync",
+    "resync_ok",
+    "add",
+    "add_ok",
+    "write_ok",
+    "cas_ok",
+    "counter_state",
+    "counter_poll",
+    "counter_poll_ok",
+    "checksum",
+    "error",
+];
+
+const FIELDS: &[&str] = &[
+    "echo", "id", "message", "messages", "node_id", "node_ids", "topology", "after", "limit", "encoding", "delta",
+    "next", "ranges", "codec", "data", "compression", "code", "text", "key", "value", "from", "to", "create_if_not_exists", "counts", "checksum", "version", "features", "started", "epoch", "workloads", "name", "was",
+];
+
+#[derive(Arbitrary, Debug)]
+enum Field {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Ints(Vec<i64>),
+    Strs(Vec<String>),
+    Map(Vec<(String, Vec<String>)>),
+}
+
+impl Field {
+    fn into_value(self) -> Value {
+        match self {
+            Field::Null => Value::Null,
+            Field::Bool(b) => json!(b),
+            Field::Int(i) => json!(i),
+            Field::Float(f) => json!(f),
+            Field::Str(s) => json!(s),
+            Field::Ints(v) => json!(v),
+            Field::Strs(v) => json!(v),
+            Field::Map(v) => Value::Object(v.into_iter().map(|(k, v)| (k, json!(v))).collect()),
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    src: u8,
+    kind: u8,
+    msg_id: Option<i64>,
+    in_reply_to: Option<i64>,
+    fields: Vec<(u8, Field)>,
+}
+
+fuzz_target!(|inputs: Vec<Input>| {
+    let nodes = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+    let mut node = Node::new(tokio::io::sink(), "n1".to_string(), nodes);
+    for input in inputs {
+        let mut body = Map::new();
+        body.insert(
+            "type".into(),
+            json!(TYPES[input.kind as usize % TYPES.len()]),
+        );
+        if let Some(id) = input.msg_id {
+            body.insert("msg_id".into(), json!(id));
+        }
+        if let Some(id) = input.in_reply_to {
+            body.insert("in_reply_to".into(), json!(id));
+        }
+        for (name, field) in input.fields {
+            body.insert(
+                FIELDS[name as usize % FIELDS.len()].into(),
+                field.into_value(),
+            );
+        }
+        let src = ["c1", "n1", "n2", "n3", "n4", "seq-kv"][input.src as usize % 6];
+        let msg = json!({ "src": src, "dest": "n1", "body": body });
+        let _ = node.handle(&msg.to_string());
+        node.gossip();
+    }
+});
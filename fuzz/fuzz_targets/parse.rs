@@ -0,0 +1,12 @@
+#![no_main]
+
+use fly_core::Msg;
+use libfuzzer_sys::fuzz_target;
+
+// anything we manage to parse has to survive a roundtrip
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = serde_json::from_slice::<Msg>(data) {
+        let s = serde_json::to_string(&msg).unwrap();
+        serde_json::from_str::<Msg>(&s).unwrap();
+    }
+});